@@ -9,7 +9,8 @@ use twprs_bouncer::{
     gh::Repo,
     report::{self, Report},
 };
-use twprs_db::db::ProfileDb;
+use twprs_db::async_db::{self, AsyncProfileDb};
+use twprs_db::db::ProfileDbOptions;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -30,7 +31,13 @@ async fn main() -> Result<(), Error> {
             reload,
             screen_name,
         } => {
-            let db = ProfileDb::open(db, false).map_err(report::Error::from)?;
+            let db = AsyncProfileDb::open_read_only(
+                db,
+                &ProfileDbOptions::default(),
+                async_db::DEFAULT_MAX_CONCURRENCY,
+            )
+            .await
+            .map_err(report::Error::from)?;
             let client = Arc::new(
                 Client::from_config_file("keys.toml")
                     .await
@@ -44,7 +51,7 @@ async fn main() -> Result<(), Error> {
 
             log::info!("Finding {} total users", report.total_user_count());
 
-            let read_count = report.read_users(&db, None)?;
+            let read_count = report.read_users(&db, None).await?;
             log::info!(
                 "Read {} cached users, downloading {}",
                 read_count,
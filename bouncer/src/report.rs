@@ -9,7 +9,7 @@ use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use twprs::model::User;
-use twprs_db::db::ProfileDb;
+use twprs_db::async_db::AsyncProfileDb;
 
 pub struct Report {
     client: Arc<Client>,
@@ -345,9 +345,9 @@ impl Report {
         self.follower_ids.union(&self.followed_ids).cloned()
     }
 
-    pub fn read_users(
+    pub async fn read_users(
         &mut self,
-        db: &ProfileDb,
+        db: &AsyncProfileDb,
         max_age: Option<Duration>,
     ) -> Result<usize, Error> {
         let now = Utc::now();
@@ -356,10 +356,14 @@ impl Report {
         std::fs::create_dir_all(&directory)?;
 
         let mut local_users = read_local_users(&directory)?;
+        let mut db_users = db
+            .lookup_many(self.missing_user_ids.iter().copied().collect(), false)
+            .await?;
 
         for user_id in &self.missing_user_ids {
-            let mut profiles = db
-                .lookup(*user_id)?
+            let mut profiles = db_users
+                .remove(user_id)
+                .unwrap_or_default()
                 .into_iter()
                 .map(|(_, profile)| profile)
                 .collect::<Vec<_>>();
@@ -442,6 +446,8 @@ pub enum Error {
     EggModeExtras(#[from] egg_mode_extras::error::Error),
     #[error("ProfileDb error")]
     ProfileDb(#[from] twprs_db::db::Error),
+    #[error("Async ProfileDb error")]
+    AsyncProfileDb(#[from] twprs_db::async_db::Error),
     #[error("JSON encoding error")]
     Json(#[from] serde_json::Error),
     #[error("I/O error")]
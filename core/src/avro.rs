@@ -1,7 +1,9 @@
-use super::model::User;
+use super::{model::User, validation::Validator};
 use apache_avro::{schema::Schema, Codec, Reader, Writer};
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
 
 pub fn writer<W: Write>(writer: W) -> Writer<'static, W> {
     Writer::with_codec(&USER_SCHEMA, writer, Codec::Snappy)
@@ -12,6 +14,17 @@ pub fn reader<R: Read>(reader: R) -> Result<Reader<'static, R>, Error> {
 }
 
 pub fn validate<R: Read>(reader: Reader<'static, R>) -> Result<usize, ValidationError> {
+    validate_with(reader, &mut Validator::new(vec![], 0))
+}
+
+/// Validate the ordering of a file while also running record-level checks.
+///
+/// The results of the record-level checks are collected in the validator's report, which is
+/// available even if the file fails the ordering validation.
+pub fn validate_with<R: Read>(
+    reader: Reader<'static, R>,
+    validator: &mut Validator,
+) -> Result<usize, ValidationError> {
     let mut count = 0;
     let mut last_snapshot = 0;
     let mut last_user_id = 0;
@@ -20,6 +33,7 @@ pub fn validate<R: Read>(reader: Reader<'static, R>) -> Result<usize, Validation
 
     for (line_number, value) in reader.enumerate() {
         let user = apache_avro::from_value::<User>(&value?)?;
+        validator.validate(line_number, &user);
 
         if user.snapshot < last_snapshot {
             misordered_line_numbers.push(line_number);
@@ -61,6 +75,108 @@ pub fn count_users<R: Read>(
     Ok(counts)
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterOptions {
+    /// Users whose records are removed.
+    pub remove_ids: HashSet<u64>,
+    /// Clear the free-text fields and image URLs of protected accounts (see `User::redact`).
+    pub redact_protected: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilterSummary {
+    pub record_count: usize,
+    pub removed_count: usize,
+    pub redacted_count: usize,
+}
+
+impl FilterSummary {
+    pub fn written_count(&self) -> usize {
+        self.record_count - self.removed_count
+    }
+}
+
+/// Copy records, removing or redacting them according to the options.
+///
+/// Records are written in their original order, so a sorted input produces a sorted output.
+pub fn filter<R: Read, W: Write>(
+    reader: Reader<'static, R>,
+    writer: &mut Writer<'static, W>,
+    options: &FilterOptions,
+) -> Result<FilterSummary, Error> {
+    let mut summary = FilterSummary::default();
+
+    for value in reader {
+        let mut user = apache_avro::from_value::<User>(&value?)?;
+        summary.record_count += 1;
+
+        if options.remove_ids.contains(&user.id()) {
+            summary.removed_count += 1;
+        } else {
+            if options.redact_protected && user.protected {
+                user.redact();
+                summary.redacted_count += 1;
+            }
+
+            writer.append_ser(user)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Filter a file into a new file.
+///
+/// If no records are written the output file is removed, since an Avro file with no records
+/// would have no header.
+pub fn filter_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    options: &FilterOptions,
+) -> Result<FilterSummary, Error> {
+    let reader = reader(File::open(input)?)?;
+    let mut writer = writer(BufWriter::new(File::create(&output)?));
+    let summary = filter(reader, &mut writer, options)?;
+    writer.into_inner()?.flush()?;
+
+    if summary.written_count() == 0 {
+        std::fs::remove_file(output)?;
+    }
+
+    Ok(summary)
+}
+
+/// Remove the given users' records from a file in place (via a temporary file that replaces the
+/// original).
+///
+/// Records are never redacted in place, since that would destroy the original data (use
+/// `filter_file` to write a redacted copy). The file is left unchanged if no records are removed,
+/// and is removed if no records are left.
+pub fn remove_users_in_place<P: AsRef<Path>>(
+    path: P,
+    user_ids: &HashSet<u64>,
+) -> Result<FilterSummary, Error> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+
+    let options = FilterOptions {
+        remove_ids: user_ids.clone(),
+        redact_protected: false,
+    };
+    let summary = filter_file(path, &temporary, &options)?;
+
+    if summary.written_count() == 0 {
+        std::fs::remove_file(path)?;
+    } else if summary.removed_count == 0 {
+        std::fs::remove_file(&temporary)?;
+    } else {
+        std::fs::rename(&temporary, path)?;
+    }
+
+    Ok(summary)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("I/O error")]
@@ -3,8 +3,16 @@ use flate2::read::GzDecoder;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
-use twprs::model::User;
+use std::path::{Path, PathBuf};
+use twprs::{
+    avro::FilterOptions,
+    cli::TableOpts,
+    model::User,
+    parquet::FileWriter,
+    query::Expr,
+    table::Column,
+    watchlist::{Scanner, Watchlist},
+};
 
 fn main() -> Result<(), Error> {
     let opts: Opts = Opts::parse();
@@ -83,6 +91,128 @@ fn main() -> Result<(), Error> {
                 }
             }
         }
+        Command::Parquet {
+            input,
+            output,
+            row_group_size,
+            partition_by_date,
+        } => {
+            let paths = input_paths(&input)?;
+
+            let mut writer = FileWriter::create(output, row_group_size, partition_by_date)?;
+
+            for path in paths {
+                let reader = twprs::avro::reader(File::open(path)?)?;
+
+                for value in reader {
+                    writer.write(apache_avro::from_value::<User>(&value?)?)?;
+                }
+            }
+
+            writer.close()?;
+        }
+        Command::Csv { input, table } => {
+            let paths = input_paths(&input)?;
+
+            let mut writer = table.writer(
+                std::io::stdout(),
+                &[Column::Id, Column::ScreenName, Column::Snapshot],
+                &[],
+            )?;
+
+            for path in paths {
+                let reader = twprs::avro::reader(File::open(path)?)?;
+
+                for value in reader {
+                    writer.write(&apache_avro::from_value::<User>(&value?)?)?;
+                }
+            }
+
+            writer.flush()?;
+        }
+        Command::Query {
+            input,
+            query,
+            follow,
+        } => {
+            // Each record is matched on its own, so quantifiers over a user's history don't apply.
+            if query.is_quantified() {
+                return Err(Error::QuantifiedQuery);
+            }
+
+            let paths = input_paths(&input)?;
+
+            let mut seen_ids = HashSet::new();
+
+            for path in paths {
+                let reader = twprs::avro::reader(File::open(path)?)?;
+
+                for value in reader {
+                    let user = apache_avro::from_value::<User>(&value?)?;
+
+                    if follow && seen_ids.contains(&user.id()) {
+                        println!("{}", serde_json::json!(user));
+                    } else if query.matches(&user) {
+                        seen_ids.insert(user.id());
+                        println!("{}", serde_json::json!(user));
+                    }
+                }
+            }
+        }
+        Command::Filter {
+            input,
+            output,
+            ids_file,
+            redact_protected,
+        } => {
+            let remove_ids = match ids_file {
+                Some(path) => BufReader::new(File::open(path)?)
+                    .lines()
+                    .filter_map(|line| match line {
+                        Ok(line) if line.trim().is_empty() => None,
+                        Ok(line) => Some(
+                            line.trim()
+                                .parse::<u64>()
+                                .map_err(|_| Error::InvalidUserId(line.clone())),
+                        ),
+                        Err(error) => Some(Err(Error::from(error))),
+                    })
+                    .collect::<Result<HashSet<_>, _>>()?,
+                None => HashSet::new(),
+            };
+
+            let summary = twprs::avro::filter_file(
+                input,
+                output,
+                &FilterOptions {
+                    remove_ids,
+                    redact_protected,
+                },
+            )?;
+
+            println!(
+                "{},{},{}",
+                summary.record_count, summary.removed_count, summary.redacted_count
+            );
+        }
+        Command::Watchlist { input, watchlist } => {
+            let watchlist = Watchlist::load(watchlist)?;
+            let paths = input_paths(&input)?;
+
+            let mut scanner = Scanner::new(&watchlist);
+
+            for path in paths {
+                let reader = twprs::avro::reader(File::open(path)?)?;
+
+                for value in reader {
+                    scanner.add(&apache_avro::from_value::<User>(&value?)?);
+                }
+            }
+
+            for tagged in scanner.into_results() {
+                println!("{}", serde_json::json!(tagged));
+            }
+        }
         Command::RtSearch { input } => {
             let mut paths = std::fs::read_dir(input)?
                 .map(|entry| entry.map(|entry| entry.path()))
@@ -127,6 +257,22 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// The input file, or the sorted paths of the files in the input directory.
+fn input_paths(input: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = Path::new(input);
+
+    if path.is_dir() {
+        let mut paths = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        Ok(paths)
+    } else {
+        Ok(vec![path.to_path_buf()])
+    }
+}
+
 fn write_from_path<P: AsRef<Path>, W: Write>(
     path: P,
     writer: &mut apache_avro::Writer<W>,
@@ -175,6 +321,16 @@ pub enum Error {
     UserAvro(#[from] twprs::avro::Error),
     #[error("JSON error")]
     Json(#[from] serde_json::Error),
+    #[error("Parquet error")]
+    Parquet(#[from] twprs::parquet::Error),
+    #[error("Table output error")]
+    Table(#[from] twprs::table::Error),
+    #[error("Watchlist error")]
+    Watchlist(#[from] twprs::watchlist::Error),
+    #[error("Invalid user ID")]
+    InvalidUserId(String),
+    #[error("Quantifiers (any, latest, all) are only supported by profiles query")]
+    QuantifiedQuery,
 }
 
 #[derive(Debug, Parser)]
@@ -220,4 +376,61 @@ enum Command {
         #[clap(short, long)]
         input: String,
     },
+    Parquet {
+        /// Input file or directory path
+        #[clap(short, long)]
+        input: String,
+        /// Output file path (or directory path if partitioning by date)
+        #[clap(short, long)]
+        output: String,
+        /// Maximum number of rows per row group
+        #[clap(long, default_value_t = twprs::parquet::DEFAULT_ROW_GROUP_SIZE)]
+        row_group_size: usize,
+        /// Write one file per snapshot date
+        #[clap(long)]
+        partition_by_date: bool,
+    },
+    /// Print records matching a query (see the `twprs::query` documentation for the syntax)
+    Query {
+        /// Input file or directory path
+        #[clap(short, long)]
+        input: String,
+        /// Query
+        #[clap(short, long)]
+        query: Expr,
+        /// Also print all later records for users who have matched
+        #[clap(long)]
+        follow: bool,
+    },
+    /// Tag users who match watchlist categories
+    Watchlist {
+        /// Input file or directory path
+        #[clap(short, long)]
+        input: String,
+        /// Watchlist file path (TOML or JSON)
+        #[clap(short, long)]
+        watchlist: String,
+    },
+    Csv {
+        /// Input file or directory path
+        #[clap(short, long)]
+        input: String,
+        #[clap(flatten)]
+        table: TableOpts,
+    },
+    /// Copy a file without the given users' records, optionally redacting protected accounts
+    Filter {
+        /// Input file path
+        #[clap(short, long)]
+        input: String,
+        /// Output file path (not written if no records are left)
+        #[clap(short, long)]
+        output: String,
+        /// File with one Twitter user ID per line to remove
+        #[clap(long)]
+        ids_file: Option<String>,
+        /// Clear the name, description, location, URL, and image URLs of protected accounts
+        #[clap(long)]
+        redact_protected: bool,
+    },
 }
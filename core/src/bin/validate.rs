@@ -1,10 +1,40 @@
+use twprs::validation::Validator;
+
+/// Usage: `validate [--strict] <path>`
+///
+/// Files that aren't ordered are always invalid, while record-level check failures are only
+/// reported unless `--strict` is given.
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = std::env::args().collect::<Vec<_>>();
-    let file = std::fs::File::open(&args[1])?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let path = args
+        .iter()
+        .find(|arg| *arg != "--strict")
+        .ok_or("Missing file path")?;
+    let file = std::fs::File::open(path)?;
     let reader = twprs::avro::reader(file)?;
 
-    let count = twprs::avro::validate(reader)?;
-    println!("Valid file with {} records", count);
+    let mut validator = Validator::default();
+    let result = twprs::avro::validate_with(reader, &mut validator);
+    let report = validator.into_report();
+
+    print!("{}", report);
+
+    let count = result?;
+
+    if report.is_valid() {
+        println!("Valid file with {} records", count);
+
+        Ok(())
+    } else if !strict {
+        println!(
+            "Warning: ordered file with {} records but {} record check failures",
+            count,
+            report.failure_count()
+        );
 
-    Ok(())
+        Ok(())
+    } else {
+        Err(format!("{} record check failures", report.failure_count()).into())
+    }
 }
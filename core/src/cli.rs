@@ -1,4 +1,6 @@
+use super::table::{self, Column, Columns, Format, UserWriter};
 use simplelog::LevelFilter;
+use std::io::Write;
 
 fn select_log_level_filter(verbosity: i32) -> LevelFilter {
     match verbosity {
@@ -19,3 +21,42 @@ pub fn init_logging(verbosity: i32) -> Result<(), log::SetLoggerError> {
         simplelog::ColorChoice::Auto,
     )
 }
+
+// Output options for commands that print one delimited row per user. This isn't a doc comment
+// because clap would use it as the description of every subcommand that flattens these options.
+#[derive(Debug, clap::Args)]
+pub struct TableOpts {
+    /// Comma-separated list of columns (e.g. "id,screen_name,expanded_url")
+    #[clap(long)]
+    pub columns: Option<Columns>,
+    /// Output format ("csv" or "tsv")
+    #[clap(long, default_value = "csv")]
+    pub format: Format,
+    /// Print a header row
+    #[clap(long)]
+    pub header: bool,
+}
+
+impl TableOpts {
+    /// Create a writer using the selected columns (or the given defaults), writing a header if
+    /// requested.
+    pub fn writer<W: Write>(
+        &self,
+        writer: W,
+        default_columns: &[Column],
+        extra: &[&str],
+    ) -> Result<UserWriter<W>, table::Error> {
+        let columns = self
+            .columns
+            .as_ref()
+            .map(|columns| columns.0.clone())
+            .unwrap_or_else(|| default_columns.to_vec());
+        let mut writer = UserWriter::new(writer, self.format, columns);
+
+        if self.header {
+            writer.write_header(extra)?;
+        }
+
+        Ok(writer)
+    }
+}
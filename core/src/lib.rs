@@ -2,5 +2,14 @@ pub mod avro;
 pub mod cli;
 pub mod extract;
 pub mod model;
+pub mod parquet;
+pub mod query;
+pub mod sort;
+pub mod table;
+#[cfg(test)]
+mod test_util;
 pub mod tsg;
+pub mod url;
 pub mod util;
+pub mod validation;
+pub mod watchlist;
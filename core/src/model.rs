@@ -83,4 +83,17 @@ impl User {
         let first_url = entity.urls.first()?;
         first_url.expanded_url.clone()
     }
+
+    /// Clear the free-text fields (name, description, location, URL, and URL entities) and the
+    /// profile image, banner, and background image URLs.
+    pub fn redact(&mut self) {
+        self.name.clear();
+        self.description = None;
+        self.location = None;
+        self.url = None;
+        self.entities = None;
+        self.profile_image_url_https.clear();
+        self.profile_banner_url = None;
+        self.profile_background_image_url_https = None;
+    }
 }
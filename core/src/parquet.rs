@@ -0,0 +1,538 @@
+//! Parquet export for user profiles.
+//!
+//! Each user object is written as a single row with the following flattened column layout:
+//!
+//! | Column | Type | Notes |
+//! |--------|------|-------|
+//! | `id` | `INT64` | |
+//! | `id_str` | `STRING` | |
+//! | `screen_name` | `STRING` | |
+//! | `name` | `STRING` | |
+//! | `location` | `STRING` | nullable |
+//! | `description` | `STRING` | nullable |
+//! | `url` | `STRING` | nullable, usually a t.co link |
+//! | `expanded_url` | `STRING` | nullable, the first expanded URL for `url` |
+//! | `url_urls` | `LIST<STRING>` | expanded URLs from `entities.url` |
+//! | `description_urls` | `LIST<STRING>` | expanded URLs from `entities.description` |
+//! | `protected` | `BOOLEAN` | |
+//! | `verified` | `BOOLEAN` | |
+//! | `followers_count` | `INT64` | |
+//! | `friends_count` | `INT64` | |
+//! | `listed_count` | `INT64` | |
+//! | `favourites_count` | `INT64` | |
+//! | `statuses_count` | `INT64` | |
+//! | `created_at` | `TIMESTAMP(SECONDS, UTC)` | null if the Twitter date can't be parsed |
+//! | `utc_offset` | `INT32` | nullable |
+//! | `time_zone` | `STRING` | nullable |
+//! | `geo_enabled` | `BOOLEAN` | nullable |
+//! | `lang` | `STRING` | nullable |
+//! | `profile_image_url_https` | `STRING` | |
+//! | `profile_banner_url` | `STRING` | nullable |
+//! | `default_profile` | `BOOLEAN` | |
+//! | `default_profile_image` | `BOOLEAN` | |
+//! | `withheld_scope` | `STRING` | nullable |
+//! | `withheld_in_countries` | `LIST<STRING>` | |
+//! | `snapshot` | `TIMESTAMP(SECONDS, UTC)` | |
+//!
+//! Entity URLs without an `expanded_url` are represented by their t.co `url`. The profile
+//! appearance fields (colors, background images, etc.) are not included.
+
+use super::model::{Entity, User};
+use super::sort::Sorter;
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::Compression;
+use ::parquet::file::properties::WriterProperties;
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{
+    ArrayRef, BooleanArray, Int32Array, Int64Array, RecordBatch, StringArray, TimestampSecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{NaiveDate, TimeZone, Utc};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 100_000;
+
+const TIMEZONE: &str = "UTC";
+const PARTITION_FILE_NAME: &str = "part-0.parquet";
+const TEMPORARY_DIRECTORY_NAME: &str = "_temporary";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Parquet error")]
+    Parquet(#[from] ::parquet::errors::ParquetError),
+    #[error("Arrow error")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[error("Invalid snapshot timestamp")]
+    InvalidSnapshot(i64),
+    #[error("Sort error")]
+    Sort(#[from] super::sort::Error),
+}
+
+lazy_static::lazy_static! {
+    pub static ref USER_SCHEMA: SchemaRef = Arc::new(user_schema());
+}
+
+fn user_schema() -> Schema {
+    let timestamp = DataType::Timestamp(TimeUnit::Second, Some(TIMEZONE.into()));
+    let string_list = DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)));
+
+    Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("id_str", DataType::Utf8, false),
+        Field::new("screen_name", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("location", DataType::Utf8, true),
+        Field::new("description", DataType::Utf8, true),
+        Field::new("url", DataType::Utf8, true),
+        Field::new("expanded_url", DataType::Utf8, true),
+        Field::new("url_urls", string_list.clone(), false),
+        Field::new("description_urls", string_list.clone(), false),
+        Field::new("protected", DataType::Boolean, false),
+        Field::new("verified", DataType::Boolean, false),
+        Field::new("followers_count", DataType::Int64, false),
+        Field::new("friends_count", DataType::Int64, false),
+        Field::new("listed_count", DataType::Int64, false),
+        Field::new("favourites_count", DataType::Int64, false),
+        Field::new("statuses_count", DataType::Int64, false),
+        Field::new("created_at", timestamp.clone(), true),
+        Field::new("utc_offset", DataType::Int32, true),
+        Field::new("time_zone", DataType::Utf8, true),
+        Field::new("geo_enabled", DataType::Boolean, true),
+        Field::new("lang", DataType::Utf8, true),
+        Field::new("profile_image_url_https", DataType::Utf8, false),
+        Field::new("profile_banner_url", DataType::Utf8, true),
+        Field::new("default_profile", DataType::Boolean, false),
+        Field::new("default_profile_image", DataType::Boolean, false),
+        Field::new("withheld_scope", DataType::Utf8, true),
+        Field::new("withheld_in_countries", string_list, false),
+        Field::new("snapshot", timestamp, false),
+    ])
+}
+
+/// Writes user objects to a single Parquet file.
+///
+/// Users are buffered and written as one row group each time the buffer reaches the row group
+/// size, so the row group size also bounds memory usage.
+pub struct Writer<W: Write + Send> {
+    underlying: ArrowWriter<W>,
+    buffer: Vec<User>,
+    row_group_size: usize,
+}
+
+impl<W: Write + Send> Writer<W> {
+    pub fn new(writer: W, row_group_size: usize) -> Result<Self, Error> {
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size)
+            .build();
+
+        Ok(Self {
+            underlying: ArrowWriter::try_new(writer, USER_SCHEMA.clone(), Some(properties))?,
+            buffer: Vec::with_capacity(row_group_size),
+            row_group_size,
+        })
+    }
+
+    pub fn write(&mut self, user: User) -> Result<(), Error> {
+        self.buffer.push(user);
+
+        if self.buffer.len() >= self.row_group_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            let batch = to_record_batch(&self.buffer)?;
+            self.underlying.write(&batch)?;
+            self.underlying.flush()?;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.underlying.close()?;
+
+        Ok(())
+    }
+}
+
+/// Writes user objects to a directory of Parquet files partitioned by snapshot date.
+///
+/// Files are written to `snapshot_date=YYYY-MM-DD/part-0.parquet` under the base directory, which
+/// is the Hive-style layout most columnar tools understand. Users are sorted by date with an
+/// external sort (see the `sort` module) that keeps at most `row_group_size` users in memory and
+/// writes its runs to a `_temporary` directory under the base directory, so each date gets a
+/// single file with full row groups whatever the order of the input.
+pub struct PartitionedWriter {
+    base: PathBuf,
+    temporary: PathBuf,
+    row_group_size: usize,
+    sorter: Sorter<fn(&User) -> Option<NaiveDate>>,
+}
+
+impl PartitionedWriter {
+    pub fn new<P: AsRef<Path>>(base: P, row_group_size: usize) -> Result<Self, Error> {
+        let base = base.as_ref().to_path_buf();
+        let temporary = base.join(TEMPORARY_DIRECTORY_NAME);
+        let sorter = Sorter::new(&temporary, row_group_size, snapshot_date as fn(&User) -> _)?;
+
+        Ok(Self {
+            base,
+            temporary,
+            row_group_size,
+            sorter,
+        })
+    }
+
+    pub fn write(&mut self, user: User) -> Result<(), Error> {
+        if snapshot_date(&user).is_none() {
+            return Err(Error::InvalidSnapshot(user.snapshot));
+        }
+
+        Ok(self.sorter.push(user)?)
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        let mut current: Option<(NaiveDate, Writer<File>)> = None;
+
+        for user in self.sorter.finish()? {
+            let user = user?;
+            let date = snapshot_date(&user).ok_or(Error::InvalidSnapshot(user.snapshot))?;
+
+            if current.as_ref().map(|(current_date, _)| *current_date) != Some(date) {
+                if let Some((_, writer)) = current.take() {
+                    writer.close()?;
+                }
+
+                let directory = self
+                    .base
+                    .join(format!("snapshot_date={}", date.format("%Y-%m-%d")));
+                std::fs::create_dir_all(&directory)?;
+                let file = File::create(directory.join(PARTITION_FILE_NAME))?;
+
+                current = Some((date, Writer::new(file, self.row_group_size)?));
+            }
+
+            if let Some((_, writer)) = current.as_mut() {
+                writer.write(user)?;
+            }
+        }
+
+        if let Some((_, writer)) = current {
+            writer.close()?;
+        }
+
+        std::fs::remove_dir_all(self.temporary)?;
+
+        Ok(())
+    }
+}
+
+fn snapshot_date(user: &User) -> Option<NaiveDate> {
+    Utc.timestamp_opt(user.snapshot, 0)
+        .single()
+        .map(|timestamp| timestamp.date_naive())
+}
+
+/// Either a single Parquet file or a directory partitioned by snapshot date.
+pub enum FileWriter {
+    Single(Writer<File>),
+    Partitioned(PartitionedWriter),
+}
+
+impl FileWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        row_group_size: usize,
+        partition_by_date: bool,
+    ) -> Result<Self, Error> {
+        if partition_by_date {
+            Ok(Self::Partitioned(PartitionedWriter::new(
+                path,
+                row_group_size,
+            )?))
+        } else {
+            Ok(Self::Single(Writer::new(
+                File::create(path)?,
+                row_group_size,
+            )?))
+        }
+    }
+
+    pub fn write(&mut self, user: User) -> Result<(), Error> {
+        match self {
+            Self::Single(writer) => writer.write(user),
+            Self::Partitioned(writer) => writer.write(user),
+        }
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        match self {
+            Self::Single(writer) => writer.close(),
+            Self::Partitioned(writer) => writer.close(),
+        }
+    }
+}
+
+pub fn to_record_batch(users: &[User]) -> Result<RecordBatch, Error> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.id),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            users.iter().map(|user| &user.id_str),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            users.iter().map(|user| &user.screen_name),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            users.iter().map(|user| &user.name),
+        )),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.location.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.description.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.url.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.expanded_url())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(url_list(users, |user| {
+            user.entities
+                .as_ref()
+                .and_then(|entities| entities.url.as_ref())
+        })),
+        Arc::new(url_list(users, |user| {
+            user.entities
+                .as_ref()
+                .and_then(|entities| entities.description.as_ref())
+        })),
+        Arc::new(BooleanArray::from(
+            users.iter().map(|user| user.protected).collect::<Vec<_>>(),
+        )),
+        Arc::new(BooleanArray::from(
+            users.iter().map(|user| user.verified).collect::<Vec<_>>(),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.followers_count),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.friends_count),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.listed_count),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.favourites_count),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            users.iter().map(|user| user.statuses_count),
+        )),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| {
+                    user.created_at()
+                        .ok()
+                        .map(|created_at| created_at.timestamp())
+                })
+                .collect::<TimestampSecondArray>()
+                .with_timezone(TIMEZONE),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.utc_offset)
+                .collect::<Int32Array>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.time_zone.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.geo_enabled)
+                .collect::<BooleanArray>(),
+        ),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.lang.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            users.iter().map(|user| &user.profile_image_url_https),
+        )),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.profile_banner_url.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(BooleanArray::from(
+            users
+                .iter()
+                .map(|user| user.default_profile)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(BooleanArray::from(
+            users
+                .iter()
+                .map(|user| user.default_profile_image)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(
+            users
+                .iter()
+                .map(|user| user.withheld_scope.as_ref())
+                .collect::<StringArray>(),
+        ),
+        Arc::new(string_list(
+            users.iter().map(|user| user.withheld_in_countries.iter()),
+        )),
+        Arc::new(
+            TimestampSecondArray::from_iter_values(users.iter().map(|user| user.snapshot))
+                .with_timezone(TIMEZONE),
+        ),
+    ];
+
+    Ok(RecordBatch::try_new(USER_SCHEMA.clone(), columns)?)
+}
+
+fn url_list<F: Fn(&User) -> Option<&Entity>>(users: &[User], f: F) -> arrow_array::ListArray {
+    string_list(users.iter().map(|user| {
+        f(user)
+            .map(|entity| entity.urls.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|url| url.expanded_url.as_ref().unwrap_or(&url.url))
+    }))
+}
+
+fn string_list<'a, I: Iterator<Item = J>, J: Iterator<Item = &'a String>>(
+    values: I,
+) -> arrow_array::ListArray {
+    let mut builder = ListBuilder::new(StringBuilder::new());
+
+    for items in values {
+        for item in items {
+            builder.values().append_value(item);
+        }
+        builder.append(true);
+    }
+
+    builder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, user};
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// The user IDs in a file, and its number of row groups.
+    fn read_ids(path: &Path) -> (Vec<i64>, usize) {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap();
+        let row_groups = builder.metadata().num_row_groups();
+        let mut ids = vec![];
+
+        for batch in builder.build().unwrap() {
+            let batch = batch.unwrap();
+            let column = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            ids.extend(column.values().iter());
+        }
+
+        (ids, row_groups)
+    }
+
+    #[test]
+    fn partition_by_date() {
+        let base = temp_dir("parquet-partitioned");
+        let day = 24 * 60 * 60;
+        let mut writer = PartitionedWriter::new(&base, 4).unwrap();
+
+        // Input in user ID order, with each user's profiles spread across the dates.
+        for id in 0..10 {
+            for date in 0..3 {
+                writer
+                    .write(user(id, "jack", 1_600_000_000 + date * day))
+                    .unwrap();
+            }
+        }
+
+        writer.close().unwrap();
+
+        let mut directories = std::fs::read_dir(&base)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        directories.sort();
+
+        assert_eq!(
+            directories,
+            vec![
+                "snapshot_date=2020-09-13",
+                "snapshot_date=2020-09-14",
+                "snapshot_date=2020-09-15"
+            ]
+        );
+
+        for directory in directories {
+            let directory = base.join(directory);
+
+            assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+            assert_eq!(
+                read_ids(&directory.join(PARTITION_FILE_NAME)),
+                ((0..10).collect(), 3)
+            );
+        }
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn invalid_snapshot() {
+        let base = temp_dir("parquet-invalid");
+        let mut writer = PartitionedWriter::new(&base, 4).unwrap();
+
+        assert!(matches!(
+            writer.write(user(1, "jack", i64::MAX)),
+            Err(Error::InvalidSnapshot(i64::MAX))
+        ));
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,651 @@
+//! A small predicate language for searching user profiles.
+//!
+//! A query is a boolean combination of field comparisons:
+//!
+//! ```text
+//! followers_count >= 1000 and (name ~ "sputnik" or description =~ "(?i)@rt_\w+")
+//! ```
+//!
+//! Field names are the column names from [`crate::table::Column`], plus `urls`, which is the
+//! list of expanded URLs from both the `url` and `description` entities (a comparison on `urls`
+//! succeeds if any URL satisfies it). Values are double- or single-quoted strings, numbers,
+//! or bare words. Boolean fields have the values `1` and `0`, and `true` and `false` can be used
+//! in comparisons with them.
+//!
+//! | Operator | Meaning |
+//! |----------|---------|
+//! | `=`, `!=` | equality (numeric if both sides are integers) |
+//! | `<`, `<=`, `>`, `>=` | ordering (numeric if both sides are integers) |
+//! | `~` | case-insensitive substring |
+//! | `=~` | regular expression |
+//!
+//! Comparisons can be combined with `and`, `or`, `not`, and parentheses. When a query is run
+//! against a user's history (all stored snapshots), `any(...)`, `latest(...)`, and `all(...)`
+//! control which snapshots the enclosed expression has to match. An expression without
+//! quantifiers matches a history if a single snapshot satisfies all of it, so that it has the
+//! same meaning as when it's run against individual user objects. Quantifiers can't be used in
+//! queries against individual user objects (see `Expr::is_quantified`).
+
+use super::{model::User, table::Column};
+use regex::Regex;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unexpected character at {0}")]
+    UnexpectedCharacter(usize, char),
+    #[error("Unterminated string at {0}")]
+    UnterminatedString(usize),
+    #[error("Unexpected token at {0}: {1}")]
+    UnexpectedToken(usize, String),
+    #[error("Unexpected end of query")]
+    UnexpectedEnd,
+    #[error("Unknown field: {0}")]
+    UnknownField(String),
+    #[error("Quantifiers cannot be nested")]
+    NestedQuantifier,
+    #[error("Invalid regular expression")]
+    InvalidRegex(#[from] regex::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Column(Column),
+    Urls,
+}
+
+impl Field {
+    fn values(&self, user: &User) -> Vec<String> {
+        match self {
+            Field::Column(column) => vec![column.value(user)],
+            Field::Urls => user
+                .entities
+                .iter()
+                .flat_map(|entities| entities.url.iter().chain(entities.description.iter()))
+                .flat_map(|entity| &entity.urls)
+                .filter_map(|url| url.expanded_url.clone())
+                .collect(),
+        }
+    }
+
+    fn is_boolean(&self) -> bool {
+        matches!(
+            self,
+            Field::Column(
+                Column::Protected
+                    | Column::Verified
+                    | Column::GeoEnabled
+                    | Column::DefaultProfile
+                    | Column::DefaultProfileImage
+            )
+        )
+    }
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "urls" {
+            Ok(Field::Urls)
+        } else {
+            s.parse()
+                .map(Field::Column)
+                .map_err(|_| Error::UnknownField(s.to_string()))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantifier {
+    Any,
+    Latest,
+    All,
+}
+
+#[derive(Clone, Debug)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    Matches(Regex),
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Compare {
+        field: Field,
+        operator: Operator,
+        value: String,
+    },
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Quantified(Quantifier, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluate the expression against a single user object (which treats any quantifiers as if
+    /// the user object were a history with a single snapshot).
+    pub fn matches(&self, user: &User) -> bool {
+        match self {
+            Expr::Compare {
+                field,
+                operator,
+                value,
+            } => field
+                .values(user)
+                .iter()
+                .any(|field_value| compare(field_value, operator, value)),
+            Expr::And(left, right) => left.matches(user) && right.matches(user),
+            Expr::Or(left, right) => left.matches(user) || right.matches(user),
+            Expr::Not(expr) => !expr.matches(user),
+            Expr::Quantified(_, expr) => expr.matches(user),
+        }
+    }
+
+    /// Evaluate the expression against a user's snapshots (ordered from oldest to newest).
+    pub fn matches_history(&self, users: &[User]) -> bool {
+        if !self.is_quantified() {
+            return users.iter().any(|user| self.matches(user));
+        }
+
+        match self {
+            Expr::Compare { .. } => users.iter().any(|user| self.matches(user)),
+            Expr::And(left, right) => left.matches_history(users) && right.matches_history(users),
+            Expr::Or(left, right) => left.matches_history(users) || right.matches_history(users),
+            Expr::Not(expr) => !expr.matches_history(users),
+            Expr::Quantified(Quantifier::Any, expr) => users.iter().any(|user| expr.matches(user)),
+            Expr::Quantified(Quantifier::Latest, expr) => {
+                users.last().filter(|user| expr.matches(user)).is_some()
+            }
+            Expr::Quantified(Quantifier::All, expr) => {
+                !users.is_empty() && users.iter().all(|user| expr.matches(user))
+            }
+        }
+    }
+
+    /// Whether the expression contains a quantifier (and so only makes sense against histories).
+    pub fn is_quantified(&self) -> bool {
+        match self {
+            Expr::Compare { .. } => false,
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.is_quantified() || right.is_quantified()
+            }
+            Expr::Not(expr) => expr.is_quantified(),
+            Expr::Quantified(_, _) => true,
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expr = parser.parse_or()?;
+
+        match parser.tokens.get(parser.position) {
+            Some((position, token)) => Err(Error::UnexpectedToken(*position, token.to_string())),
+            None => Ok(expr),
+        }
+    }
+}
+
+fn compare(field_value: &str, operator: &Operator, value: &str) -> bool {
+    let numbers = field_value
+        .parse::<i64>()
+        .ok()
+        .zip(value.parse::<i64>().ok());
+
+    match operator {
+        Operator::Eq => match numbers {
+            Some((left, right)) => left == right,
+            None => field_value == value,
+        },
+        Operator::Ne => match numbers {
+            Some((left, right)) => left != right,
+            None => field_value != value,
+        },
+        Operator::Lt => match numbers {
+            Some((left, right)) => left < right,
+            None => field_value < value,
+        },
+        Operator::Le => match numbers {
+            Some((left, right)) => left <= right,
+            None => field_value <= value,
+        },
+        Operator::Gt => match numbers {
+            Some((left, right)) => left > right,
+            None => field_value > value,
+        },
+        Operator::Ge => match numbers {
+            Some((left, right)) => left >= right,
+            None => field_value >= value,
+        },
+        Operator::Contains => field_value.to_lowercase().contains(&value.to_lowercase()),
+        Operator::Matches(regex) => regex.is_match(field_value),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(value) => write!(f, "{}", value),
+            Token::Str(value) => write!(f, "{:?}", value),
+            Token::Op(value) => write!(f, "{}", value),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+const OPERATORS: [&str; 8] = ["=~", "!=", "<=", ">=", "=", "<", ">", "~"];
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push((position, Token::Open));
+        } else if c == ')' {
+            chars.next();
+            tokens.push((position, Token::Close));
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut value = String::new();
+            let mut terminated = false;
+
+            while let Some((_, next)) = chars.next() {
+                if next == c {
+                    terminated = true;
+                    break;
+                } else if next == '\\' {
+                    // Backslashes only escape quotes and other backslashes, so that regular
+                    // expressions can be written without double escaping.
+                    match chars.next() {
+                        Some((_, escaped)) if escaped == c || escaped == '\\' => {
+                            value.push(escaped)
+                        }
+                        Some((_, other)) => {
+                            value.push('\\');
+                            value.push(other);
+                        }
+                        None => break,
+                    }
+                } else {
+                    value.push(next);
+                }
+            }
+
+            if !terminated {
+                return Err(Error::UnterminatedString(position));
+            }
+
+            tokens.push((position, Token::Str(value)));
+        } else if let Some(op) = OPERATORS
+            .iter()
+            .find(|op| input[position..].starts_with(*op))
+        {
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push((position, Token::Op(op)));
+        } else if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '@' {
+            let mut value = String::new();
+
+            while let Some(&(_, next)) = chars.peek() {
+                if next.is_alphanumeric()
+                    || next == '_'
+                    || next == '-'
+                    || next == '.'
+                    || next == '@'
+                {
+                    value.push(next);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            tokens.push((position, Token::Word(value)));
+        } else {
+            return Err(Error::UnexpectedCharacter(position, c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), Error> {
+        let next = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(Error::UnexpectedEnd)?;
+        self.position += 1;
+
+        Ok(next)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(value)) if value.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        let (position, token) = self.next()?;
+
+        if token == expected {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedToken(position, token.to_string()))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_and()?;
+
+        while self.peek_keyword("or") {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_not()?;
+
+        while self.peek_keyword("and") {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, Error> {
+        if self.peek_keyword("not") {
+            self.position += 1;
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, Error> {
+        let (position, token) = self.next()?;
+
+        match token {
+            Token::Open => {
+                let expr = self.parse_or()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Token::Word(word) if self.peek() == Some(&Token::Open) => {
+                let quantifier = match word.to_lowercase().as_str() {
+                    "any" => Quantifier::Any,
+                    "latest" => Quantifier::Latest,
+                    "all" => Quantifier::All,
+                    _ => return Err(Error::UnexpectedToken(position, word)),
+                };
+                self.position += 1;
+
+                let expr = self.parse_or()?;
+                self.expect(Token::Close)?;
+
+                if expr.is_quantified() {
+                    Err(Error::NestedQuantifier)
+                } else {
+                    Ok(Expr::Quantified(quantifier, Box::new(expr)))
+                }
+            }
+            Token::Word(word) => {
+                let field: Field = word.parse()?;
+                let operator = match self.next()? {
+                    (_, Token::Op(op)) => op,
+                    (position, other) => {
+                        return Err(Error::UnexpectedToken(position, other.to_string()))
+                    }
+                };
+                let value = match self.next()? {
+                    (_, Token::Word(value)) | (_, Token::Str(value)) => value,
+                    (position, other) => {
+                        return Err(Error::UnexpectedToken(position, other.to_string()))
+                    }
+                };
+                let value = match value.as_str() {
+                    "true" if field.is_boolean() => "1".to_string(),
+                    "false" if field.is_boolean() => "0".to_string(),
+                    _ => value,
+                };
+
+                let operator = match operator {
+                    "=" => Operator::Eq,
+                    "!=" => Operator::Ne,
+                    "<" => Operator::Lt,
+                    "<=" => Operator::Le,
+                    ">" => Operator::Gt,
+                    ">=" => Operator::Ge,
+                    "~" => Operator::Contains,
+                    _ => Operator::Matches(Regex::new(&value)?),
+                };
+
+                Ok(Expr::Compare {
+                    field,
+                    operator,
+                    value,
+                })
+            }
+            other => Err(Error::UnexpectedToken(position, other.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Entities, Entity, Url};
+    use crate::test_util::user;
+
+    fn parse(query: &str) -> Expr {
+        query.parse().unwrap()
+    }
+
+    fn profile(snapshot: i64, followers_count: i64, verified: bool, description: &str) -> User {
+        User {
+            followers_count,
+            verified,
+            description: Some(description.to_string()),
+            ..user(1, "foo", snapshot)
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            "followers_count >".parse::<Expr>(),
+            Err(Error::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            "name ~ 'foo".parse::<Expr>(),
+            Err(Error::UnterminatedString(7))
+        ));
+        assert!(matches!(
+            "name ~ foo )".parse::<Expr>(),
+            Err(Error::UnexpectedToken(11, _))
+        ));
+        assert!(matches!(
+            "name ~ foo & verified = 1".parse::<Expr>(),
+            Err(Error::UnexpectedCharacter(11, '&'))
+        ));
+        assert!(matches!(
+            "nonexistent = 1".parse::<Expr>(),
+            Err(Error::UnknownField(field)) if field == "nonexistent"
+        ));
+        assert!(matches!(
+            "some(verified = 1)".parse::<Expr>(),
+            Err(Error::UnexpectedToken(0, _))
+        ));
+        assert!(matches!(
+            "description =~ '('".parse::<Expr>(),
+            Err(Error::InvalidRegex(_))
+        ));
+        assert!(matches!(
+            "any(latest(verified = 1))".parse::<Expr>(),
+            Err(Error::NestedQuantifier)
+        ));
+    }
+
+    #[test]
+    fn precedence() {
+        let user = profile(0, 10, false, "");
+
+        // `and` binds more tightly than `or`, and `not` more tightly than both.
+        assert!(
+            parse("verified = 1 and followers_count = 0 or followers_count = 10").matches(&user)
+        );
+        assert!(
+            !parse("verified = 1 and (followers_count = 0 or followers_count = 10)").matches(&user)
+        );
+        assert!(parse("not verified = 1 and followers_count = 10").matches(&user));
+        assert!(!parse("not (verified = 0 and followers_count = 10)").matches(&user));
+        assert!(parse("NOT verified = 1 AND followers_count = 10").matches(&user));
+    }
+
+    #[test]
+    fn compare_values() {
+        let user = profile(0, 9, false, "Sputnik news");
+
+        // Integers are compared numerically, and everything else lexicographically.
+        assert!(parse("followers_count < 10").matches(&user));
+        assert!(parse("followers_count = 09").matches(&user));
+        assert!(!parse("followers_count >= 10").matches(&user));
+        assert!(parse("description > 'Sputnik a'").matches(&user));
+        assert!(parse("description != sputnik").matches(&user));
+        assert!(parse("screen_name = foo").matches(&user));
+
+        assert!(parse("description ~ 'SPUTNIK'").matches(&user));
+        assert!(!parse("description =~ '^news'").matches(&user));
+        assert!(parse(r#"description =~ "(?i)^sputnik\s""#).matches(&user));
+    }
+
+    #[test]
+    fn boolean_literals() {
+        let verified = profile(0, 0, true, "true");
+        let unverified = profile(0, 0, false, "false");
+
+        assert!(parse("verified = true").matches(&verified));
+        assert!(!parse("verified = true").matches(&unverified));
+        assert!(parse("verified = false").matches(&unverified));
+        assert!(parse("verified = 1 and protected = false").matches(&verified));
+
+        // The literals are only rewritten for boolean fields.
+        assert!(parse("description = true").matches(&verified));
+        assert!(parse("description = 'false'").matches(&unverified));
+    }
+
+    #[test]
+    fn urls() {
+        let entity = |urls: &[&str]| Entity {
+            urls: urls
+                .iter()
+                .map(|url| Url {
+                    expanded_url: Some(url.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let user = User {
+            entities: Some(Entities {
+                url: Some(entity(&["https://example.com/"])),
+                description: Some(entity(&["https://t.me/foo", "https://rt.com/"])),
+            }),
+            ..user(1, "foo", 0)
+        };
+
+        assert!(parse("urls ~ 'rt.com'").matches(&user));
+        assert!(parse("urls = 'https://example.com/'").matches(&user));
+        assert!(!parse("urls ~ 'sputnik'").matches(&user));
+        assert!(!parse("urls ~ 'rt.com'").matches(&User::default()));
+    }
+
+    #[test]
+    fn quantifiers() {
+        let history = vec![
+            profile(1, 10, false, "a"),
+            profile(2, 20, true, "b"),
+            profile(3, 30, false, "c"),
+        ];
+
+        assert!(parse("any(verified = true)").matches_history(&history));
+        assert!(!parse("latest(verified = true)").matches_history(&history));
+        assert!(!parse("all(verified = true)").matches_history(&history));
+        assert!(parse("latest(followers_count = 30)").matches_history(&history));
+        assert!(parse("all(followers_count >= 10)").matches_history(&history));
+        assert!(parse("any(verified = 1) and not latest(verified = 1)").matches_history(&history));
+        assert!(!parse("not any(verified = 1)").matches_history(&history));
+
+        assert!(!parse("any(verified = 1)").matches_history(&[]));
+        assert!(!parse("latest(verified = 0)").matches_history(&[]));
+        assert!(!parse("all(verified = 0)").matches_history(&[]));
+
+        // Against a single user object the quantifiers have no effect.
+        assert!(parse("all(verified = 1)").matches(&history[1]));
+        assert!(!parse("latest(verified = 1)").matches(&history[2]));
+    }
+
+    #[test]
+    fn per_snapshot_matching() {
+        let history = vec![profile(1, 10, true, "a"), profile(2, 20, false, "b")];
+
+        // Without quantifiers, a single snapshot has to satisfy the whole expression.
+        assert!(!parse("verified = 1 and followers_count = 20").matches_history(&history));
+        assert!(parse("verified = 1 and followers_count = 10").matches_history(&history));
+        assert!(parse("not verified = 1").matches_history(&history));
+        assert!(!parse("verified = 1 and followers_count = 20").matches_history(&[]));
+
+        // Quantified subexpressions are evaluated against the whole history independently.
+        assert!(parse("any(verified = 1) and any(followers_count = 20)").matches_history(&history));
+        assert!(parse("any(verified = 1) and followers_count = 20").matches_history(&history));
+    }
+
+    #[test]
+    fn is_quantified() {
+        assert!(!parse("verified = 1 and (name ~ foo or not protected = 1)").is_quantified());
+        assert!(
+            parse("verified = 1 and (name ~ foo or not latest(protected = 1))").is_quantified()
+        );
+        assert!(parse("ANY(verified = 1)").is_quantified());
+    }
+}
@@ -0,0 +1,280 @@
+//! External sorting for user objects.
+//!
+//! Users are buffered in memory up to a fixed count, and each full buffer is sorted and written to
+//! a temporary Avro file (a run). The runs are then merged, at most `MAX_MERGE_WIDTH` at a time,
+//! so memory use and the number of open files are bounded however many users are sorted. The sort
+//! is stable: users with equal keys stay in the order they were added.
+
+use super::model::User;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The maximum number of runs that are merged (and open) at once.
+const MAX_MERGE_WIDTH: usize = 64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Avro error")]
+    Avro(#[from] apache_avro::Error),
+    #[error("User profile Avro error")]
+    UserAvro(#[from] super::avro::Error),
+}
+
+pub struct Sorter<F> {
+    directory: PathBuf,
+    run_size: usize,
+    key: F,
+    buffer: Vec<User>,
+    runs: Vec<PathBuf>,
+    run_count: usize,
+}
+
+impl<K: Ord, F: Fn(&User) -> K> Sorter<F> {
+    /// Create a sorter that keeps at most `run_size` users in memory and writes its runs to
+    /// `directory` (which is created if necessary, and isn't removed).
+    pub fn new<P: AsRef<Path>>(directory: P, run_size: usize, key: F) -> Result<Self, Error> {
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(Self {
+            directory: directory.as_ref().to_path_buf(),
+            run_size: run_size.max(1),
+            key,
+            buffer: vec![],
+            runs: vec![],
+            run_count: 0,
+        })
+    }
+
+    pub fn push(&mut self, user: User) -> Result<(), Error> {
+        self.buffer.push(user);
+
+        if self.buffer.len() >= self.run_size {
+            self.write_run()?;
+        }
+
+        Ok(())
+    }
+
+    /// Return all of the users in key order.
+    ///
+    /// Nothing is written if all of the users fit in memory. Run files are removed once they have
+    /// been merged.
+    pub fn finish(mut self) -> Result<Sorted<K, F>, Error> {
+        if self.runs.is_empty() {
+            let key = &self.key;
+            self.buffer.sort_by_cached_key(|user| key(user));
+
+            return Ok(Sorted::Buffer(std::mem::take(&mut self.buffer).into_iter()));
+        }
+
+        self.write_run()?;
+
+        while self.runs.len() > MAX_MERGE_WIDTH {
+            let runs = std::mem::take(&mut self.runs);
+
+            for group in runs.chunks(MAX_MERGE_WIDTH) {
+                let path = self.next_run_path();
+                let mut writer = super::avro::writer(BufWriter::new(File::create(&path)?));
+
+                for user in Merge::new(group.to_vec(), &self.key)? {
+                    writer.append_ser(user?)?;
+                }
+
+                writer.into_inner()?.flush()?;
+                self.runs.push(path);
+            }
+        }
+
+        Ok(Sorted::Merge(Merge::new(
+            std::mem::take(&mut self.runs),
+            self.key,
+        )?))
+    }
+
+    fn write_run(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            let key = &self.key;
+            self.buffer.sort_by_cached_key(|user| key(user));
+
+            let path = self.next_run_path();
+            let mut writer = super::avro::writer(BufWriter::new(File::create(&path)?));
+
+            for user in self.buffer.drain(..) {
+                writer.append_ser(user)?;
+            }
+
+            writer.into_inner()?.flush()?;
+            self.runs.push(path);
+        }
+
+        Ok(())
+    }
+
+    fn next_run_path(&mut self) -> PathBuf {
+        let path = self.directory.join(format!("run-{}.avro", self.run_count));
+        self.run_count += 1;
+        path
+    }
+}
+
+/// The sorted users from a `Sorter`.
+pub enum Sorted<K, F> {
+    Buffer(std::vec::IntoIter<User>),
+    Merge(Merge<K, F>),
+}
+
+impl<K: Ord, F: Fn(&User) -> K> Iterator for Sorted<K, F> {
+    type Item = Result<User, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Buffer(users) => users.next().map(Ok),
+            Self::Merge(merge) => merge.next(),
+        }
+    }
+}
+
+type Run = Box<dyn Iterator<Item = Result<User, Error>>>;
+
+/// A merge of sorted run files, which are removed when the merge is dropped.
+pub struct Merge<K, F> {
+    paths: Vec<PathBuf>,
+    runs: Vec<Run>,
+    /// The next user from each run.
+    heads: Vec<Option<User>>,
+    /// The keys of the next users, with the index of their run (to keep the merge stable).
+    heap: BinaryHeap<Reverse<(K, usize)>>,
+    key: F,
+}
+
+impl<K: Ord, F: Fn(&User) -> K> Merge<K, F> {
+    fn new(paths: Vec<PathBuf>, key: F) -> Result<Self, Error> {
+        let mut merge = Self {
+            paths,
+            runs: vec![],
+            heads: vec![],
+            heap: BinaryHeap::new(),
+            key,
+        };
+
+        for path in &merge.paths {
+            let reader = super::avro::reader(BufReader::new(File::open(path)?))?;
+
+            merge.runs.push(Box::new(
+                reader.map(|value| Ok(apache_avro::from_value::<User>(&value?)?)),
+            ));
+            merge.heads.push(None);
+        }
+
+        for index in 0..merge.runs.len() {
+            merge.advance(index)?;
+        }
+
+        Ok(merge)
+    }
+
+    fn advance(&mut self, index: usize) -> Result<(), Error> {
+        if let Some(user) = self.runs[index].next().transpose()? {
+            self.heap.push(Reverse(((self.key)(&user), index)));
+            self.heads[index] = Some(user);
+        }
+
+        Ok(())
+    }
+}
+
+impl<K: Ord, F: Fn(&User) -> K> Iterator for Merge<K, F> {
+    type Item = Result<User, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, index)) = self.heap.pop()?;
+        let user = self.heads[index].take()?;
+
+        match self.advance(index) {
+            Ok(()) => Some(Ok(user)),
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+impl<K, F> Drop for Merge<K, F> {
+    fn drop(&mut self) {
+        // Close the files before removing them.
+        self.runs.clear();
+
+        for path in &self.paths {
+            if let Err(error) = std::fs::remove_file(path) {
+                log::warn!("Could not remove sort run {:?}: {:?}", path, error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, user};
+
+    /// Users with snapshots that cycle through a few values, so that many have equal keys.
+    fn users(count: i64) -> Vec<User> {
+        (0..count)
+            .map(|id| user(id, &format!("user{}", id), id * 7 % 5))
+            .collect()
+    }
+
+    fn sort(users: Vec<User>, run_size: usize, directory: &Path) -> Vec<User> {
+        let mut sorter = Sorter::new(directory, run_size, |user: &User| user.snapshot).unwrap();
+
+        for user in users {
+            sorter.push(user).unwrap();
+        }
+
+        sorter
+            .finish()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn expected(mut users: Vec<User>) -> Vec<User> {
+        users.sort_by_key(|user| user.snapshot);
+        users
+    }
+
+    #[test]
+    fn sort_in_memory() {
+        let directory = temp_dir("sort-in-memory");
+
+        assert_eq!(sort(users(100), 100, &directory), expected(users(100)));
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn sort_runs() {
+        let directory = temp_dir("sort-runs");
+
+        // Uneven runs, and more runs than can be merged at once (which takes two passes).
+        for run_size in [7, 1] {
+            assert_eq!(sort(users(300), run_size, &directory), expected(users(300)));
+            assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        }
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn sort_empty() {
+        let directory = temp_dir("sort-empty");
+
+        assert_eq!(sort(vec![], 10, &directory), vec![]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
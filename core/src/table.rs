@@ -0,0 +1,276 @@
+//! Delimited (CSV or TSV) output for user objects with configurable columns.
+
+use super::model::User;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("CSV error")]
+    Csv(#[from] csv::Error),
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Unknown column: {0}")]
+    UnknownColumn(String),
+    #[error("Unknown format: {0}")]
+    UnknownFormat(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Id,
+    IdStr,
+    ScreenName,
+    Name,
+    Location,
+    Description,
+    Url,
+    /// The first expanded URL for the profile's `url` field.
+    ExpandedUrl,
+    Protected,
+    Verified,
+    FollowersCount,
+    FriendsCount,
+    ListedCount,
+    FavouritesCount,
+    StatusesCount,
+    /// The parsed `created_at` value as an epoch second (empty if it can't be parsed).
+    CreatedAt,
+    /// The `created_at` value as it appears in the Twitter API response.
+    CreatedAtRaw,
+    UtcOffset,
+    TimeZone,
+    GeoEnabled,
+    Lang,
+    ProfileImageUrlHttps,
+    ProfileBannerUrl,
+    DefaultProfile,
+    DefaultProfileImage,
+    WithheldScope,
+    /// Country codes separated by semicolons.
+    WithheldInCountries,
+    Snapshot,
+}
+
+impl Column {
+    pub const ALL: [Column; 28] = [
+        Column::Id,
+        Column::IdStr,
+        Column::ScreenName,
+        Column::Name,
+        Column::Location,
+        Column::Description,
+        Column::Url,
+        Column::ExpandedUrl,
+        Column::Protected,
+        Column::Verified,
+        Column::FollowersCount,
+        Column::FriendsCount,
+        Column::ListedCount,
+        Column::FavouritesCount,
+        Column::StatusesCount,
+        Column::CreatedAt,
+        Column::CreatedAtRaw,
+        Column::UtcOffset,
+        Column::TimeZone,
+        Column::GeoEnabled,
+        Column::Lang,
+        Column::ProfileImageUrlHttps,
+        Column::ProfileBannerUrl,
+        Column::DefaultProfile,
+        Column::DefaultProfileImage,
+        Column::WithheldScope,
+        Column::WithheldInCountries,
+        Column::Snapshot,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::IdStr => "id_str",
+            Column::ScreenName => "screen_name",
+            Column::Name => "name",
+            Column::Location => "location",
+            Column::Description => "description",
+            Column::Url => "url",
+            Column::ExpandedUrl => "expanded_url",
+            Column::Protected => "protected",
+            Column::Verified => "verified",
+            Column::FollowersCount => "followers_count",
+            Column::FriendsCount => "friends_count",
+            Column::ListedCount => "listed_count",
+            Column::FavouritesCount => "favourites_count",
+            Column::StatusesCount => "statuses_count",
+            Column::CreatedAt => "created_at",
+            Column::CreatedAtRaw => "created_at_raw",
+            Column::UtcOffset => "utc_offset",
+            Column::TimeZone => "time_zone",
+            Column::GeoEnabled => "geo_enabled",
+            Column::Lang => "lang",
+            Column::ProfileImageUrlHttps => "profile_image_url_https",
+            Column::ProfileBannerUrl => "profile_banner_url",
+            Column::DefaultProfile => "default_profile",
+            Column::DefaultProfileImage => "default_profile_image",
+            Column::WithheldScope => "withheld_scope",
+            Column::WithheldInCountries => "withheld_in_countries",
+            Column::Snapshot => "snapshot",
+        }
+    }
+
+    /// Render the value of this column for the given user.
+    ///
+    /// Missing values are empty strings and booleans are rendered as `1` or `0`.
+    pub fn value(&self, user: &User) -> String {
+        match self {
+            Column::Id => user.id.to_string(),
+            Column::IdStr => user.id_str.clone(),
+            Column::ScreenName => user.screen_name.clone(),
+            Column::Name => user.name.clone(),
+            Column::Location => user.location.clone().unwrap_or_default(),
+            Column::Description => user.description.clone().unwrap_or_default(),
+            Column::Url => user.url.clone().unwrap_or_default(),
+            Column::ExpandedUrl => user.expanded_url().unwrap_or_default(),
+            Column::Protected => bool_value(user.protected),
+            Column::Verified => bool_value(user.verified),
+            Column::FollowersCount => user.followers_count.to_string(),
+            Column::FriendsCount => user.friends_count.to_string(),
+            Column::ListedCount => user.listed_count.to_string(),
+            Column::FavouritesCount => user.favourites_count.to_string(),
+            Column::StatusesCount => user.statuses_count.to_string(),
+            Column::CreatedAt => user
+                .created_at()
+                .map(|created_at| created_at.timestamp().to_string())
+                .unwrap_or_default(),
+            Column::CreatedAtRaw => user.created_at.clone(),
+            Column::UtcOffset => user
+                .utc_offset
+                .map(|utc_offset| utc_offset.to_string())
+                .unwrap_or_default(),
+            Column::TimeZone => user.time_zone.clone().unwrap_or_default(),
+            Column::GeoEnabled => user.geo_enabled.map(bool_value).unwrap_or_default(),
+            Column::Lang => user.lang.clone().unwrap_or_default(),
+            Column::ProfileImageUrlHttps => user.profile_image_url_https.clone(),
+            Column::ProfileBannerUrl => user.profile_banner_url.clone().unwrap_or_default(),
+            Column::DefaultProfile => bool_value(user.default_profile),
+            Column::DefaultProfileImage => bool_value(user.default_profile_image),
+            Column::WithheldScope => user.withheld_scope.clone().unwrap_or_default(),
+            Column::WithheldInCountries => user.withheld_in_countries.join(";"),
+            Column::Snapshot => user.snapshot.to_string(),
+        }
+    }
+}
+
+impl Display for Column {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Column {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .iter()
+            .find(|column| column.name() == s)
+            .copied()
+            .ok_or_else(|| Error::UnknownColumn(s.to_string()))
+    }
+}
+
+/// A comma-separated list of column names (for use as a command-line argument).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Columns(pub Vec<Column>);
+
+impl FromStr for Columns {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|name| name.trim().parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map(Columns)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Tsv,
+}
+
+impl Format {
+    fn delimiter(&self) -> u8 {
+        match self {
+            Format::Csv => b',',
+            Format::Tsv => b'\t',
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            other => Err(Error::UnknownFormat(other.to_string())),
+        }
+    }
+}
+
+/// Writes one quoted CSV or TSV record per user.
+///
+/// Commands that compute values that aren't part of the user object (such as first-seen
+/// timestamps) can pass them as extra columns, which are written after the selected columns.
+pub struct UserWriter<W: Write> {
+    underlying: csv::Writer<W>,
+    columns: Vec<Column>,
+}
+
+impl<W: Write> UserWriter<W> {
+    pub fn new(writer: W, format: Format, columns: Vec<Column>) -> Self {
+        let underlying = csv::WriterBuilder::new()
+            .delimiter(format.delimiter())
+            .from_writer(writer);
+
+        Self {
+            underlying,
+            columns,
+        }
+    }
+
+    pub fn write_header(&mut self, extra: &[&str]) -> Result<(), Error> {
+        let names = self.columns.iter().map(|column| column.name());
+        self.underlying
+            .write_record(names.chain(extra.iter().copied()))?;
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, user: &User) -> Result<(), Error> {
+        self.write_with_extra::<&str>(user, &[])
+    }
+
+    pub fn write_with_extra<S: AsRef<str>>(
+        &mut self,
+        user: &User,
+        extra: &[S],
+    ) -> Result<(), Error> {
+        let values = self.columns.iter().map(|column| column.value(user));
+        self.underlying
+            .write_record(values.chain(extra.iter().map(|value| value.as_ref().to_string())))?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.underlying.flush()?)
+    }
+}
+
+fn bool_value(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
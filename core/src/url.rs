@@ -0,0 +1,231 @@
+//! Normalization for the URLs that appear in profile entities.
+//!
+//! Normalized URLs use the `https` scheme, a lowercase host without a leading `www.`, no
+//! fragment, no tracking query parameters (`utm_*`, `fbclid`, etc.), and no port if it's the
+//! default for either `http` or `https` (since both schemes are normalized to `https`). Paths are
+//! case-sensitive and are left as they are.
+//!
+//! The registrable domain is an approximation of the public suffix algorithm: it keeps the last
+//! two labels of the host, or three when the last two form a common second-level suffix such as
+//! `co.uk`.
+
+use ::url::{Host, Url};
+
+const TRACKING_PARAMETERS: [&str; 10] = [
+    "fbclid", "gclid", "dclid", "msclkid", "igshid", "mc_cid", "mc_eid", "ref_src", "ref_url", "si",
+];
+
+const SHORTENERS: [&str; 20] = [
+    "bit.ly",
+    "buff.ly",
+    "cutt.ly",
+    "dlvr.it",
+    "fb.me",
+    "goo.gl",
+    "ift.tt",
+    "is.gd",
+    "lnkd.in",
+    "ow.ly",
+    "rebrand.ly",
+    "shorturl.at",
+    "t.co",
+    "t.ly",
+    "tiny.cc",
+    "tinyurl.com",
+    "trib.al",
+    "v.gd",
+    "wp.me",
+    "youtu.be",
+];
+
+const SECOND_LEVEL_SUFFIXES: [&str; 24] = [
+    "ac.jp", "ac.uk", "co.id", "co.il", "co.in", "co.jp", "co.kr", "co.nz", "co.uk", "co.za",
+    "com.ar", "com.au", "com.br", "com.cn", "com.mx", "com.pl", "com.sg", "com.tr", "com.tw",
+    "com.ua", "gov.uk", "ne.jp", "or.jp", "org.uk",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NormalizedUrl {
+    pub url: String,
+    pub host: String,
+    pub domain: String,
+    /// Whether the host is a known link shortener (in which case the domain says little about
+    /// the link's destination).
+    pub is_shortener: bool,
+}
+
+/// Normalize a URL, adding an `http` scheme if there isn't one.
+///
+/// Returns `None` if the value can't be parsed as a URL with a host.
+pub fn normalize(input: &str) -> Option<NormalizedUrl> {
+    let input = input.trim();
+    let mut url = Url::parse(input)
+        .ok()
+        .filter(|url| url.has_host())
+        .or_else(|| Url::parse(&format!("http://{}", input)).ok())?;
+
+    let host = match url.host()? {
+        Host::Domain(domain) => strip_www(&domain.to_lowercase()).to_string(),
+        other => other.to_string(),
+    };
+
+    let query = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_parameter(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    url.set_fragment(None);
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    let authority = match url.port_or_known_default() {
+        Some(80 | 443) | None => host.clone(),
+        Some(port) => format!("{}:{}", host, port),
+    };
+    let path = url.path().trim_end_matches('/');
+    let normalized = match url.query() {
+        Some(query) => format!("https://{}{}?{}", authority, path, query),
+        None => format!("https://{}{}", authority, path),
+    };
+
+    Some(NormalizedUrl {
+        url: normalized,
+        domain: registrable_domain(&host),
+        is_shortener: is_shortener(&host),
+        host,
+    })
+}
+
+/// Approximate the registrable domain for a host (see the module documentation).
+pub fn registrable_domain(host: &str) -> String {
+    let host = strip_www(host.trim_end_matches('.'));
+
+    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return host.to_string();
+    }
+
+    let labels = host.split('.').collect::<Vec<_>>();
+    let count = if labels.len() >= 3
+        && SECOND_LEVEL_SUFFIXES.contains(&labels[labels.len() - 2..].join(".").as_str())
+    {
+        3
+    } else {
+        2
+    };
+
+    labels[labels.len().saturating_sub(count)..]
+        .join(".")
+        .to_lowercase()
+}
+
+/// Whether a host (or registrable domain) is a known link shortener.
+pub fn is_shortener(host: &str) -> bool {
+    SHORTENERS.contains(&strip_www(host))
+}
+
+fn is_tracking_parameter(key: &str) -> bool {
+    key.starts_with("utm_") || TRACKING_PARAMETERS.contains(&key)
+}
+
+fn strip_www(host: &str) -> &str {
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(input: &str) -> String {
+        normalize(input).unwrap().url
+    }
+
+    #[test]
+    fn normalize_scheme() {
+        assert_eq!(normalized("http://example.com/a"), "https://example.com/a");
+        assert_eq!(normalized("https://example.com/a"), "https://example.com/a");
+        assert_eq!(normalized("example.com/a"), "https://example.com/a");
+    }
+
+    #[test]
+    fn normalize_www_and_case() {
+        assert_eq!(
+            normalized("HTTPS://WWW.Example.COM/Some/Path"),
+            "https://example.com/Some/Path"
+        );
+        assert_eq!(normalize("www.example.com").unwrap().host, "example.com");
+    }
+
+    #[test]
+    fn normalize_trailing_slash() {
+        assert_eq!(normalized("https://example.com/"), "https://example.com");
+        assert_eq!(
+            normalized("https://example.com/a/"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_ports() {
+        assert_eq!(
+            normalized("http://example.com:80/a"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalized("http://example.com:443/a"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalized("https://example.com:80/a"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalized("http://example.com:8080/a"),
+            "https://example.com:8080/a"
+        );
+        assert_eq!(
+            normalized("example.com:8443/a"),
+            "https://example.com:8443/a"
+        );
+    }
+
+    #[test]
+    fn normalize_query_and_fragment() {
+        assert_eq!(
+            normalized("https://example.com/a?utm_source=x&id=1&fbclid=abc#top"),
+            "https://example.com/a?id=1"
+        );
+        assert_eq!(
+            normalized("https://example.com/a?utm_medium=y"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_invalid() {
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("not a url"), None);
+    }
+
+    #[test]
+    fn normalize_shortener() {
+        let url = normalize("http://bit.ly/abc").unwrap();
+
+        assert!(url.is_shortener);
+        assert!(!normalize("https://example.com").unwrap().is_shortener);
+        assert!(is_shortener("www.tinyurl.com"));
+        assert!(!is_shortener("bitly.com"));
+    }
+
+    #[test]
+    fn registrable_domains() {
+        assert_eq!(registrable_domain("example.com"), "example.com");
+        assert_eq!(registrable_domain("a.b.example.com"), "example.com");
+        assert_eq!(registrable_domain("www.example.com"), "example.com");
+        assert_eq!(registrable_domain("news.bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(registrable_domain("127.0.0.1"), "127.0.0.1");
+    }
+}
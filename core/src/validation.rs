@@ -0,0 +1,258 @@
+use super::model::{Entity, User};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+const DEFAULT_SAMPLE_SIZE: usize = 10;
+
+/// A record-level check that can be run against individual user objects.
+pub trait Check {
+    /// A short identifier that is used as the key for this check in reports.
+    fn name(&self) -> &'static str;
+
+    /// Returns a description of the problem if the user object fails this check.
+    fn check(&self, user: &User) -> Option<String>;
+}
+
+/// Checks that `id_str` is the string representation of `id`.
+pub struct IdMatchesIdStr;
+
+impl Check for IdMatchesIdStr {
+    fn name(&self) -> &'static str {
+        "id-matches-id-str"
+    }
+
+    fn check(&self, user: &User) -> Option<String> {
+        if user.id_str.parse::<i64>().ok() == Some(user.id) {
+            None
+        } else {
+            Some(format!(
+                "id {} does not match id_str {:?}",
+                user.id, user.id_str
+            ))
+        }
+    }
+}
+
+/// Checks that `created_at` is in the expected Twitter date-time format.
+pub struct CreatedAtParses;
+
+impl Check for CreatedAtParses {
+    fn name(&self) -> &'static str {
+        "created-at-parses"
+    }
+
+    fn check(&self, user: &User) -> Option<String> {
+        user.created_at()
+            .err()
+            .map(|error| format!("invalid created_at {:?}: {}", user.created_at, error))
+    }
+}
+
+/// Checks that the snapshot timestamp does not fall before the account's creation time.
+///
+/// Records with a `created_at` value that cannot be parsed are not reported by this check.
+pub struct SnapshotAfterCreation;
+
+impl Check for SnapshotAfterCreation {
+    fn name(&self) -> &'static str {
+        "snapshot-after-creation"
+    }
+
+    fn check(&self, user: &User) -> Option<String> {
+        let created_at = user.created_at().ok()?;
+
+        if user.snapshot < created_at.timestamp() {
+            Some(format!(
+                "snapshot {} is before created_at {}",
+                user.snapshot,
+                created_at.timestamp()
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Checks that the indices of URL entities fall within the text they refer to.
+///
+/// Indices for `entities.url` refer to the profile's `url` field, and indices for
+/// `entities.description` refer to `description`. Both are counted in characters.
+pub struct EntityIndicesInRange;
+
+impl EntityIndicesInRange {
+    fn check_entity(field: &str, entity: &Entity, text: Option<&String>) -> Option<String> {
+        let length = text.map(|text| text.chars().count()).unwrap_or_default() as i64;
+
+        entity
+            .urls
+            .iter()
+            .find_map(|url| match url.indices.as_slice() {
+                [start, end] if 0 <= *start && start <= end && *end <= length => None,
+                indices => Some(format!(
+                    "{} entity indices {:?} out of range for length {}",
+                    field, indices, length
+                )),
+            })
+    }
+}
+
+impl Check for EntityIndicesInRange {
+    fn name(&self) -> &'static str {
+        "entity-indices-in-range"
+    }
+
+    fn check(&self, user: &User) -> Option<String> {
+        let entities = user.entities.as_ref()?;
+
+        entities
+            .url
+            .as_ref()
+            .and_then(|entity| Self::check_entity("url", entity, user.url.as_ref()))
+            .or_else(|| {
+                entities.description.as_ref().and_then(|entity| {
+                    Self::check_entity("description", entity, user.description.as_ref())
+                })
+            })
+    }
+}
+
+pub fn default_checks() -> Vec<Box<dyn Check>> {
+    vec![
+        Box::new(IdMatchesIdStr),
+        Box::new(CreatedAtParses),
+        Box::new(SnapshotAfterCreation),
+        Box::new(EntityIndicesInRange),
+    ]
+}
+
+/// Runs a set of checks against a stream of user objects and collects the results.
+pub struct Validator {
+    checks: Vec<Box<dyn Check>>,
+    sample_size: usize,
+    report: Report,
+}
+
+impl Validator {
+    pub fn new(checks: Vec<Box<dyn Check>>, sample_size: usize) -> Self {
+        let report = Report {
+            record_count: 0,
+            checks: checks
+                .iter()
+                .map(|check| (check.name().to_string(), CheckReport::default()))
+                .collect(),
+        };
+
+        Self {
+            checks,
+            sample_size,
+            report,
+        }
+    }
+
+    /// Check a single record, returning `true` if it passes all checks.
+    ///
+    /// The index is only used to identify the record in samples (for example a line number).
+    pub fn validate(&mut self, index: usize, user: &User) -> bool {
+        let mut valid = true;
+        self.report.record_count += 1;
+
+        for check in &self.checks {
+            if let Some(message) = check.check(user) {
+                valid = false;
+
+                let check_report = self
+                    .report
+                    .checks
+                    .entry(check.name().to_string())
+                    .or_default();
+                check_report.failure_count += 1;
+
+                if check_report.samples.len() < self.sample_size {
+                    check_report.samples.push(Sample {
+                        index,
+                        user_id: user.id,
+                        screen_name: user.screen_name.clone(),
+                        snapshot: user.snapshot,
+                        message,
+                    });
+                }
+            }
+        }
+
+        valid
+    }
+
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    pub fn into_report(self) -> Report {
+        self.report
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new(default_checks(), DEFAULT_SAMPLE_SIZE)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Report {
+    pub record_count: usize,
+    pub checks: BTreeMap<String, CheckReport>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.checks
+            .values()
+            .all(|check_report| check_report.failure_count == 0)
+    }
+
+    pub fn failure_count(&self) -> usize {
+        self.checks
+            .values()
+            .map(|check_report| check_report.failure_count)
+            .sum()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} records checked", self.record_count)?;
+
+        for (name, check_report) in &self.checks {
+            writeln!(f, "{}: {} failures", name, check_report.failure_count)?;
+
+            for sample in &check_report.samples {
+                writeln!(
+                    f,
+                    "  {}: {} ({}) at {}: {}",
+                    sample.index,
+                    sample.user_id,
+                    sample.screen_name,
+                    sample.snapshot,
+                    sample.message
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct CheckReport {
+    pub failure_count: usize,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Sample {
+    pub index: usize,
+    pub user_id: i64,
+    pub screen_name: String,
+    pub snapshot: i64,
+    pub message: String,
+}
@@ -0,0 +1,445 @@
+//! Named watchlists of link domains, bio keywords, and display name patterns.
+//!
+//! Watchlists are defined in TOML (or JSON, for files with a `.json` extension) as a set of named
+//! categories:
+//!
+//! ```toml
+//! [categories.rt]
+//! domains = ["rt.com", "sputniknews.com"]
+//! keywords = ["@rt_com"]
+//! display_names = ["(?i)\\bsputnik\\b"]
+//! ```
+//!
+//! Domains match the host of any expanded URL in the profile's `url` or `description` entities
+//! (ignoring case and a leading `www.`), including subdomains. Keywords are case-insensitive
+//! substrings of the description, and display name patterns are regular expressions.
+
+use super::{model::User, url::normalize};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    #[error("TOML error")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid display name pattern")]
+    InvalidPattern(#[from] regex::Error),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Config {
+    pub categories: BTreeMap<String, CategoryConfig>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CategoryConfig {
+    pub domains: Vec<String>,
+    pub keywords: Vec<String>,
+    pub display_names: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Category {
+    name: String,
+    domains: Vec<String>,
+    keywords: Vec<String>,
+    display_names: Vec<Regex>,
+}
+
+impl Category {
+    fn new(name: String, config: CategoryConfig) -> Result<Self, Error> {
+        Ok(Self {
+            name,
+            domains: config
+                .domains
+                .iter()
+                .map(|domain| strip_www(&domain.to_lowercase()).to_string())
+                .collect(),
+            keywords: config
+                .keywords
+                .iter()
+                .map(|keyword| keyword.to_lowercase())
+                .collect(),
+            display_names: config
+                .display_names
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn find(&self, user: &User, hosts: &[String]) -> Option<Reason> {
+        hosts
+            .iter()
+            .find_map(|host| {
+                self.domains
+                    .iter()
+                    .find(|domain| {
+                        host == *domain
+                            || (host.ends_with(domain.as_str())
+                                && host[..host.len() - domain.len()].ends_with('.'))
+                    })
+                    .map(|domain| Reason::Domain(domain.clone()))
+            })
+            .or_else(|| {
+                let description = user.description.as_ref()?.to_lowercase();
+
+                self.keywords
+                    .iter()
+                    .find(|keyword| description.contains(keyword.as_str()))
+                    .map(|keyword| Reason::Keyword(keyword.clone()))
+            })
+            .or_else(|| {
+                self.display_names
+                    .iter()
+                    .find(|pattern| pattern.is_match(&user.name))
+                    .map(|pattern| Reason::DisplayName(pattern.as_str().to_string()))
+            })
+    }
+}
+
+/// Why a user object matched a category.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Reason {
+    Domain(String),
+    Keyword(String),
+    DisplayName(String),
+}
+
+/// The first snapshot at which a user matched a category.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Match {
+    pub category: String,
+    pub snapshot: i64,
+    pub reason: Reason,
+}
+
+/// A user with the categories they have matched.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Tagged {
+    pub id: u64,
+    pub screen_name: String,
+    pub matches: Vec<Match>,
+}
+
+impl Tagged {
+    fn update(&mut self, matches: Vec<Match>) {
+        for new_match in matches {
+            match self
+                .matches
+                .iter_mut()
+                .find(|current| current.category == new_match.category)
+            {
+                Some(current) => {
+                    if new_match.snapshot < current.snapshot {
+                        *current = new_match;
+                    }
+                }
+                None => self.matches.push(new_match),
+            }
+        }
+
+        self.matches.sort_by(|a, b| a.category.cmp(&b.category));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchlist {
+    categories: Vec<Category>,
+}
+
+impl Watchlist {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        Ok(Self {
+            categories: config
+                .categories
+                .into_iter()
+                .map(|(name, config)| Category::new(name, config))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let config = if path
+            .extension()
+            .filter(|extension| extension.eq_ignore_ascii_case("json"))
+            .is_some()
+        {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        Self::new(config)
+    }
+
+    /// Return the categories matched by a single user object.
+    pub fn matches(&self, user: &User) -> Vec<Match> {
+        let hosts = hosts(user);
+
+        self.categories
+            .iter()
+            .filter_map(|category| {
+                category.find(user, &hosts).map(|reason| Match {
+                    category: category.name.clone(),
+                    snapshot: user.snapshot,
+                    reason,
+                })
+            })
+            .collect()
+    }
+
+    /// Tag a user given their history (ordered from oldest to newest snapshot).
+    pub fn scan(&self, users: &[User]) -> Option<Tagged> {
+        let mut scanner = Scanner::new(self);
+
+        for user in users {
+            scanner.add(user);
+        }
+
+        scanner.into_results().pop()
+    }
+}
+
+/// Accumulates matches for user objects that may arrive in any order.
+pub struct Scanner<'a> {
+    watchlist: &'a Watchlist,
+    results: HashMap<u64, (i64, Tagged)>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(watchlist: &'a Watchlist) -> Self {
+        Self {
+            watchlist,
+            results: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, user: &User) {
+        let matches = self.watchlist.matches(user);
+
+        if !matches.is_empty() {
+            let (latest, tagged) = self.results.entry(user.id()).or_insert_with(|| {
+                (
+                    user.snapshot,
+                    Tagged {
+                        id: user.id(),
+                        screen_name: user.screen_name.clone(),
+                        matches: vec![],
+                    },
+                )
+            });
+
+            tagged.update(matches);
+
+            // Keep the screen name from the most recent matching snapshot.
+            if user.snapshot >= *latest {
+                tagged.screen_name = user.screen_name.clone();
+                *latest = user.snapshot;
+            }
+        }
+    }
+
+    /// Return the tagged users, sorted by user ID.
+    pub fn into_results(self) -> Vec<Tagged> {
+        let mut results = self
+            .results
+            .into_values()
+            .map(|(_, tagged)| tagged)
+            .collect::<Vec<_>>();
+        results.sort_by_key(|tagged| tagged.id);
+        results
+    }
+}
+
+fn hosts(user: &User) -> Vec<String> {
+    user.entities
+        .iter()
+        .flat_map(|entities| entities.url.iter().chain(entities.description.iter()))
+        .flat_map(|entity| &entity.urls)
+        .filter_map(|url| url.expanded_url.as_deref())
+        .filter_map(normalize)
+        .map(|url| url.host)
+        .collect()
+}
+
+fn strip_www(host: &str) -> &str {
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[categories.rt]
+domains = ["www.RT.com", "sputniknews.com"]
+keywords = ["@RT_com"]
+
+[categories.names]
+display_names = ["(?i)\\bsputnik\\b"]
+"#;
+
+    fn user(snapshot: i64, name: &str, description: &str, expanded_url: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": 12,
+            "id_str": "12",
+            "name": name,
+            "screen_name": format!("user{}", snapshot),
+            "description": description,
+            "entities": {
+                "url": {
+                    "urls": [{
+                        "url": "https://t.co/abc",
+                        "expanded_url": expanded_url,
+                        "indices": [0, 16]
+                    }]
+                }
+            },
+            "protected": false,
+            "followers_count": 0,
+            "friends_count": 0,
+            "listed_count": 0,
+            "created_at": "Tue Mar 21 20:50:14 +0000 2006",
+            "favourites_count": 0,
+            "verified": false,
+            "statuses_count": 0,
+            "profile_image_url_https": "",
+            "default_profile": false,
+            "default_profile_image": false,
+            "withheld_in_countries": [],
+            "snapshot": snapshot
+        }))
+        .unwrap()
+    }
+
+    fn watchlist() -> Watchlist {
+        Watchlist::new(toml::from_str(CONFIG).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+
+        assert_eq!(
+            config.categories.keys().collect::<Vec<_>>(),
+            vec!["names", "rt"]
+        );
+        assert_eq!(config.categories["rt"].keywords, vec!["@RT_com"]);
+        assert!(config.categories["names"].domains.is_empty());
+    }
+
+    #[test]
+    fn load_toml_and_json() {
+        let directory = crate::test_util::temp_dir("watchlist");
+
+        let toml_path = directory.join("watchlist.toml");
+        std::fs::write(&toml_path, CONFIG).unwrap();
+        let json_path = directory.join("watchlist.JSON");
+        std::fs::write(
+            &json_path,
+            r#"{"categories": {"rt": {"domains": ["rt.com"]}}}"#,
+        )
+        .unwrap();
+
+        let from_toml = Watchlist::load(&toml_path).unwrap();
+        let from_json = Watchlist::load(&json_path).unwrap();
+        let user = user(1, "", "", "https://rt.com/news");
+
+        assert_eq!(from_toml.matches(&user).len(), 1);
+        assert_eq!(from_json.matches(&user).len(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let config = toml::from_str("[categories.bad]\ndisplay_names = [\"(\"]").unwrap();
+
+        assert!(matches!(
+            Watchlist::new(config),
+            Err(Error::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn match_domains() {
+        let watchlist = watchlist();
+        let matched = |url| {
+            watchlist
+                .matches(&user(1, "", "", url))
+                .into_iter()
+                .map(|found| found.reason)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            matched("https://WWW.rt.com/a"),
+            vec![Reason::Domain("rt.com".to_string())]
+        );
+        assert_eq!(
+            matched("http://de.rt.com"),
+            vec![Reason::Domain("rt.com".to_string())]
+        );
+        assert!(matched("https://art.com").is_empty());
+        assert!(matched("https://example.com/rt.com").is_empty());
+    }
+
+    #[test]
+    fn match_keywords_and_display_names() {
+        let watchlist = watchlist();
+        let matches = watchlist.matches(&user(1, "Sputnik fan", "Follow @rt_COM", ""));
+
+        assert_eq!(
+            matches,
+            vec![
+                Match {
+                    category: "names".to_string(),
+                    snapshot: 1,
+                    reason: Reason::DisplayName("(?i)\\bsputnik\\b".to_string()),
+                },
+                Match {
+                    category: "rt".to_string(),
+                    snapshot: 1,
+                    reason: Reason::Keyword("@rt_com".to_string()),
+                },
+            ]
+        );
+        assert!(watchlist.matches(&user(1, "Sputniks", "", "")).is_empty());
+    }
+
+    #[test]
+    fn scan_history() {
+        let watchlist = watchlist();
+        let tagged = watchlist
+            .scan(&[
+                user(1, "", "", ""),
+                user(2, "", "", "https://sputniknews.com"),
+                user(3, "sputnik", "", "https://sputniknews.com"),
+                user(4, "", "", ""),
+            ])
+            .unwrap();
+
+        assert_eq!(tagged.id, 12);
+        assert_eq!(tagged.screen_name, "user3");
+        assert_eq!(
+            tagged
+                .matches
+                .iter()
+                .map(|found| (found.category.as_str(), found.snapshot))
+                .collect::<Vec<_>>(),
+            vec![("names", 3), ("rt", 2)]
+        );
+        assert_eq!(watchlist.scan(&[user(1, "", "", "")]), None);
+    }
+}
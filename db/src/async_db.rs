@@ -0,0 +1,189 @@
+//! An async facade for `ProfileDb`.
+//!
+//! RocksDB reads can block for a long time when they hit table files that aren't cached, so every
+//! operation is run on Tokio's blocking thread pool instead of the calling task. A semaphore
+//! bounds the number of operations that run at once. Streams hold a permit until they are finished
+//! or dropped, and are fed through a bounded channel, so a slow consumer pauses the iteration.
+
+use super::counters::CounterSample;
+use super::db::{
+    self, ObservedUserProfiles, ProfileDb, ProfileDbOptions, ProfileIterator, UserProfiles,
+};
+use futures::Stream;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, AcquireError, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinError;
+use twprs::model::User;
+
+pub const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// The number of users buffered by a stream before iteration waits for the consumer.
+const STREAM_BUFFER_SIZE: usize = 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("ProfileDb error")]
+    Db(#[from] db::Error),
+    #[error("Blocking task error")]
+    Join(#[from] JoinError),
+    #[error("Semaphore error")]
+    Semaphore(#[from] AcquireError),
+}
+
+#[derive(Clone)]
+pub struct AsyncProfileDb {
+    db: Arc<ProfileDb>,
+    permits: Arc<Semaphore>,
+}
+
+impl AsyncProfileDb {
+    /// Wrap a database, allowing at most `max_concurrency` (which must be positive) operations to
+    /// run at once.
+    pub fn new(db: ProfileDb, max_concurrency: usize) -> Self {
+        Self {
+            db: Arc::new(db),
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+        }
+    }
+
+    pub async fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ProfileDbOptions,
+        max_concurrency: usize,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let options = options.clone();
+        let db = tokio::task::spawn_blocking(move || ProfileDb::open_with_options(path, &options))
+            .await??;
+
+        Ok(Self::new(db, max_concurrency))
+    }
+
+    pub async fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        options: &ProfileDbOptions,
+        max_concurrency: usize,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let options = options.clone();
+        let db = tokio::task::spawn_blocking(move || ProfileDb::open_read_only(path, &options))
+            .await??;
+
+        Ok(Self::new(db, max_concurrency))
+    }
+
+    /// The underlying database (note that calling its methods directly will block).
+    pub fn blocking(&self) -> &ProfileDb {
+        &self.db
+    }
+
+    pub async fn lookup(&self, user_id: u64) -> Result<UserProfiles, Error> {
+        self.run(move |db| db.lookup(user_id)).await
+    }
+
+    /// Look up the profiles for many user IDs at once (see `ProfileDb::lookup_many`).
+    pub async fn lookup_many(
+        &self,
+        user_ids: Vec<u64>,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, UserProfiles>, Error> {
+        self.run(move |db| db.lookup_many(user_ids, latest_only))
+            .await
+    }
+
+    pub async fn lookup_many_observed(
+        &self,
+        user_ids: Vec<u64>,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, ObservedUserProfiles>, Error> {
+        self.run(move |db| db.lookup_many_observed(user_ids, latest_only))
+            .await
+    }
+
+    pub async fn counter_series(&self, user_id: u64) -> Result<Vec<CounterSample>, Error> {
+        self.run(move |db| db.counter_series(user_id)).await
+    }
+
+    pub async fn update(&self, user: User) -> Result<(), Error> {
+        self.run(move |db| db.update(&user)).await
+    }
+
+    /// Stream the profiles for each user, in user ID order.
+    pub async fn stream(
+        &self,
+    ) -> Result<impl Stream<Item = Result<UserProfiles, Error>> + Send + Unpin, Error> {
+        self.stream_with(|db| db.iter()).await
+    }
+
+    /// Stream the users with IDs in the range from `start_id` (inclusive) to `end_id`
+    /// (exclusive).
+    pub async fn stream_range(
+        &self,
+        start_id: u64,
+        end_id: u64,
+    ) -> Result<impl Stream<Item = Result<UserProfiles, Error>> + Send + Unpin, Error> {
+        self.stream_with(move |db| db.iter_range(start_id, end_id))
+            .await
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, Error> {
+        Ok(self.permits.clone().acquire_owned().await?)
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ProfileDb) -> Result<T, db::Error> + Send + 'static,
+    {
+        let permit = self.acquire().await?;
+        let db = self.db.clone();
+
+        let result = tokio::task::spawn_blocking(move || {
+            let result = f(&db);
+            drop(permit);
+            result
+        })
+        .await??;
+
+        Ok(result)
+    }
+
+    async fn stream_with<F>(
+        &self,
+        iterate: F,
+    ) -> Result<impl Stream<Item = Result<UserProfiles, Error>> + Send + Unpin, Error>
+    where
+        F: FnOnce(&ProfileDb) -> ProfileIterator<'_> + Send + 'static,
+    {
+        let permit = self.acquire().await?;
+        let db = self.db.clone();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+
+        let handle = tokio::task::spawn_blocking(move || {
+            for result in iterate(&db) {
+                // Stop if the stream has been dropped.
+                if sender.blocking_send(result.map_err(Error::from)).is_err() {
+                    break;
+                }
+            }
+
+            drop(permit);
+        });
+
+        Ok(Box::pin(futures::stream::unfold(
+            (receiver, Some(handle)),
+            |(mut receiver, handle)| async move {
+                match receiver.recv().await {
+                    Some(result) => Some((result, (receiver, handle))),
+                    // Report a panic in the iteration instead of silently ending the stream.
+                    None => match handle?.await {
+                        Ok(()) => None,
+                        Err(error) => Some((Err(Error::from(error)), (receiver, None))),
+                    },
+                }
+            },
+        )))
+    }
+}
@@ -0,0 +1,112 @@
+//! Incremental backups of a profile database.
+//!
+//! Backups are created from an open database with `ProfileDb::backup`. Table files are shared
+//! between the backups in a backup directory, so each new backup only copies the files that have
+//! been added since the previous one.
+
+use chrono::{DateTime, TimeZone, Utc};
+use rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions};
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("RocksDb error")]
+    Db(#[from] rocksdb::Error),
+    #[error("Restore target already exists")]
+    AlreadyExists(PathBuf),
+    #[error("No backups found")]
+    NoBackups(PathBuf),
+    #[error("Unknown backup")]
+    UnknownBackup(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+    pub timestamp: DateTime<Utc>,
+    /// Size in bytes (including files shared with other backups).
+    pub size: u64,
+    pub file_count: u32,
+}
+
+impl From<BackupEngineInfo> for BackupInfo {
+    fn from(info: BackupEngineInfo) -> Self {
+        Self {
+            id: info.backup_id,
+            timestamp: Utc
+                .timestamp_opt(info.timestamp, 0)
+                .single()
+                .unwrap_or_default(),
+            size: info.size,
+            file_count: info.num_files,
+        }
+    }
+}
+
+/// List the backups in a directory, ordered by ID.
+pub fn list<P: AsRef<Path>>(backup_dir: P) -> Result<Vec<BackupInfo>, Error> {
+    let engine = open_engine(backup_dir)?;
+
+    Ok(backup_infos(&engine))
+}
+
+/// Check that the files for a backup (or the latest backup) are present and have the expected
+/// sizes.
+pub fn verify<P: AsRef<Path>>(backup_dir: P, id: Option<u32>) -> Result<BackupInfo, Error> {
+    let engine = open_engine(&backup_dir)?;
+    let info = select(&engine, backup_dir.as_ref(), id)?;
+
+    engine.verify_backup(info.id)?;
+
+    Ok(info)
+}
+
+/// Restore a backup (or the latest backup) into a new database directory.
+pub fn restore<P: AsRef<Path>, D: AsRef<Path>>(
+    backup_dir: P,
+    db_path: D,
+    id: Option<u32>,
+) -> Result<BackupInfo, Error> {
+    let db_path = db_path.as_ref();
+
+    if db_path.exists() {
+        return Err(Error::AlreadyExists(db_path.to_path_buf()));
+    }
+
+    let mut engine = open_engine(&backup_dir)?;
+    let info = select(&engine, backup_dir.as_ref(), id)?;
+
+    engine.verify_backup(info.id)?;
+    engine.restore_from_backup(db_path, db_path, &RestoreOptions::default(), info.id)?;
+
+    Ok(info)
+}
+
+pub(crate) fn open_engine<P: AsRef<Path>>(backup_dir: P) -> Result<BackupEngine, rocksdb::Error> {
+    BackupEngine::open(&BackupEngineOptions::default(), backup_dir)
+}
+
+pub(crate) fn backup_infos(engine: &BackupEngine) -> Vec<BackupInfo> {
+    let mut infos = engine
+        .get_backup_info()
+        .into_iter()
+        .map(BackupInfo::from)
+        .collect::<Vec<_>>();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+fn select(engine: &BackupEngine, backup_dir: &Path, id: Option<u32>) -> Result<BackupInfo, Error> {
+    let infos = backup_infos(engine);
+
+    match id {
+        Some(id) => infos
+            .into_iter()
+            .find(|info| info.id == id)
+            .ok_or(Error::UnknownBackup(id)),
+        None => infos
+            .last()
+            .copied()
+            .ok_or_else(|| Error::NoBackups(backup_dir.to_path_buf())),
+    }
+}
@@ -0,0 +1,174 @@
+//! Compare point lookup and full scan latency for the default and tuned database settings.
+//!
+//! Each configuration runs against its own checkpoint of the database, which is compacted with
+//! that configuration's settings first (so that the tuned configuration's table files have prefix
+//! bloom filters), and the original database isn't modified. The configurations are measured in
+//! several rounds, alternating which goes first, so that neither consistently benefits from the
+//! other warming the caches.
+//!
+//! Output is CSV with the columns `config,round,operation,count,total_ms,per_op_us`.
+
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use twprs_db::db::{ProfileDb, ProfileDbOptions};
+
+fn main() -> Result<(), Error> {
+    let opts: Opts = Opts::parse();
+    twprs::cli::init_logging(opts.verbose)?;
+
+    let scratch = opts
+        .scratch
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("{}-bench-{}", opts.db, std::process::id())));
+    std::fs::create_dir(&scratch)?;
+
+    let configs = [
+        ("default", ProfileDbOptions::default()),
+        ("tuned", ProfileDbOptions::tuned()),
+    ];
+
+    let user_ids = {
+        let db = ProfileDb::open_read_only(&opts.db, &ProfileDbOptions::default())?;
+        let mut user_ids = vec![];
+
+        for result in db.raw_iter() {
+            let (user_id, _) = result?;
+
+            if user_ids.last() != Some(&user_id) {
+                user_ids.push(user_id);
+            }
+        }
+
+        for (name, _) in &configs {
+            db.checkpoint(scratch.join(name))?;
+        }
+
+        user_ids
+    };
+
+    let result = run(&opts, &scratch, &configs, &user_ids);
+    std::fs::remove_dir_all(&scratch)?;
+
+    result
+}
+
+fn run(
+    opts: &Opts,
+    scratch: &Path,
+    configs: &[(&str, ProfileDbOptions)],
+    user_ids: &[u64],
+) -> Result<(), Error> {
+    if user_ids.is_empty() {
+        log::warn!("No users in database");
+        return Ok(());
+    }
+
+    let step = (user_ids.len() / opts.lookups.max(1)).max(1);
+    let hits = user_ids
+        .iter()
+        .step_by(step)
+        .take(opts.lookups)
+        .copied()
+        .collect::<Vec<_>>();
+    let misses = hits
+        .iter()
+        .map(|user_id| user_id.wrapping_add(1))
+        .filter(|user_id| user_ids.binary_search(user_id).is_err())
+        .collect::<Vec<_>>();
+
+    log::info!(
+        "Benchmarking {} hits and {} misses over {} users",
+        hits.len(),
+        misses.len(),
+        user_ids.len()
+    );
+
+    let mut dbs = vec![];
+
+    for (name, options) in configs {
+        let db = ProfileDb::open_with_options(scratch.join(name), options)?;
+
+        let start = Instant::now();
+        db.rewrite_tables();
+        log::info!("Compacted with {} settings in {:?}", name, start.elapsed());
+
+        dbs.push((*name, db));
+    }
+
+    for round in 0..opts.rounds {
+        for index in 0..dbs.len() {
+            let (name, db) = &dbs[(index + round) % dbs.len()];
+
+            let start = Instant::now();
+            for user_id in &hits {
+                db.lookup(*user_id)?;
+            }
+            print_result(name, round, "lookup_hit", hits.len(), start.elapsed());
+
+            let start = Instant::now();
+            for user_id in &misses {
+                db.lookup(*user_id)?;
+            }
+            print_result(name, round, "lookup_miss", misses.len(), start.elapsed());
+
+            let start = Instant::now();
+            let mut count = 0;
+            for result in db.iter() {
+                result?;
+                count += 1;
+            }
+            print_result(name, round, "full_scan", count, start.elapsed());
+        }
+    }
+
+    Ok(())
+}
+
+fn print_result(config: &str, round: usize, operation: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{},{},{},{},{:.3},{:.3}",
+        config,
+        round,
+        operation,
+        count,
+        elapsed.as_secs_f64() * 1_000.0,
+        if count == 0 {
+            0.0
+        } else {
+            elapsed.as_secs_f64() * 1_000_000.0 / count as f64
+        }
+    );
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("ProfileDb error")]
+    ProfileDb(#[from] twprs_db::db::Error),
+    #[error("Log initialization error")]
+    LogInitialization(#[from] log::SetLoggerError),
+}
+
+#[derive(Debug, Parser)]
+#[clap(name = "profiles-bench", version, author)]
+struct Opts {
+    /// Level of verbosity
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+    /// Database path
+    #[clap(long)]
+    db: String,
+    /// Number of user IDs to look up
+    #[clap(long, default_value = "10000")]
+    lookups: usize,
+    /// Number of rounds (the order of the configurations alternates between rounds)
+    #[clap(long, default_value = "3")]
+    rounds: usize,
+    /// Directory for the checkpoint copies (must not exist, and should be on the same file
+    /// system as the database; defaults to a sibling of the database)
+    #[clap(long)]
+    scratch: Option<String>,
+}
@@ -1,53 +1,175 @@
 use clap::Parser;
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
-use twprs::model::User;
-use twprs_db::db::ProfileDb;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc::SyncSender;
+use twprs::{
+    cli::TableOpts, model::User, parquet::FileWriter, query::Expr, table::Column,
+    validation::Validator, watchlist::Watchlist,
+};
+use twprs_db::{
+    check::{Action, Mode},
+    db::{ProfileDb, ProfileDbOptions},
+    growth::{self, GrowthOptions},
+};
+
+/// The number of results that parallel scans can get ahead of printing.
+const PRINT_SCAN_BUFFER_SIZE: usize = 1024;
 
 fn main() -> Result<(), Error> {
     let opts: Opts = Opts::parse();
     twprs::cli::init_logging(opts.verbose)?;
-    let db = ProfileDb::open(opts.db, true)?;
+
+    // Restoring creates a new database, so we don't open one first.
+    if let Command::Restore { input, id, list } = &opts.command {
+        if *list {
+            for info in twprs_db::backup::list(input)? {
+                let status = match twprs_db::backup::verify(input, Some(info.id)) {
+                    Ok(_) => "ok",
+                    Err(error) => {
+                        log::error!("Backup {} failed verification: {:?}", info.id, error);
+                        "invalid"
+                    }
+                };
+
+                println!("{},{}", backup_info_row(&info), status);
+            }
+        } else {
+            let info = twprs_db::backup::restore(input, &opts.db, *id)?;
+            log::info!("Restored backup {} to {}", info.id, opts.db);
+        }
+
+        return Ok(());
+    }
+
+    let settings = ProfileDbOptions {
+        enable_statistics: true,
+        // Compacting to find merge errors should never drop the failures.
+        strict_merge: opts.strict_merge || matches!(opts.command, Command::MergeErrors { .. }),
+        ..if opts.tuned {
+            ProfileDbOptions::tuned()
+        } else {
+            ProfileDbOptions::default()
+        }
+    };
+    let mut db = match opts.secondary {
+        Some(secondary_path) => ProfileDb::open_secondary(&opts.db, secondary_path, &settings)?,
+        None if opts.read_only => ProfileDb::open_read_only(&opts.db, &settings)?,
+        None => ProfileDb::open_with_options(&opts.db, &settings)?,
+    };
+    let shards = opts.shards.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+    });
 
     match opts.command {
-        Command::Import { input } => {
+        Command::Import {
+            input,
+            validate,
+            skip_invalid,
+        } => {
             let file = File::open(input)?;
             let reader = twprs::avro::reader(file)?;
+            let mut validator = Validator::default();
 
-            for value in reader {
+            for (line_number, value) in reader.enumerate() {
                 let user = apache_avro::from_value::<User>(&value?)?;
+
+                if validate || skip_invalid {
+                    let valid = validator.validate(line_number, &user);
+
+                    if !valid && skip_invalid {
+                        continue;
+                    }
+                }
+
                 db.update(&user)?;
             }
-        }
-        Command::Lookup { id } => {
-            let users = db.lookup(id)?;
 
-            for user in users {
-                println!("{}", serde_json::to_value(user)?);
+            if validate || skip_invalid {
+                print!("{}", validator.report());
             }
         }
-        Command::Count => {
-            let mut user_count = 0;
-            let mut screen_name_count = 0;
-            let mut verified = 0;
-            let mut protected = 0;
-            for result in db.iter() {
-                let batch = result?;
+        Command::Lookup {
+            id,
+            ids_file,
+            latest,
+            observations,
+        } => {
+            let mut user_ids = id.into_iter().collect::<Vec<_>>();
+
+            if let Some(path) = ids_file {
+                user_ids.extend(read_user_ids(path)?);
+            }
 
-                user_count += 1;
-                screen_name_count += batch.len();
+            if observations {
+                let results = db.lookup_many_observed(user_ids.iter().copied(), latest)?;
 
-                if let Some((_, profile)) = batch.last() {
-                    if profile.verified {
-                        verified += 1;
+                for user_id in user_ids {
+                    if let Some(users) = results.get(&user_id) {
+                        for (observations, user) in users {
+                            println!(
+                                "{}",
+                                serde_json::json!({
+                                    "first_seen": observations.first_seen,
+                                    "last_seen": observations.last_seen,
+                                    "count": observations.count,
+                                    "distinct_days": observations.distinct_days,
+                                    "user": user,
+                                })
+                            );
+                        }
                     }
-                    if profile.protected {
-                        protected += 1;
+                }
+            } else {
+                let results = db.lookup_many(user_ids.iter().copied(), latest)?;
+
+                for user_id in user_ids {
+                    if let Some(users) = results.get(&user_id) {
+                        for user in users {
+                            println!("{}", serde_json::to_value(user)?);
+                        }
                     }
                 }
             }
+        }
+        Command::Count => {
+            let counts = db.par_scan(shards, |iterator| {
+                let mut user_count = 0;
+                let mut screen_name_count = 0;
+                let mut verified = 0;
+                let mut protected = 0;
+
+                for result in iterator {
+                    let batch = result?;
+
+                    user_count += 1;
+                    screen_name_count += batch.len();
+
+                    if let Some((_, profile)) = batch.last() {
+                        if profile.verified {
+                            verified += 1;
+                        }
+                        if profile.protected {
+                            protected += 1;
+                        }
+                    }
+                }
+
+                Ok((user_count, screen_name_count, verified, protected))
+            })?;
+
+            let (user_count, screen_name_count, verified, protected) =
+                counts.into_iter().fold((0, 0, 0, 0), |acc, counts| {
+                    (
+                        acc.0 + counts.0,
+                        acc.1 + counts.1,
+                        acc.2 + counts.2,
+                        acc.3 + counts.3,
+                    )
+                });
 
             println!("{} users, {} screen names", user_count, screen_name_count);
             println!("{} verified, {} protected", verified, protected);
@@ -86,19 +208,477 @@ fn main() -> Result<(), Error> {
                 println!("{}", serde_json::to_value(profile)?);
             }
         }
+        Command::Export {
+            output,
+            order,
+            partition_by_date,
+            first,
+            last,
+            ids_file,
+        } => {
+            let options = twprs_db::export::ExportOptions {
+                order,
+                partition_by_date,
+                first_snapshot: first,
+                last_snapshot: last,
+                user_ids: ids_file.map(read_user_ids).transpose()?,
+            };
+
+            let summary = twprs_db::export::export(&db, output, &options)?;
+
+            log::info!(
+                "Exported {} profiles for {} users to {} files",
+                summary.profile_count,
+                summary.user_count,
+                summary.file_count
+            );
+        }
+        Command::ExportParquet {
+            output,
+            row_group_size,
+            partition_by_date,
+            latest,
+        } => {
+            let mut writer = FileWriter::create(output, row_group_size, partition_by_date)?;
+
+            if latest {
+                for result in db.iter() {
+                    let batch = result?;
+
+                    if let Some((_, most_recent)) = batch.into_iter().last() {
+                        writer.write(most_recent)?;
+                    }
+                }
+            } else {
+                for result in db.raw_iter() {
+                    let (_, (_, user)) = result?;
+                    writer.write(user)?;
+                }
+            }
+
+            writer.close()?;
+        }
+        Command::Check { quarantine, repair } => {
+            let mode = if repair {
+                Mode::Repair
+            } else if quarantine {
+                Mode::Quarantine
+            } else {
+                Mode::Report
+            };
+
+            let report = db.check(mode)?;
+
+            for issue in &report.issues {
+                for problem in &issue.problems {
+                    println!(
+                        "{},{},{},{}",
+                        issue
+                            .key_user_id()
+                            .map(|user_id| user_id.to_string())
+                            .unwrap_or_default(),
+                        issue.key_screen_name(),
+                        problem.kind(),
+                        issue.action.name()
+                    );
+
+                    let detail = problem.detail();
+                    if !detail.is_empty() {
+                        log::warn!("{:?}: {}: {}", issue.key, problem.kind(), detail);
+                    }
+                }
+            }
+
+            log::info!(
+                "Checked {} entries: {} with problems, {} repaired, {} quarantined",
+                report.entry_count,
+                report.issues.len(),
+                report.action_count(Action::Repaired),
+                report.action_count(Action::Quarantined)
+            );
+
+            if report.action_count(Action::None) < report.issues.len() {
+                log::info!("Entries were changed, so the indices should be rebuilt with reindex");
+            }
+        }
+        Command::MergeErrors { compact } => {
+            if compact {
+                db.compact();
+            }
+
+            for failure in db.merge_failures()? {
+                println!(
+                    "{},{},{},{},{}",
+                    failure
+                        .user_id()
+                        .map(|user_id| user_id.to_string())
+                        .unwrap_or_default(),
+                    failure.screen_name(),
+                    failure.source.name(),
+                    failure.recorded_at.timestamp(),
+                    failure.bytes.len()
+                );
+                log::warn!("{:?}: {}", failure.key, failure.message);
+            }
+        }
+        Command::Merge { from } => {
+            let other = ProfileDb::open_read_only(from, &ProfileDbOptions::default())?;
+            let summary = db.merge_from(&other)?;
+
+            log::info!(
+                "Merged {} entries: {} new users, {} new screen names, {} updated profiles",
+                summary.entry_count,
+                summary.new_users,
+                summary.new_screen_names,
+                summary.updated_profiles
+            );
+
+            if summary.skipped_entries > 0 {
+                log::warn!("Skipped {} invalid entries", summary.skipped_entries);
+            }
+        }
+        Command::Purge {
+            ids_file,
+            avro,
+            audit,
+        } => {
+            let user_ids = read_user_ids(ids_file)?.into_iter().collect::<HashSet<_>>();
+            let paths = avro_paths(&avro)?;
+
+            // Open the audit log first, so that nothing is deleted if it can't be written.
+            let mut audit_file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(audit)?;
+            let timestamp = chrono::Utc::now().timestamp();
+
+            let summary = db.purge_many(&user_ids)?;
+
+            log::info!(
+                "Deleted {} entries: {} profiles, {} index entries, {} counter samples, {} other",
+                summary.total(),
+                summary.profile_entries,
+                summary.index_entries,
+                summary.counter_entries,
+                summary.other_entries
+            );
+
+            let mut sorted_user_ids = user_ids.iter().copied().collect::<Vec<_>>();
+            sorted_user_ids.sort_unstable();
+
+            // The database purge is recorded before any Avro files are rewritten, and each file
+            // gets its own record (with the same timestamp) as soon as it's been rewritten or
+            // has failed.
+            let record = serde_json::json!({
+                "timestamp": timestamp,
+                "db": opts.db,
+                "user_ids": sorted_user_ids,
+                "profile_entries": summary.profile_entries,
+                "index_entries": summary.index_entries,
+                "counter_entries": summary.counter_entries,
+                "other_entries": summary.other_entries,
+                "avro_paths": paths,
+            });
+            writeln!(audit_file, "{}", record)?;
+
+            let mut failure_count = 0;
+
+            for path in paths {
+                let record = match twprs::avro::remove_users_in_place(&path, &user_ids) {
+                    Ok(file_summary) => {
+                        log::info!(
+                            "Removed {} of {} records in {:?}",
+                            file_summary.removed_count,
+                            file_summary.record_count,
+                            path
+                        );
+
+                        serde_json::json!({
+                            "timestamp": timestamp,
+                            "path": path,
+                            "records": file_summary.record_count,
+                            "removed": file_summary.removed_count,
+                        })
+                    }
+                    Err(error) => {
+                        log::error!("Failed to rewrite {:?}: {:?}", path, error);
+                        failure_count += 1;
+
+                        serde_json::json!({
+                            "timestamp": timestamp,
+                            "path": path,
+                            "error": format!("{:?}", error),
+                        })
+                    }
+                };
+
+                writeln!(audit_file, "{}", record)?;
+            }
+
+            if failure_count > 0 {
+                return Err(Error::PurgeFailures(failure_count));
+            }
+        }
+        Command::Backup {
+            output,
+            checkpoint,
+            keep,
+        } => {
+            if checkpoint {
+                db.checkpoint(&output)?;
+                log::info!("Created checkpoint at {}", output);
+            } else {
+                let info = db.backup(output, keep)?;
+                println!("{}", backup_info_row(&info));
+            }
+        }
+        Command::Restore { .. } => {}
+        Command::ExportSqlite {
+            output,
+            deactivations,
+        } => {
+            let log = match deactivations {
+                Some(path) => Some(twprs_db::deactivation::Log::read(File::open(path)?)?),
+                None => None,
+            };
+
+            let summary = twprs_db::sqlite::export(&db, log.as_ref(), output)?;
+
+            log::info!(
+                "Exported {} users, {} snapshots, {} URLs, and {} deactivations",
+                summary.user_count,
+                summary.snapshot_count,
+                summary.url_count,
+                summary.deactivation_count
+            );
+        }
+        Command::Query { query, all } => {
+            for result in db.iter() {
+                let batch = result?;
+                let users = batch.into_iter().map(|(_, user)| user).collect::<Vec<_>>();
+
+                if query.matches_history(&users) {
+                    if all {
+                        for user in &users {
+                            println!("{}", serde_json::to_value(user)?);
+                        }
+                    } else if let Some(most_recent) = users.last() {
+                        println!("{}", serde_json::to_value(most_recent)?);
+                    }
+                }
+            }
+        }
+        Command::Watchlist { watchlist } => {
+            let watchlist = Watchlist::load(watchlist)?;
+
+            for result in db.iter() {
+                let batch = result?;
+                let users = batch.into_iter().map(|(_, user)| user).collect::<Vec<_>>();
+
+                if let Some(tagged) = watchlist.scan(&users) {
+                    println!("{}", serde_json::to_value(tagged)?);
+                }
+            }
+        }
+        Command::Domains {
+            count,
+            include_shorteners,
+        } => {
+            let mut counts = db.domain_user_counts()?;
+            if !include_shorteners {
+                counts.retain(|(domain, _)| !twprs::url::is_shortener(domain));
+            }
+
+            counts.sort_by(|(domain_a, count_a), (domain_b, count_b)| {
+                count_b.cmp(count_a).then_with(|| domain_a.cmp(domain_b))
+            });
+
+            for (domain, user_count) in counts.into_iter().take(count) {
+                println!("{},{}", domain, user_count);
+            }
+        }
+        Command::Domain { domain } => {
+            let domain = twprs::url::registrable_domain(&domain.to_lowercase());
+
+            for entry in db.domain_users(&domain)? {
+                println!(
+                    "{},{},{}",
+                    entry.user_id,
+                    entry.first_seen.timestamp(),
+                    entry.last_seen.timestamp()
+                );
+            }
+        }
+        Command::Series { id, json } => {
+            for sample in db.counter_series(id)? {
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "snapshot": sample.snapshot.timestamp(),
+                            "followers_count": sample.followers_count,
+                            "friends_count": sample.friends_count,
+                            "statuses_count": sample.statuses_count,
+                            "listed_count": sample.listed_count,
+                        })
+                    );
+                } else {
+                    println!(
+                        "{},{},{},{},{}",
+                        sample.snapshot.timestamp(),
+                        sample.followers_count,
+                        sample.friends_count,
+                        sample.statuses_count,
+                        sample.listed_count
+                    );
+                }
+            }
+        }
+        Command::GrowthAnomalies {
+            z_threshold,
+            min_change,
+            min_baseline,
+            min_relative_change,
+        } => {
+            let options = GrowthOptions {
+                z_threshold,
+                min_change,
+                min_baseline,
+                min_relative_change,
+            };
+
+            for result in db.counter_series_iter()? {
+                let (user_id, samples) = result?;
+
+                for anomaly in growth::anomalies(user_id, &samples, &options) {
+                    println!(
+                        "{},{},{},{},{},{},{:.4},{}",
+                        anomaly.user_id,
+                        anomaly.start.timestamp(),
+                        anomaly.end.timestamp(),
+                        anomaly.before,
+                        anomaly.after,
+                        anomaly.change(),
+                        anomaly.relative_change(),
+                        anomaly
+                            .z_score
+                            .map(|z_score| format!("{:.2}", z_score))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Command::Search { query } => {
+            for (user_id, hits) in db.search(&query)? {
+                for hit in hits {
+                    println!(
+                        "{},{},{},{}",
+                        user_id,
+                        hit.field.name(),
+                        hit.first_snapshot,
+                        hit.last_snapshot
+                    );
+                }
+            }
+        }
+        Command::Reindex => {
+            let count = db.reindex()?;
+            log::info!("Indexed {} profiles", count);
+        }
+        Command::MigrateKeys => {
+            let count = db.migrate_keys()?;
+            log::info!("Migrated {} keys", count);
+        }
         Command::Stats => {
+            if db.has_mixed_keys() {
+                println!(
+                    "Key format: {} (migration incomplete)",
+                    db.key_format().name()
+                );
+            } else {
+                println!("Key format: {}", db.key_format().name());
+            }
             println!("Estimate the number of keys: {}", db.estimate_key_count()?);
             println!("{:?}", db.statistics());
         }
-        Command::ScreenNames => {
+        Command::Health { json } => {
+            let health = db.health()?;
+            let warnings = health.warnings();
+
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "level_sizes": health.level_sizes,
+                        "level_file_counts": health.level_file_counts,
+                        "total_sst_bytes": health.total_sst_bytes(),
+                        "pending_compaction_bytes": health.pending_compaction_bytes,
+                        "running_compactions": health.running_compactions,
+                        "memtable_bytes": health.memtable_bytes,
+                        "estimated_key_count": health.estimated_key_count,
+                        "block_cache_hit_rate": health.block_cache_hit_rate(),
+                        "delayed_write_rate": health.delayed_write_rate,
+                        "write_stopped": health.write_stopped,
+                        "warnings": warnings
+                            .iter()
+                            .map(|warning| serde_json::json!({
+                                "kind": warning.kind(),
+                                "message": warning.message(),
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                );
+            } else {
+                for (level, (size, file_count)) in health
+                    .level_sizes
+                    .iter()
+                    .zip(&health.level_file_counts)
+                    .enumerate()
+                {
+                    println!("level_{}_bytes,{}", level, size);
+                    println!("level_{}_files,{}", level, file_count);
+                }
+
+                println!("total_sst_bytes,{}", health.total_sst_bytes());
+                println!(
+                    "pending_compaction_bytes,{}",
+                    health.pending_compaction_bytes
+                );
+                println!("running_compactions,{}", health.running_compactions);
+                println!("memtable_bytes,{}", health.memtable_bytes);
+                println!("estimated_key_count,{}", health.estimated_key_count);
+                println!(
+                    "block_cache_hit_rate,{}",
+                    health
+                        .block_cache_hit_rate()
+                        .map(|rate| format!("{:.4}", rate))
+                        .unwrap_or_default()
+                );
+                println!(
+                    "delayed_write_rate,{}",
+                    health.delayed_write_rate.unwrap_or_default()
+                );
+                println!("write_stopped,{}", health.write_stopped);
+            }
+
+            for warning in warnings {
+                log::warn!("{}", warning.message());
+            }
+        }
+        Command::ScreenNames { table } => {
+            let mut writer =
+                table.writer(std::io::stdout(), &[Column::Id, Column::ScreenName], &[])?;
+
             for result in db.iter() {
                 let batch = result?;
                 if let Some((_, most_recent)) = batch.last() {
-                    println!("{},{}", most_recent.id, most_recent.screen_name);
+                    writer.write(most_recent)?;
                 } else {
                     log::error!("Empty user result when reading database");
                 }
             }
+
+            writer.flush()?;
         }
         Command::SnapshotAge { count } => {
             let mut queue = priority_queue::DoublePriorityQueue::with_capacity(count);
@@ -124,51 +704,100 @@ fn main() -> Result<(), Error> {
                 println!("{},{}", id, snapshot.0);
             }
         }
-        Command::AllScreenNames => {
-            for result in db.iter() {
+        Command::AllScreenNames {
+            table,
+            observations: with_observations,
+        } => {
+            // The default columns put the first-seen timestamp before the snapshot.
+            let default_order = table.columns.is_none();
+            let mut extra = vec!["first_seen"];
+
+            if default_order {
+                extra.push("snapshot");
+            }
+
+            if with_observations {
+                extra.extend(["last_seen", "observations", "distinct_days"]);
+            }
+
+            let mut writer =
+                table.writer(std::io::stdout(), &[Column::Id, Column::ScreenName], &extra)?;
+
+            for result in db.iter_observed() {
                 let batch = result?;
 
-                for (first, profile) in batch {
-                    println!(
-                        "{},{},{},{}",
-                        profile.id,
-                        profile.screen_name,
-                        first.timestamp(),
-                        profile.snapshot
-                    );
+                for (observations, profile) in batch {
+                    let mut values = vec![observations.first_seen.timestamp().to_string()];
+
+                    if default_order {
+                        values.push(profile.snapshot.to_string());
+                    }
+
+                    if with_observations {
+                        values.extend([
+                            observations.last_seen.timestamp().to_string(),
+                            observations.count.to_string(),
+                            observations.distinct_days.to_string(),
+                        ]);
+                    }
+
+                    writer.write_with_extra(&profile, &values)?;
                 }
             }
+
+            writer.flush()?;
         }
-        Command::Statuses => {
+        Command::Statuses { table } => {
+            let mut writer = table.writer(
+                std::io::stdout(),
+                &[
+                    Column::Id,
+                    Column::ScreenName,
+                    Column::FollowersCount,
+                    Column::FriendsCount,
+                    Column::Protected,
+                ],
+                &[],
+            )?;
+
             for result in db.iter() {
                 let batch = result?;
                 if let Some((_, most_recent)) = batch.last() {
-                    println!(
-                        "{},{},{},{},{}",
-                        most_recent.id,
-                        most_recent.screen_name,
-                        most_recent.followers_count,
-                        most_recent.friends_count,
-                        if most_recent.protected { 1 } else { 0 },
-                    );
+                    writer.write(most_recent)?;
                 } else {
                     log::error!("Empty user result when reading database");
                 }
             }
+
+            writer.flush()?;
         }
         Command::Withheld => {
-            for result in db.iter() {
-                let batch = result?;
+            print_scan(
+                |sender| {
+                    db.par_scan(shards, |iterator| {
+                        for result in iterator {
+                            let mut batch = result?;
 
-                if batch
-                    .iter()
-                    .any(|(_, user)| !user.withheld_in_countries.is_empty())
-                {
-                    if let Some((_, most_recent)) = batch.last() {
-                        println!("{}", serde_json::to_value(most_recent)?);
-                    }
-                }
-            }
+                            if batch
+                                .iter()
+                                .any(|(_, user)| !user.withheld_in_countries.is_empty())
+                            {
+                                if let Some((_, most_recent)) = batch.pop() {
+                                    if sender.send(most_recent).is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+
+                        Ok(())
+                    })
+                },
+                |most_recent| {
+                    println!("{}", serde_json::to_value(most_recent)?);
+                    Ok(())
+                },
+            )?;
         }
         Command::Urls { query } => {
             /*let keywords = query
@@ -177,41 +806,41 @@ fn main() -> Result<(), Error> {
             .collect::<Vec<_>>();*/
             let keyword = query.to_lowercase();
 
-            for result in db.iter() {
-                let batch = result?;
+            print_scan(
+                |sender| {
+                    db.par_scan(shards, |iterator| {
+                        for result in iterator {
+                            let batch = result?;
 
-                for (_, profile) in batch {
-                    if let Some(ref entities) = profile.entities {
-                        if entities
-                            .url
-                            .as_ref()
-                            .map(|urls| {
-                                urls.urls.iter().any(|url| {
-                                    url.expanded_url
-                                        .as_ref()
-                                        .map(|url| url.to_lowercase().contains(&keyword))
-                                        .unwrap_or(false)
-                                })
-                            })
-                            .unwrap_or(false)
-                            || entities
-                                .description
-                                .as_ref()
-                                .map(|urls| {
-                                    urls.urls.iter().any(|url| {
-                                        url.expanded_url
-                                            .as_ref()
-                                            .map(|url| url.to_lowercase().contains(&keyword))
-                                            .unwrap_or(false)
-                                    })
-                                })
-                                .unwrap_or(false)
-                        {
-                            println!("{}", serde_json::to_value(profile)?);
+                            for (_, profile) in batch {
+                                if let Some(ref entities) = profile.entities {
+                                    if entities
+                                        .url
+                                        .iter()
+                                        .chain(entities.description.iter())
+                                        .flat_map(|entity| &entity.urls)
+                                        .any(|url| {
+                                            url.expanded_url
+                                                .as_ref()
+                                                .map(|url| url.to_lowercase().contains(&keyword))
+                                                .unwrap_or(false)
+                                        })
+                                        && sender.send(profile).is_err()
+                                    {
+                                        return Ok(());
+                                    }
+                                }
+                            }
                         }
-                    }
-                }
-            }
+
+                        Ok(())
+                    })
+                },
+                |profile| {
+                    println!("{}", serde_json::to_value(profile)?);
+                    Ok(())
+                },
+            )?;
 
             /*let hits = keywords
                     .iter()
@@ -264,46 +893,69 @@ fn main() -> Result<(), Error> {
                 }
             }*/
         }
-        Command::Bio { query } => {
+        Command::Bio { query, table } => {
             let keywords = query
                 .split(',')
                 .map(|keyword| keyword.to_lowercase())
                 .collect::<Vec<_>>();
+            let mut writer = table.writer(
+                std::io::stdout(),
+                &[
+                    Column::Id,
+                    Column::ScreenName,
+                    Column::FollowersCount,
+                    Column::FriendsCount,
+                ],
+                &keywords
+                    .iter()
+                    .map(|keyword| keyword.as_str())
+                    .collect::<Vec<_>>(),
+            )?;
 
-            for result in db.iter() {
-                let batch = result?;
+            print_scan(
+                |sender| {
+                    db.par_scan(shards, |iterator| {
+                        for result in iterator {
+                            let mut batch = result?;
 
-                let hits = keywords
-                    .iter()
-                    .map(|keyword| {
-                        batch.iter().any(|(_, profile)| {
-                            profile
-                                .description
-                                .as_ref()
-                                .map(|description| description.to_lowercase().contains(keyword))
-                                .unwrap_or(false)
-                        })
+                            let hits = keywords
+                                .iter()
+                                .map(|keyword| {
+                                    batch.iter().any(|(_, profile)| {
+                                        profile
+                                            .description
+                                            .as_ref()
+                                            .map(|description| {
+                                                description.to_lowercase().contains(keyword)
+                                            })
+                                            .unwrap_or(false)
+                                    })
+                                })
+                                .collect::<Vec<_>>();
+
+                            if hits.iter().any(|hit| *hit) {
+                                if let Some((_, most_recent)) = batch.pop() {
+                                    if sender.send((most_recent, hits)).is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+
+                        Ok(())
                     })
-                    .collect::<Vec<_>>();
+                },
+                |(most_recent, hits)| {
+                    let results = hits
+                        .iter()
+                        .map(|hit| if *hit { "1" } else { "0" })
+                        .collect::<Vec<_>>();
+                    writer.write_with_extra(&most_recent, &results)?;
+                    Ok(())
+                },
+            )?;
 
-                if hits.iter().any(|hit| *hit) {
-                    if let Some((_, most_recent)) = batch.last() {
-                        let results = hits
-                            .iter()
-                            .map(|hit| if *hit { "1" } else { "0" })
-                            .collect::<Vec<_>>()
-                            .join(",");
-                        println!(
-                            "{},{},{},{},{}",
-                            most_recent.id,
-                            most_recent.screen_name,
-                            most_recent.followers_count,
-                            most_recent.friends_count,
-                            results
-                        );
-                    }
-                }
-            }
+            writer.flush()?;
 
             /*let mut matches = vec![];
             for result in db.iter() {
@@ -380,8 +1032,12 @@ fn main() -> Result<(), Error> {
             let mut suspended_user_profiles: HashMap<u64, User> = HashMap::new();
             let mut screen_name_change_user_profiles: HashMap<u64, Vec<_>> = HashMap::new();
 
+            let mut batches = db
+                .snapshot()
+                .lookup_many(suspended_user_ids.iter().copied(), false)?;
+
             for user_id in &suspended_user_ids {
-                let batch = db.lookup(*user_id)?;
+                let batch = batches.remove(user_id).unwrap_or_default();
 
                 if let Some((_, most_recent)) = batch.last() {
                     if suspended_user_ids.contains(&most_recent.id()) {
@@ -450,19 +1106,31 @@ fn main() -> Result<(), Error> {
             let mut suspended_user_profiles: HashMap<u64, User> = HashMap::new();
             let mut screen_name_change_user_profiles: HashMap<u64, Vec<_>> = HashMap::new();
 
-            for result in db.iter() {
-                let batch = result?;
-                if let Some((_, most_recent)) = batch.last() {
-                    if suspended_user_ids.contains(&most_recent.id()) {
-                        suspended_user_profiles.insert(most_recent.id(), most_recent.clone());
-                    }
+            // Both reports are built from one scan of a single snapshot.
+            let snapshot = db.snapshot();
+            let shard_results = snapshot.par_scan(shards, |iterator| {
+                let mut suspended_user_profiles = vec![];
+                let mut screen_name_change_user_profiles = vec![];
 
-                    if batch.len() > 1 {
-                        screen_name_change_user_profiles.insert(most_recent.id(), batch);
+                for result in iterator {
+                    let batch = result?;
+                    if let Some((_, most_recent)) = batch.last() {
+                        if suspended_user_ids.contains(&most_recent.id()) {
+                            suspended_user_profiles.push((most_recent.id(), most_recent.clone()));
+                        }
+
+                        if batch.len() > 1 {
+                            screen_name_change_user_profiles.push((most_recent.id(), batch));
+                        }
                     }
-                } /*else {
-                      log::error!("Empty user result when reading database");
-                  }*/
+                }
+
+                Ok((suspended_user_profiles, screen_name_change_user_profiles))
+            })?;
+
+            for (suspended, screen_name_changes) in shard_results {
+                suspended_user_profiles.extend(suspended);
+                screen_name_change_user_profiles.extend(screen_name_changes);
             }
 
             let mut suspension_report = File::create(suspensions)?;
@@ -542,6 +1210,90 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Expand directories to the `.avro` files they contain (in path order).
+fn avro_paths(paths: &[String]) -> Result<Vec<std::path::PathBuf>, Error> {
+    let mut result = vec![];
+
+    for path in paths {
+        let path = std::path::Path::new(path);
+
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.retain(|entry| entry.extension().filter(|ext| *ext == "avro").is_some());
+            entries.sort();
+            result.extend(entries);
+        } else {
+            result.push(path.to_path_buf());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Run a parallel scan that sends its results to a single printing thread as they're found, so
+/// that matches are written without being collected in memory first.
+///
+/// Scans should stop sending when the channel is closed (because printing failed).
+fn print_scan<T, S, P>(scan: S, mut print: P) -> Result<(), Error>
+where
+    T: Send,
+    S: FnOnce(SyncSender<T>) -> Result<Vec<()>, twprs_db::db::Error>,
+    P: FnMut(T) -> Result<(), Error> + Send,
+{
+    let (sender, receiver) = std::sync::mpsc::sync_channel(PRINT_SCAN_BUFFER_SIZE);
+
+    std::thread::scope(|scope| {
+        let printer = scope.spawn(move || {
+            for value in receiver {
+                print(value)?;
+            }
+
+            Ok(())
+        });
+
+        let scanned = scan(sender);
+        let printed: Result<(), Error> = printer
+            .join()
+            .unwrap_or_else(|error| std::panic::resume_unwind(error));
+
+        // A printing error closes the channel and stops the scan, so it's reported first.
+        printed?;
+        scanned?;
+
+        Ok(())
+    })
+}
+
+fn read_user_ids<P: AsRef<std::path::Path>>(path: P) -> Result<Vec<u64>, Error> {
+    let mut user_ids = vec![];
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if !line.is_empty() {
+            user_ids.push(
+                line.parse()
+                    .map_err(|_| Error::InvalidUserId(line.to_string()))?,
+            );
+        }
+    }
+
+    Ok(user_ids)
+}
+
+fn backup_info_row(info: &twprs_db::backup::BackupInfo) -> String {
+    format!(
+        "{},{},{},{}",
+        info.id,
+        info.timestamp.timestamp(),
+        info.size,
+        info.file_count
+    )
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("ProfileDb error")]
@@ -558,6 +1310,22 @@ pub enum Error {
     LogInitialization(#[from] log::SetLoggerError),
     #[error("Deactivations file parsing error")]
     DeactivationsFile(#[from] twprs_db::deactivation::Error),
+    #[error("Parquet error")]
+    Parquet(#[from] twprs::parquet::Error),
+    #[error("Table output error")]
+    Table(#[from] twprs::table::Error),
+    #[error("SQLite export error")]
+    Sqlite(#[from] twprs_db::sqlite::Error),
+    #[error("Watchlist error")]
+    Watchlist(#[from] twprs::watchlist::Error),
+    #[error("Invalid user ID")]
+    InvalidUserId(String),
+    #[error("Backup error")]
+    Backup(#[from] twprs_db::backup::Error),
+    #[error("Avro export error")]
+    Export(#[from] twprs_db::export::Error),
+    #[error("Failed to rewrite {0} Avro files during purge")]
+    PurgeFailures(usize),
 }
 
 #[derive(Debug, Parser)]
@@ -569,6 +1337,22 @@ struct Opts {
     /// Database path
     #[clap(long)]
     db: String,
+    /// Number of shards for parallel scans (defaults to the available parallelism)
+    #[clap(long)]
+    shards: Option<usize>,
+    /// Open the database with prefix bloom filters and tuned caching and compaction settings
+    #[clap(long)]
+    tuned: bool,
+    /// Open the database read-only (allows reading while another process is writing)
+    #[clap(long)]
+    read_only: bool,
+    /// Open the database as a secondary instance, using this directory for its logs
+    #[clap(long, conflicts_with = "read-only")]
+    secondary: Option<String>,
+    /// Record profile values that fail to decode during merges instead of only logging them (not
+    /// available for read-only or secondary instances)
+    #[clap(long)]
+    strict_merge: bool,
     #[clap(subcommand)]
     command: Command,
 }
@@ -579,11 +1363,26 @@ enum Command {
         /// Avro input path
         #[clap(short, long)]
         input: String,
+        /// Run record-level checks and print a report
+        #[clap(long)]
+        validate: bool,
+        /// Run record-level checks and skip records that fail them
+        #[clap(long)]
+        skip_invalid: bool,
     },
     Lookup {
         /// Twitter user ID
+        #[clap(long, required_unless_present = "ids-file")]
+        id: Option<u64>,
+        /// File with one Twitter user ID per line
         #[clap(long)]
-        id: u64,
+        ids_file: Option<String>,
+        /// Only print the most recent profile for each user
+        #[clap(long)]
+        latest: bool,
+        /// Include the last-seen timestamp, observation count, and distinct days observed
+        #[clap(long)]
+        observations: bool,
     },
     Count,
     CountRaw,
@@ -592,14 +1391,197 @@ enum Command {
         last: i64,
     },
     Stats,
-    ScreenNames,
-    AllScreenNames,
+    /// Print storage metrics and warn about unhealthy states such as write stalls
+    Health {
+        /// Print JSON instead of CSV
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check the integrity of every entry (see the `twprs_db::check` documentation)
+    Check {
+        /// Move entries with problems to the quarantine column family
+        #[clap(long)]
+        quarantine: bool,
+        /// Repair the entries that can be repaired and quarantine the others
+        #[clap(long, conflicts_with = "quarantine")]
+        repair: bool,
+    },
+    /// Print the profile values that failed to decode in strict merge mode
+    MergeErrors {
+        /// Compact the database first (which merges all pending values)
+        #[clap(long)]
+        compact: bool,
+    },
+    /// Merge the profiles from another database into this one
+    Merge {
+        /// Path of the database to merge from
+        #[clap(long)]
+        from: String,
+    },
+    /// Delete users from the database and Avro files and append records to an audit log
+    Purge {
+        /// File with one Twitter user ID per line
+        #[clap(long)]
+        ids_file: String,
+        /// Avro files (or directories of Avro files) to rewrite without the users' records (use
+        /// `avro filter` to write redacted copies)
+        #[clap(long)]
+        avro: Vec<String>,
+        /// Audit log path (JSON lines: one record for the database and one per Avro file)
+        #[clap(long, default_value = "purge-audit.jsonl")]
+        audit: String,
+    },
+    /// Create a verified incremental backup of the database
+    Backup {
+        /// Backup directory (or checkpoint path if creating a checkpoint)
+        #[clap(short, long)]
+        output: String,
+        /// Create a checkpoint (a copy that can be opened directly) instead of a backup
+        #[clap(long)]
+        checkpoint: bool,
+        /// Number of most recent backups to keep
+        #[clap(long, conflicts_with = "checkpoint")]
+        keep: Option<usize>,
+    },
+    /// Restore a backup to a new database at the database path
+    Restore {
+        /// Backup directory
+        #[clap(short, long)]
+        input: String,
+        /// Backup ID (defaults to the most recent backup)
+        #[clap(long)]
+        id: Option<u32>,
+        /// List and verify the backups instead of restoring
+        #[clap(long)]
+        list: bool,
+    },
+    /// Export profiles to Avro
+    Export {
+        /// Output file path (or directory path if partitioning by date)
+        #[clap(short, long)]
+        output: String,
+        /// Ordering of the profiles ("snapshot" or "id")
+        #[clap(long, default_value = "snapshot")]
+        order: twprs_db::export::Order,
+        /// Write one file per snapshot date
+        #[clap(long)]
+        partition_by_date: bool,
+        /// Earliest snapshot timestamp to export (epoch seconds)
+        #[clap(long)]
+        first: Option<i64>,
+        /// Latest snapshot timestamp to export (epoch seconds)
+        #[clap(long)]
+        last: Option<i64>,
+        /// File with one Twitter user ID per line to export
+        #[clap(long)]
+        ids_file: Option<String>,
+    },
+    ExportParquet {
+        /// Output file path (or directory path if partitioning by date)
+        #[clap(short, long)]
+        output: String,
+        /// Maximum number of rows per row group
+        #[clap(long, default_value_t = twprs::parquet::DEFAULT_ROW_GROUP_SIZE)]
+        row_group_size: usize,
+        /// Write one file per snapshot date
+        #[clap(long)]
+        partition_by_date: bool,
+        /// Only export the most recent profile for each user
+        #[clap(long)]
+        latest: bool,
+    },
+    /// Print users whose history matches a query (see the `twprs::query` documentation)
+    Query {
+        /// Query
+        #[clap(short, long)]
+        query: Expr,
+        /// Print all stored snapshots for matching users (instead of only the most recent)
+        #[clap(long)]
+        all: bool,
+    },
+    /// Tag users who match watchlist categories
+    Watchlist {
+        /// Watchlist file path (TOML or JSON)
+        #[clap(short, long)]
+        watchlist: String,
+    },
+    /// Print the domains linked by the most users
+    Domains {
+        /// Number of domains to print
+        #[clap(long, default_value = "100")]
+        count: usize,
+        /// Include link shorteners (e.g. bit.ly), which say little about where links lead
+        #[clap(long)]
+        include_shorteners: bool,
+    },
+    /// Print the users who have linked to a domain (with first and last snapshot timestamps)
+    Domain {
+        /// Domain (normalized to its registrable domain)
+        #[clap(short, long)]
+        domain: String,
+    },
+    /// Print the follower, friend, status, and listed counts for a user by snapshot
+    Series {
+        /// Twitter user ID
+        #[clap(long)]
+        id: u64,
+        /// Print JSON lines instead of CSV
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print sudden follower count changes (see the `twprs_db::growth` documentation)
+    GrowthAnomalies {
+        /// Minimum absolute z-score of the daily change rate
+        #[clap(long, default_value = "4.0")]
+        z_threshold: f64,
+        /// Minimum absolute change in followers
+        #[clap(long, default_value = "1000")]
+        min_change: i64,
+        /// Number of other intervals required to use the z-score
+        #[clap(long, default_value = "5")]
+        min_baseline: usize,
+        /// Minimum change relative to the starting count when there are too few intervals
+        #[clap(long, default_value = "0.5")]
+        min_relative_change: f64,
+    },
+    /// Search descriptions, names, and locations (see the `twprs_db::search` documentation)
+    Search {
+        /// Query
+        #[clap(short, long)]
+        query: twprs_db::search::Query,
+    },
+    /// Rebuild the secondary indices from the stored profiles
+    Reindex,
+    /// Rewrite legacy profile keys in the current key format
+    MigrateKeys,
+    ExportSqlite {
+        /// Output SQLite file path (must not exist)
+        #[clap(short, long)]
+        output: String,
+        /// Optional deactivations file to include
+        #[clap(long)]
+        deactivations: Option<String>,
+    },
+    ScreenNames {
+        #[clap(flatten)]
+        table: TableOpts,
+    },
+    AllScreenNames {
+        #[clap(flatten)]
+        table: TableOpts,
+        /// Add observation columns (last_seen, observations, distinct_days)
+        #[clap(long)]
+        observations: bool,
+    },
     SnapshotAge {
         /// How many oldest values to include
         #[clap(long, default_value = "1000000")]
         count: usize,
     },
-    Statuses,
+    Statuses {
+        #[clap(flatten)]
+        table: TableOpts,
+    },
     SuspensionReport {
         /// Deactivations file path
         #[clap(long)]
@@ -620,6 +1602,8 @@ enum Command {
         /// Keywords
         #[clap(long)]
         query: String,
+        #[clap(flatten)]
+        table: TableOpts,
     },
     Urls {
         /// Keywords
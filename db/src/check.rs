@@ -0,0 +1,361 @@
+//! Integrity checks for the profile entries in a database.
+//!
+//! Each entry is checked for a key in the database's key format (or in either format while a key
+//! migration is incomplete; see the `key` module) with a UTF-8 screen name, a value with a valid
+//! header (see the `value` module) and a decodable Avro user object, a user ID and normalized
+//! screen name that match the key, and a first-seen timestamp that is not after the snapshot.
+//!
+//! Entries with a decodable user object can be repaired by moving them to the correct key in their
+//! own format (where they are combined with any existing entry by the merge operator) or by
+//! resetting the first-seen timestamp to the snapshot. Other problem entries can be moved to a
+//! quarantine column family. The secondary indices are not updated, so `reindex` should be run
+//! after changes.
+
+use super::db::Error;
+use super::key::{self, KeyFormat};
+use super::value::{self, Header};
+use twprs::model::User;
+
+pub(crate) const QUARANTINE_CF_NAME: &str = "quarantine";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Only report problems.
+    Report,
+    /// Move all problem entries to the quarantine column family.
+    Quarantine,
+    /// Repair the entries that can be repaired and quarantine the others.
+    Repair,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    InvalidKey,
+    KeyFormatMismatch(KeyFormat),
+    ShortValue(usize),
+    InvalidHeader,
+    InvalidAvro(String),
+    UserIdMismatch {
+        key_user_id: u64,
+        user_id: u64,
+    },
+    ScreenNameMismatch {
+        key_screen_name: String,
+        screen_name: String,
+    },
+    FirstSeenAfterSnapshot {
+        first_seen: i64,
+        snapshot: i64,
+    },
+}
+
+impl Problem {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidKey => "invalid_key",
+            Self::KeyFormatMismatch(_) => "key_format_mismatch",
+            Self::ShortValue(_) => "short_value",
+            Self::InvalidHeader => "invalid_header",
+            Self::InvalidAvro(_) => "invalid_avro",
+            Self::UserIdMismatch { .. } => "user_id_mismatch",
+            Self::ScreenNameMismatch { .. } => "screen_name_mismatch",
+            Self::FirstSeenAfterSnapshot { .. } => "first_seen_after_snapshot",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            Self::InvalidKey => String::new(),
+            Self::KeyFormatMismatch(format) => format.name().to_string(),
+            Self::ShortValue(length) => length.to_string(),
+            Self::InvalidHeader => String::new(),
+            Self::InvalidAvro(message) => message.clone(),
+            Self::UserIdMismatch {
+                key_user_id,
+                user_id,
+            } => format!("{} != {}", key_user_id, user_id),
+            Self::ScreenNameMismatch {
+                key_screen_name,
+                screen_name,
+            } => format!("{} != {}", key_screen_name, screen_name),
+            Self::FirstSeenAfterSnapshot {
+                first_seen,
+                snapshot,
+            } => format!("{} > {}", first_seen, snapshot),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    pub key: Vec<u8>,
+    pub problems: Vec<Problem>,
+    pub action: Action,
+}
+
+impl Issue {
+    /// The user ID prefix of the key, if it has one.
+    pub fn key_user_id(&self) -> Option<u64> {
+        key::parse(&self.key).map(|(_, user_id, _)| user_id)
+    }
+
+    /// The screen name suffix of the key (with invalid UTF-8 replaced).
+    pub fn key_screen_name(&self) -> String {
+        key::parse(&self.key)
+            .map(|(_, _, screen_name)| String::from_utf8_lossy(screen_name).into_owned())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    Quarantined,
+    Repaired,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Quarantined => "quarantined",
+            Self::Repaired => "repaired",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub entry_count: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn action_count(&self, action: Action) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.action == action)
+            .count()
+    }
+}
+
+/// The result of checking a single entry.
+pub(crate) struct Checked {
+    pub(crate) problems: Vec<Problem>,
+    /// The value header and user object, if the value could be decoded.
+    pub(crate) decoded: Option<(Header, User)>,
+}
+
+pub(crate) fn check_entry(key: &[u8], value: &[u8], key_formats: &[KeyFormat]) -> Checked {
+    let mut problems = vec![];
+
+    let key_parts = key::parse(key).and_then(|(format, user_id, screen_name)| {
+        std::str::from_utf8(screen_name)
+            .ok()
+            .map(|screen_name| (format, user_id, screen_name))
+    });
+
+    match key_parts {
+        Some((format, _, _)) if !key_formats.contains(&format) => {
+            problems.push(Problem::KeyFormatMismatch(format))
+        }
+        Some(_) => {}
+        None => problems.push(Problem::InvalidKey),
+    }
+
+    let decoded = match value::decode(value) {
+        Ok(decoded) => Some(decoded),
+        Err(error) => {
+            problems.push(match error {
+                Error::InvalidTimestamp(_) => Problem::ShortValue(value.len()),
+                Error::InvalidValueHeader(_) => Problem::InvalidHeader,
+                other => Problem::InvalidAvro(format!("{:?}", other)),
+            });
+            None
+        }
+    };
+
+    if let Some((header, user)) = &decoded {
+        if let Some((format, key_user_id, key_screen_name)) = key_parts {
+            if key_user_id != user.id() {
+                problems.push(Problem::UserIdMismatch {
+                    key_user_id,
+                    user_id: user.id(),
+                });
+            }
+
+            if key_screen_name != format.normalize(&user.screen_name) {
+                problems.push(Problem::ScreenNameMismatch {
+                    key_screen_name: key_screen_name.to_string(),
+                    screen_name: user.screen_name.clone(),
+                });
+            }
+        }
+
+        if header.first_seen > user.snapshot {
+            problems.push(Problem::FirstSeenAfterSnapshot {
+                first_seen: header.first_seen,
+                snapshot: user.snapshot,
+            });
+        }
+    }
+
+    Checked { problems, decoded }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::user;
+
+    const BOTH: &[KeyFormat] = &[KeyFormat::Legacy, KeyFormat::V1];
+
+    fn value(first_seen: i64, user: &User) -> Vec<u8> {
+        value::encode_user(
+            &Header {
+                first_seen,
+                ..Header::new(user.snapshot)
+            },
+            user,
+        )
+        .unwrap()
+    }
+
+    fn problems(key: &[u8], value: &[u8], key_formats: &[KeyFormat]) -> Vec<Problem> {
+        check_entry(key, value, key_formats).problems
+    }
+
+    #[test]
+    fn valid_entries() {
+        let user = user(1, "ÀB_c", 100);
+
+        for format in [KeyFormat::Legacy, KeyFormat::V1] {
+            let checked = check_entry(&format.key(1, "ÀB_c"), &value(50, &user), &[format]);
+
+            assert_eq!(checked.problems, vec![]);
+            assert_eq!(
+                checked.decoded.map(|(_, decoded)| decoded),
+                Some(user.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn key_problems() {
+        let value = value(50, &user(1, "foo", 100));
+
+        assert_eq!(
+            problems(&KeyFormat::V1.key(1, "foo"), &value, &[KeyFormat::Legacy]),
+            vec![Problem::KeyFormatMismatch(KeyFormat::V1)]
+        );
+        assert_eq!(
+            problems(&KeyFormat::Legacy.key(1, "foo"), &value, &[KeyFormat::V1]),
+            vec![Problem::KeyFormatMismatch(KeyFormat::Legacy)]
+        );
+
+        let mut invalid_utf8 = KeyFormat::V1.prefix(1);
+        invalid_utf8.extend([0, 0xFF]);
+        let mut missing_separator = KeyFormat::V1.prefix(1);
+        missing_separator.extend(b"foo");
+
+        // The user object is still checked against whatever can be read from the key.
+        for key in [
+            &[][..],
+            &[0xFF, 1, 2],
+            &1u64.to_be_bytes()[..4],
+            &invalid_utf8,
+            &missing_separator,
+        ] {
+            assert_eq!(problems(key, &value, BOTH), vec![Problem::InvalidKey]);
+        }
+    }
+
+    #[test]
+    fn value_problems() {
+        let key = KeyFormat::V1.key(1, "foo");
+        let valid = value(50, &user(1, "foo", 100));
+
+        assert_eq!(
+            problems(&key, &[1, 2, 3], BOTH),
+            vec![Problem::ShortValue(3)]
+        );
+        assert_eq!(problems(&key, &[0xFF], BOTH), vec![Problem::InvalidHeader]);
+        assert_eq!(
+            problems(&key, &valid[..valid.len() - 1], BOTH)
+                .iter()
+                .map(Problem::kind)
+                .collect::<Vec<_>>(),
+            vec!["invalid_avro"]
+        );
+
+        let checked = check_entry(&[], &[0xFF], BOTH);
+        assert_eq!(
+            checked.problems,
+            vec![Problem::InvalidKey, Problem::InvalidHeader]
+        );
+        assert!(checked.decoded.is_none());
+    }
+
+    #[test]
+    fn user_problems() {
+        let key = KeyFormat::V1.key(1, "foo");
+
+        assert_eq!(
+            problems(&key, &value(200, &user(2, "Bar", 100)), BOTH),
+            vec![
+                Problem::UserIdMismatch {
+                    key_user_id: 1,
+                    user_id: 2
+                },
+                Problem::ScreenNameMismatch {
+                    key_screen_name: "foo".to_string(),
+                    screen_name: "Bar".to_string()
+                },
+                Problem::FirstSeenAfterSnapshot {
+                    first_seen: 200,
+                    snapshot: 100
+                }
+            ]
+        );
+
+        // Screen names are compared after normalization for the key's own format.
+        let legacy = KeyFormat::Legacy.key(1, "ÀB");
+        assert_eq!(
+            problems(&legacy, &value(50, &user(1, "àb", 100)), BOTH),
+            vec![]
+        );
+        assert_eq!(
+            problems(
+                &KeyFormat::V1.key_from_bytes(1, "àb".as_bytes()),
+                &value(50, &user(1, "ÀB", 100)),
+                BOTH
+            ),
+            vec![Problem::ScreenNameMismatch {
+                key_screen_name: "àb".to_string(),
+                screen_name: "ÀB".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn problem_descriptions() {
+        let problem = Problem::FirstSeenAfterSnapshot {
+            first_seen: 2,
+            snapshot: 1,
+        };
+
+        assert_eq!(problem.kind(), "first_seen_after_snapshot");
+        assert_eq!(problem.detail(), "2 > 1");
+        assert_eq!(Problem::KeyFormatMismatch(KeyFormat::V1).detail(), "v1");
+
+        let issue = Issue {
+            key: KeyFormat::V1.key(12, "Foo"),
+            problems: vec![],
+            action: Action::Repaired,
+        };
+
+        assert_eq!(issue.key_user_id(), Some(12));
+        assert_eq!(issue.key_screen_name(), "foo");
+        assert_eq!(issue.action.name(), "repaired");
+    }
+}
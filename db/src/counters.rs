@@ -0,0 +1,127 @@
+//! Encoding for the time series of profile counters.
+//!
+//! Keys are the user ID and the snapshot timestamp (both big-endian), so each user's samples are
+//! contiguous and ordered by snapshot. Values are the follower, friend, status, and listed counts
+//! as zigzag varints.
+//!
+//! Samples are written for every profile that is added to the database, so the series for a user
+//! only includes snapshots that were imported after this column family was added (and the latest
+//! profile for each screen name, which `reindex` adds).
+
+use chrono::{DateTime, TimeZone, Utc};
+use integer_encoding::VarInt;
+use twprs::model::User;
+
+pub(crate) const COUNTERS_CF_NAME: &str = "counters";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CounterSample {
+    pub snapshot: DateTime<Utc>,
+    pub followers_count: i64,
+    pub friends_count: i64,
+    pub statuses_count: i64,
+    pub listed_count: i64,
+}
+
+pub(crate) fn key(user_id: u64, snapshot: i64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[0..8].copy_from_slice(&user_id.to_be_bytes());
+    key[8..16].copy_from_slice(&snapshot.to_be_bytes());
+    key
+}
+
+pub(crate) fn value(user: &User) -> Vec<u8> {
+    let mut value = Vec::with_capacity(16);
+
+    for count in [
+        user.followers_count,
+        user.friends_count,
+        user.statuses_count,
+        user.listed_count,
+    ] {
+        value.extend_from_slice(&count.encode_var_vec());
+    }
+
+    value
+}
+
+pub(crate) fn parse_entry(key: &[u8], value: &[u8]) -> Option<CounterSample> {
+    let snapshot = i64::from_be_bytes(key.get(8..16)?.try_into().ok()?);
+    let mut counts = [0; 4];
+    let mut offset = 0;
+
+    for count in counts.iter_mut() {
+        let (decoded, length) = i64::decode_var(value.get(offset..)?)?;
+        *count = decoded;
+        offset += length;
+    }
+
+    Some(CounterSample {
+        snapshot: Utc.timestamp_opt(snapshot, 0).single()?,
+        followers_count: counts[0],
+        friends_count: counts[1],
+        statuses_count: counts[2],
+        listed_count: counts[3],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::user;
+
+    fn counted(followers_count: i64, friends_count: i64, statuses_count: i64) -> User {
+        User {
+            followers_count,
+            friends_count,
+            statuses_count,
+            listed_count: 3,
+            ..user(1, "foo", 1_600_000_000)
+        }
+    }
+
+    #[test]
+    fn key_layout() {
+        assert_eq!(
+            key(258, 1),
+            [0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+
+        // Samples are grouped by user and ordered by snapshot.
+        assert!(key(1, 1_600_000_000) < key(1, 1_700_000_000));
+        assert!(key(1, 1_700_000_000) < key(2, 1_600_000_000));
+    }
+
+    #[test]
+    fn round_trip() {
+        for user in [
+            counted(0, 0, 0),
+            counted(1_000_000, 150, -1),
+            counted(i64::MAX, i64::MIN, 64),
+        ] {
+            assert_eq!(
+                parse_entry(&key(1, user.snapshot), &value(&user)),
+                Some(CounterSample {
+                    snapshot: Utc.timestamp_opt(user.snapshot, 0).unwrap(),
+                    followers_count: user.followers_count,
+                    friends_count: user.friends_count,
+                    statuses_count: user.statuses_count,
+                    listed_count: 3,
+                })
+            );
+        }
+
+        // Zigzag varints: 150 takes two bytes, and -1 takes one.
+        assert_eq!(value(&counted(1, 150, -1)), vec![2, 0xAC, 0x02, 1, 6]);
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let value = value(&counted(1, 150, -1));
+
+        assert_eq!(parse_entry(&key(1, 100)[..15], &value), None);
+        assert_eq!(parse_entry(&key(1, 100), &value[..value.len() - 1]), None);
+        assert_eq!(parse_entry(&key(1, 100), &[]), None);
+        assert_eq!(parse_entry(&key(1, i64::MAX), &value), None);
+    }
+}
@@ -1,10 +1,25 @@
-use apache_avro::{from_avro_datum, from_value, to_avro_datum, to_value};
-use chrono::{DateTime, TimeZone, Utc};
-use rocksdb::{DBCompressionType, DBIterator, IteratorMode, MergeOperands, Options, DB};
-use std::io::Cursor;
-use std::path::Path;
+use super::backup::{self, BackupInfo};
+use super::check::{self, Action, Issue, Mode, Report, QUARANTINE_CF_NAME};
+use super::counters::{self, CounterSample, COUNTERS_CF_NAME};
+use super::domains::{self, DomainEntry, DOMAINS_CF_NAME};
+use super::health::{self, Health};
+use super::key::{self, KeyFormat};
+use super::merge_errors::{
+    Flusher, MergeFailure, Sink, Source, MERGE_ERRORS_CF_NAME, PERSIST_INTERVAL,
+};
+use super::search::{self, Hit, Query, TERMS_CF_NAME};
+use super::value::{self, Header, Observations};
+use chrono::{DateTime, Utc};
+use rocksdb::{
+    checkpoint::Checkpoint, BlockBasedOptions, BottommostLevelCompaction, Cache, ColumnFamily,
+    ColumnFamilyDescriptor, CompactOptions, DBCompressionType, DBIterator, DBRawIterator,
+    IteratorMode, MergeOperands, Options, ReadOptions, SliceTransform, Snapshot, WriteBatch, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use twprs::{avro::USER_SCHEMA, model::User};
+use twprs::model::User;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -20,102 +35,1443 @@ pub enum Error {
     InvalidKey(Vec<u8>),
     #[error("Invalid timestamp")]
     InvalidTimestamp(Vec<u8>),
+    #[error("Invalid value header")]
+    InvalidValueHeader(Vec<u8>),
+    #[error("Invalid domain index entry")]
+    InvalidDomainEntry(Vec<u8>),
+    #[error("Invalid search index entry")]
+    InvalidSearchEntry(Vec<u8>),
+    #[error("Invalid counter entry")]
+    InvalidCounterEntry(Vec<u8>),
+    #[error("Missing column family")]
+    MissingColumnFamily(&'static str),
+    #[error("Output path already exists")]
+    AlreadyExists(PathBuf),
+    #[error("Backup error")]
+    Backup(#[from] backup::Error),
+    #[error("Invalid merge error entry")]
+    InvalidMergeErrorEntry(Vec<u8>),
+}
+
+const MERGE_BATCH_SIZE: usize = 10_000;
+const MERGE_PROGRESS_INTERVAL: usize = 1_000_000;
+
+/// The stored profiles for a user, with first-seen timestamps, ordered by snapshot.
+pub type UserProfiles = Vec<(DateTime<Utc>, User)>;
+
+/// The stored profiles for a user, with observation statistics, ordered by snapshot.
+pub type ObservedUserProfiles = Vec<(Observations, User)>;
+
+/// An iterator over users with observation statistics for each profile.
+pub type ObservedProfileIterator<'a> = ProfileIterator<'a, Observations>;
+
+/// Storage settings for opening a `ProfileDb`.
+///
+/// The default value matches the configuration used by `ProfileDb::open`, while `tuned` enables
+/// a user ID prefix extractor with prefix bloom filters, a block cache, and settings that favour
+/// larger databases.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileDbOptions {
+    pub enable_statistics: bool,
+    /// Use the user ID prefix of keys (see the `key` module) for bloom filters and prefix seeks.
+    pub prefix_extractor: bool,
+    /// Bits per key for the prefix bloom filters (only used with the prefix extractor).
+    pub bloom_bits_per_key: f64,
+    /// Block cache size in bytes (if unset the RocksDb default cache is used).
+    pub block_cache_size: Option<usize>,
+    pub block_size: Option<usize>,
+    pub write_buffer_size: Option<usize>,
+    pub target_file_size_base: Option<u64>,
+    pub max_background_jobs: Option<i32>,
+    pub level_compaction_dynamic_level_bytes: bool,
+    /// Record profile values that can't be decoded during merges (only when the database is
+    /// opened for writing; see the `merge_errors` module).
+    pub strict_merge: bool,
+}
+
+impl Default for ProfileDbOptions {
+    fn default() -> Self {
+        Self {
+            enable_statistics: false,
+            prefix_extractor: false,
+            bloom_bits_per_key: 10.0,
+            block_cache_size: None,
+            block_size: None,
+            write_buffer_size: None,
+            target_file_size_base: None,
+            max_background_jobs: None,
+            level_compaction_dynamic_level_bytes: false,
+            strict_merge: false,
+        }
+    }
+}
+
+impl ProfileDbOptions {
+    pub fn tuned() -> Self {
+        Self {
+            enable_statistics: false,
+            prefix_extractor: true,
+            bloom_bits_per_key: 10.0,
+            block_cache_size: Some(512 * 1024 * 1024),
+            block_size: Some(16 * 1024),
+            write_buffer_size: Some(128 * 1024 * 1024),
+            target_file_size_base: Some(128 * 1024 * 1024),
+            max_background_jobs: std::thread::available_parallelism()
+                .ok()
+                .map(|count| count.get().min(8) as i32),
+            level_compaction_dynamic_level_bytes: true,
+            strict_merge: false,
+        }
+    }
+
+    fn to_options(&self, merge_failures: Option<&Sink>) -> Result<Options, Error> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.set_compression_type(DBCompressionType::Zstd);
+
+        match merge_failures {
+            Some(sink) => {
+                let sink = sink.clone();
+                options.set_merge_operator_associative(
+                    "merge",
+                    move |key: &[u8], existing_val: Option<&[u8]>, operands: &MergeOperands| {
+                        merge_profiles(key, existing_val, operands, &mut |failure| {
+                            sink.push(failure)
+                        })
+                    },
+                );
+            }
+            None => {
+                options.set_merge_operator_associative("merge", merge);
+            }
+        }
+
+        if self.enable_statistics {
+            options.enable_statistics();
+        }
+
+        let mut block_options = BlockBasedOptions::default();
+
+        if self.prefix_extractor {
+            options.set_prefix_extractor(SliceTransform::create(
+                "profile_key_prefix",
+                key::prefix_transform,
+                Some(key::prefix_in_domain),
+            ));
+            options.set_memtable_prefix_bloom_ratio(0.1);
+            block_options.set_bloom_filter(self.bloom_bits_per_key, false);
+            block_options.set_whole_key_filtering(false);
+        }
+
+        if let Some(size) = self.block_cache_size {
+            block_options.set_block_cache(&Cache::new_lru_cache(size)?);
+            block_options.set_cache_index_and_filter_blocks(true);
+            block_options.set_pin_l0_filter_and_index_blocks_in_cache(true);
+        }
+
+        if let Some(size) = self.block_size {
+            block_options.set_block_size(size);
+        }
+
+        options.set_block_based_table_factory(&block_options);
+
+        if let Some(size) = self.write_buffer_size {
+            options.set_write_buffer_size(size);
+        }
+
+        if let Some(size) = self.target_file_size_base {
+            options.set_target_file_size_base(size);
+        }
+
+        if let Some(jobs) = self.max_background_jobs {
+            options.set_max_background_jobs(jobs);
+        }
+
+        options.set_level_compaction_dynamic_level_bytes(self.level_compaction_dynamic_level_bytes);
+
+        Ok(options)
+    }
+}
+
+/// Counts of the changes made by `ProfileDb::merge_from`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// Number of entries (user ID and screen name pairs) read from the other database.
+    pub entry_count: usize,
+    pub new_users: usize,
+    /// Number of entries for screen names that weren't already stored for the user.
+    pub new_screen_names: usize,
+    /// Number of existing entries that had a newer profile or an earlier first-seen timestamp.
+    pub updated_profiles: usize,
+    /// Number of entries in the other database that couldn't be decoded (and weren't merged).
+    pub skipped_entries: usize,
+}
+
+/// Counts of the entries deleted by `ProfileDb::purge_many`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PurgeSummary {
+    pub profile_entries: usize,
+    /// Domain and search index entries.
+    pub index_entries: usize,
+    pub counter_entries: usize,
+    /// Quarantined entries and merge error records.
+    pub other_entries: usize,
+}
+
+impl PurgeSummary {
+    pub fn total(&self) -> usize {
+        self.profile_entries + self.index_entries + self.counter_entries + self.other_entries
+    }
 }
 
 #[derive(Clone)]
 pub struct ProfileDb {
     db: Arc<DB>,
     options: Options,
+    merge_failures: Option<Sink>,
+    /// Persists merge failures in the background (only when they're collected), until the last
+    /// clone is dropped.
+    flusher: Option<Arc<Flusher>>,
+    key_format: KeyFormat,
+    mixed_keys: bool,
+}
+
+impl ProfileDb {
+    pub fn open<P: AsRef<Path>>(path: P, enable_statistics: bool) -> Result<Self, Error> {
+        Self::open_with_options(
+            path,
+            &ProfileDbOptions {
+                enable_statistics,
+                ..ProfileDbOptions::default()
+            },
+        )
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        settings: &ProfileDbOptions,
+    ) -> Result<Self, Error> {
+        let merge_failures = settings.strict_merge.then(Sink::default);
+        let options = settings.to_options(merge_failures.as_ref())?;
+        let mut db_options = options.clone();
+        db_options.create_missing_column_families(true);
+
+        let db =
+            DB::open_cf_descriptors(&db_options, path, column_family_descriptors(&options, None))?;
+        let (key_format, mixed_keys) = detect_key_format(&db)?;
+        let db = Arc::new(db);
+
+        let flusher = match &merge_failures {
+            Some(sink) => {
+                let (db, sink) = (db.clone(), sink.clone());
+
+                Some(Arc::new(Flusher::spawn(PERSIST_INTERVAL, move || {
+                    if let Err(error) = persist_merge_failures(&db, &sink) {
+                        log::error!("Could not record merge failures: {:?}", error);
+                    }
+                })?))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            db,
+            options,
+            merge_failures,
+            flusher,
+            key_format,
+            mixed_keys,
+        })
+    }
+
+    /// Open an existing database without taking the write lock.
+    ///
+    /// The database is a static view as of the time it was opened, and it may be open in another
+    /// process (e.g. during an import). Fails if there is no database at the path.
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        settings: &ProfileDbOptions,
+    ) -> Result<Self, Error> {
+        // Read-only instances can't record merge failures.
+        let mut options = settings.to_options(None)?;
+        options.create_if_missing(false);
+
+        let existing = DB::list_cf(&options, &path)?;
+        let db = DB::open_cf_descriptors_read_only(
+            &options,
+            path,
+            column_family_descriptors(&options, Some(&existing)),
+            false,
+        )?;
+        let (key_format, mixed_keys) = detect_key_format(&db)?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            options,
+            merge_failures: None,
+            flusher: None,
+            key_format,
+            mixed_keys,
+        })
+    }
+
+    /// Open an existing database as a secondary instance that can follow a primary instance that
+    /// is open for writing in another process.
+    ///
+    /// The secondary instance stores its own logs in `secondary_path`, and sees the primary's
+    /// updates as of opening or the last call to `try_catch_up_with_primary`.
+    pub fn open_secondary<P: AsRef<Path>, S: AsRef<Path>>(
+        path: P,
+        secondary_path: S,
+        settings: &ProfileDbOptions,
+    ) -> Result<Self, Error> {
+        // Read-only instances can't record merge failures.
+        let mut options = settings.to_options(None)?;
+        options.create_if_missing(false);
+        options.set_max_open_files(-1);
+
+        let existing = DB::list_cf(&options, &path)?;
+        let db = DB::open_cf_descriptors_as_secondary(
+            &options,
+            path.as_ref(),
+            secondary_path.as_ref(),
+            column_family_descriptors(&options, Some(&existing)),
+        )?;
+        let (key_format, mixed_keys) = detect_key_format(&db)?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            options,
+            merge_failures: None,
+            flusher: None,
+            key_format,
+            mixed_keys,
+        })
+    }
+
+    /// The format of the profile keys in the database (see the `key` module).
+    pub fn key_format(&self) -> KeyFormat {
+        self.key_format
+    }
+
+    /// Whether the database has profile keys in both formats (after an interrupted key
+    /// migration).
+    ///
+    /// New entries are written in the legacy format until the migration is finished, but entries
+    /// in both formats are read, purged, and accepted by `check`.
+    pub fn has_mixed_keys(&self) -> bool {
+        self.mixed_keys
+    }
+
+    /// Update a secondary instance with the primary's latest changes.
+    pub fn try_catch_up_with_primary(&self) -> Result<(), Error> {
+        Ok(self.db.try_catch_up_with_primary()?)
+    }
+
+    pub fn estimate_key_count(&self) -> Result<usize, Error> {
+        let value = self.db.property_int_value("rocksdb.estimate-num-keys")?;
+
+        Ok(value.map(|value| value as usize).unwrap_or_default())
+    }
+
+    pub fn statistics(&self) -> Option<String> {
+        self.options.get_statistics()
+    }
+
+    /// Storage metrics for the profile column family (see the `health` module).
+    pub fn health(&self) -> Result<Health, Error> {
+        let int_property = |name: &str| -> Result<u64, Error> {
+            Ok(self.db.property_int_value(name)?.unwrap_or_default())
+        };
+
+        let mut health = Health {
+            pending_compaction_bytes: int_property("rocksdb.estimate-pending-compaction-bytes")?,
+            running_compactions: int_property("rocksdb.num-running-compactions")?,
+            memtable_bytes: int_property("rocksdb.cur-size-all-mem-tables")?,
+            estimated_key_count: int_property("rocksdb.estimate-num-keys")?,
+            delayed_write_rate: Some(int_property("rocksdb.actual-delayed-write-rate")?)
+                .filter(|rate| *rate > 0),
+            write_stopped: int_property("rocksdb.is-write-stopped")? != 0,
+            ..Health::default()
+        };
+
+        for file in self.db.live_files()? {
+            if file.column_family_name == DEFAULT_COLUMN_FAMILY_NAME {
+                let level = file.level.max(0) as usize;
+
+                if health.level_sizes.len() <= level {
+                    health.level_sizes.resize(level + 1, 0);
+                    health.level_file_counts.resize(level + 1, 0);
+                }
+
+                health.level_sizes[level] += file.size as u64;
+                health.level_file_counts[level] += 1;
+            }
+        }
+
+        if let Some(statistics) = self.statistics() {
+            health.block_cache_hits = health::parse_ticker(&statistics, "rocksdb.block.cache.hit");
+            health.block_cache_misses =
+                health::parse_ticker(&statistics, "rocksdb.block.cache.miss");
+        }
+
+        Ok(health)
+    }
+
+    /// Create a consistent copy of the database at a new path.
+    ///
+    /// Table files are hard-linked when the path is on the same file system, so checkpoints are
+    /// cheap to create, and the checkpoint can be opened as a normal database.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            return Err(Error::AlreadyExists(path.to_path_buf()));
+        }
+
+        Ok(Checkpoint::new(&self.db)?.create_checkpoint(path)?)
+    }
+
+    /// Add a verified incremental backup to the backup directory, optionally keeping only the
+    /// given number of most recent backups.
+    ///
+    /// See the `backup` module for listing, verifying, and restoring backups.
+    pub fn backup<P: AsRef<Path>>(
+        &self,
+        backup_dir: P,
+        keep: Option<usize>,
+    ) -> Result<BackupInfo, Error> {
+        let mut engine = backup::open_engine(&backup_dir)?;
+        engine.create_new_backup_flush(&self.db, true)?;
+
+        if let Some(keep) = keep {
+            engine.purge_old_backups(keep.max(1))?;
+        }
+
+        let info = backup::backup_infos(&engine)
+            .pop()
+            .ok_or_else(|| backup::Error::NoBackups(backup_dir.as_ref().to_path_buf()))?;
+        engine.verify_backup(info.id)?;
+
+        Ok(info)
+    }
+
+    /// Compact the profile key space (which rewrites table files with the current settings).
+    pub fn compact(&self) {
+        self.db.compact_range::<&[u8], &[u8]>(None, None);
+    }
+
+    /// Compact the profile entries, rewriting every table file (unlike `compact`, which may skip
+    /// files on the last level), so that all files are written with the current table options.
+    pub fn rewrite_tables(&self) {
+        let mut options = CompactOptions::default();
+        options.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+
+        self.db
+            .compact_range_opt::<&[u8], &[u8]>(None, None, &options);
+    }
+
+    /// A view of the database at the current point in time.
+    ///
+    /// Reads from the snapshot will not see any later updates, which makes it possible to build
+    /// several outputs from one consistent database state while an import is running.
+    pub fn snapshot(&self) -> ProfileSnapshot<'_> {
+        ProfileSnapshot {
+            snapshot: self.db.snapshot(),
+            key_format: self.key_format,
+            mixed_keys: self.mixed_keys,
+        }
+    }
+
+    pub fn lookup(&self, user_id: u64) -> Result<Vec<(DateTime<Utc>, User)>, Error> {
+        self.reader().lookup(user_id)
+    }
+
+    /// Look up the profiles for many user IDs at once.
+    ///
+    /// The IDs are sorted and looked up with a single iterator, which is much faster than calling
+    /// `lookup` for each ID. User IDs with no profiles in the database are not included in the
+    /// result. If `latest_only` is set, only the most recent profile is returned for each user.
+    pub fn lookup_many<I: IntoIterator<Item = u64>>(
+        &self,
+        user_ids: I,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, UserProfiles>, Error> {
+        self.reader()
+            .lookup_many(user_ids, latest_only, parse_value)
+    }
+
+    /// Look up the profiles for many user IDs with observation statistics (see `lookup_many`).
+    pub fn lookup_many_observed<I: IntoIterator<Item = u64>>(
+        &self,
+        user_ids: I,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, ObservedUserProfiles>, Error> {
+        self.reader()
+            .lookup_many(user_ids, latest_only, parse_observed_value)
+    }
+
+    pub fn iter(&self) -> ProfileIterator<'_> {
+        self.reader().iter(parse_value)
+    }
+
+    pub fn iter_observed(&self) -> ObservedProfileIterator<'_> {
+        self.reader().iter(parse_observed_value)
+    }
+
+    /// Iterate over the users with IDs in the range from `start_id` (inclusive) to `end_id`
+    /// (exclusive).
+    pub fn iter_range(&self, start_id: u64, end_id: u64) -> ProfileIterator<'_> {
+        self.reader().iter_range(start_id, end_id)
+    }
+
+    /// Split the range of user IDs in the database into the given number of contiguous shards.
+    ///
+    /// The shards divide the range between the smallest and largest IDs evenly, so they will only
+    /// contain similar numbers of users if IDs are distributed evenly.
+    pub fn shard_ranges(&self, shards: usize) -> Result<Vec<(u64, u64)>, Error> {
+        self.reader().shard_ranges(shards)
+    }
+
+    /// Scan the database in parallel, running the function on an iterator for each shard.
+    ///
+    /// Results are returned in the order of the shards (i.e. in user ID order).
+    pub fn par_scan<T, F>(&self, shards: usize, f: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        F: Fn(ProfileIterator<'_>) -> Result<T, Error> + Sync,
+    {
+        self.reader().par_scan(shards, f)
+    }
+
+    pub fn raw_iter(&self) -> ProfileRawIterator {
+        self.reader().raw_iter()
+    }
+
+    pub fn update(&self, user: &User) -> Result<(), Error> {
+        let key = self.key_format.key(user.id(), &user.screen_name);
+        let value = value::encode_user(&Header::new(user.snapshot), user)?;
+
+        let mut batch = WriteBatch::default();
+        batch.merge(key, value);
+        self.add_index_entries(&mut batch, user)?;
+        self.db.write(batch)?;
+        self.persist_merge_failures()?;
+
+        Ok(())
+    }
+
+    /// Write any merge failures collected in strict mode, returning the number written.
+    ///
+    /// Failures are also written periodically in the background (see the `merge_errors` module),
+    /// so this is only needed to make sure that they've been written at a particular point.
+    pub fn persist_merge_failures(&self) -> Result<usize, Error> {
+        match &self.merge_failures {
+            Some(sink) => persist_merge_failures(&self.db, sink),
+            None => Ok(0),
+        }
+    }
+
+    /// Return the recorded merge failures, ordered by entry key.
+    pub fn merge_failures(&self) -> Result<Vec<MergeFailure>, Error> {
+        self.persist_merge_failures()?;
+
+        let iterator = self
+            .db
+            .iterator_cf(self.merge_errors_cf()?, IteratorMode::Start);
+        let mut failures = vec![];
+
+        for result in iterator {
+            let (key, value) = result?;
+            failures.push(
+                MergeFailure::parse_record(&key, &value)
+                    .ok_or_else(|| Error::InvalidMergeErrorEntry(key.to_vec()))?,
+            );
+        }
+
+        Ok(failures)
+    }
+
+    /// Return the users whose profiles have linked to the given registrable domain.
+    pub fn domain_users(&self, domain: &str) -> Result<Vec<DomainEntry>, Error> {
+        let prefix = domains::prefix(domain);
+        let iterator = self.db.iterator_cf(
+            self.domains_cf()?,
+            IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+        );
+        let mut entries = vec![];
+
+        for result in iterator {
+            let (key, value) = result?;
+
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let entry = domains::parse_entry(&key, &value)
+                .ok_or_else(|| Error::InvalidDomainEntry(key.to_vec()))?;
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    /// Return the number of distinct users linking to each domain, ordered by domain.
+    pub fn domain_user_counts(&self) -> Result<Vec<(String, usize)>, Error> {
+        let iterator = self.db.iterator_cf(self.domains_cf()?, IteratorMode::Start);
+        let mut counts: Vec<(String, usize)> = vec![];
+
+        for result in iterator {
+            let (key, _) = result?;
+            let (domain, _) =
+                domains::parse_key(&key).ok_or_else(|| Error::InvalidDomainEntry(key.to_vec()))?;
+
+            match counts.last_mut() {
+                Some((last_domain, count)) if last_domain == domain => {
+                    *count += 1;
+                }
+                _ => {
+                    counts.push((domain.to_string(), 1));
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Return the counter samples for a user, ordered by snapshot.
+    pub fn counter_series(&self, user_id: u64) -> Result<Vec<CounterSample>, Error> {
+        let prefix = user_id.to_be_bytes();
+        let iterator = self.db.iterator_cf(
+            self.counters_cf()?,
+            IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+        );
+        let mut samples = vec![];
+
+        for result in iterator {
+            let (key, value) = result?;
+
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let sample = counters::parse_entry(&key, &value)
+                .ok_or_else(|| Error::InvalidCounterEntry(key.to_vec()))?;
+            samples.push(sample);
+        }
+
+        Ok(samples)
+    }
+
+    /// Iterate over the counter series for all users, in user ID order.
+    pub fn counter_series_iter(&self) -> Result<CounterSeriesIterator<'_>, Error> {
+        let mut underlying = self.db.raw_iterator_cf(self.counters_cf()?);
+        underlying.seek_to_first();
+
+        Ok(CounterSeriesIterator { underlying })
+    }
+
+    /// Return the users whose indexed fields match the query, with the matching fields.
+    pub fn search(&self, query: &Query) -> Result<BTreeMap<u64, Vec<Hit>>, Error> {
+        let terms_cf = self.terms_cf()?;
+
+        query.evaluate(&mut |field, token| {
+            let prefix = search::prefix(field, token);
+            let iterator = self.db.iterator_cf(
+                terms_cf,
+                IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+            );
+            let mut postings = vec![];
+
+            for result in iterator {
+                let (key, value) = result?;
+
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+
+                postings.push(
+                    search::parse_posting(&key, &value)
+                        .ok_or_else(|| Error::InvalidSearchEntry(key.to_vec()))?,
+                );
+            }
+
+            Ok(postings)
+        })
+    }
+
+    /// Merge all stored profiles from another database into this one.
+    ///
+    /// Entries are combined with the same merge operator used by `update`, so the result is the
+    /// same as if both databases had been built from the union of their inputs. Counter samples
+    /// are copied from the other database if it has them. Entries that can't be decoded are
+    /// logged and skipped, so that a few corrupt entries don't stop a merge part of the way
+    /// through.
+    pub fn merge_from(&self, other: &ProfileDb) -> Result<MergeSummary, Error> {
+        let mut summary = MergeSummary::default();
+        let mut current_user_id = None;
+        let mut batch = WriteBatch::default();
+        let mut iterator = other.db.raw_iterator_opt(total_order_read_options());
+        iterator.seek_to_first();
+
+        while let Some((other_key, value)) = iterator.item() {
+            let entry = parse_profile_user_id(other_key)
+                .and_then(|user_id| Ok((user_id, parse_value(value)?)));
+
+            let (user_id, (first_seen, user)) = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    log::warn!("Skipping invalid entry {:?}: {:?}", other_key, error);
+                    summary.skipped_entries += 1;
+                    iterator.next();
+                    continue;
+                }
+            };
+            // The other database may use a different key format.
+            let key = self.key_format.key(user_id, &user.screen_name);
+
+            if current_user_id != Some(user_id) {
+                if !self.contains_user(user_id)? {
+                    summary.new_users += 1;
+                }
+                current_user_id = Some(user_id);
+            }
+
+            match self.db.get_pinned(&key)? {
+                Some(existing) => {
+                    let (existing_first_seen, existing_user) = parse_value(&existing)?;
+
+                    if first_seen < existing_first_seen || user.snapshot > existing_user.snapshot {
+                        summary.updated_profiles += 1;
+                    }
+                }
+                None => {
+                    summary.new_screen_names += 1;
+                }
+            }
+
+            batch.merge(key, value);
+            self.add_index_entries(&mut batch, &user)?;
+            summary.entry_count += 1;
+
+            if summary.entry_count % MERGE_BATCH_SIZE == 0 {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+
+            if summary.entry_count % MERGE_PROGRESS_INTERVAL == 0 {
+                log::info!(
+                    "Merged {} entries ({} new users, {} new screen names, {} updated)",
+                    summary.entry_count,
+                    summary.new_users,
+                    summary.new_screen_names,
+                    summary.updated_profiles
+                );
+            }
+
+            iterator.next();
+        }
+
+        iterator.status()?;
+        self.db.write(std::mem::take(&mut batch))?;
+
+        // The other database may have counter samples for snapshots it no longer stores.
+        if let Some(other_counters_cf) = other.db.cf_handle(COUNTERS_CF_NAME) {
+            let counters_cf = self.counters_cf()?;
+
+            for result in other.db.iterator_cf(other_counters_cf, IteratorMode::Start) {
+                let (key, value) = result?;
+                batch.put_cf(counters_cf, key, value);
+
+                if batch.len() >= MERGE_BATCH_SIZE {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+
+            self.db.write(batch)?;
+        }
+
+        self.persist_merge_failures()?;
+
+        Ok(summary)
+    }
+
+    /// Delete all stored data for a user (see `purge_many`).
+    pub fn purge(&self, user_id: u64) -> Result<PurgeSummary, Error> {
+        self.purge_many(&HashSet::from([user_id]))
+    }
+
+    /// Delete all stored data for the given users from every column family.
+    ///
+    /// The secondary indices are not keyed by user ID, so they are scanned in full (once for all
+    /// of the users). The affected key ranges are compacted so that the deleted values are
+    /// removed from the table files, but they will still be present in existing backups and
+    /// checkpoints.
+    ///
+    /// Pending merge failures are written before the merge error records are deleted, and no
+    /// failures are written while the purge runs, so none for the users are left behind.
+    pub fn purge_many(&self, user_ids: &HashSet<u64>) -> Result<PurgeSummary, Error> {
+        let _writing = match &self.merge_failures {
+            Some(sink) => {
+                let writing = sink.lock_writing();
+                write_merge_failures(&self.db, sink.take())?;
+                Some(writing)
+            }
+            None => None,
+        };
+
+        let mut summary = PurgeSummary::default();
+        let mut sorted_user_ids = user_ids.iter().copied().collect::<Vec<_>>();
+        sorted_user_ids.sort_unstable();
+
+        let default_cf = self
+            .db
+            .cf_handle(DEFAULT_COLUMN_FAMILY_NAME)
+            .ok_or(Error::MissingColumnFamily(DEFAULT_COLUMN_FAMILY_NAME))?;
+
+        for user_id in sorted_user_ids {
+            for prefix in self.reader().prefixes(user_id) {
+                summary.profile_entries += self.purge_prefix(default_cf, &prefix)?;
+            }
+            summary.counter_entries +=
+                self.purge_prefix(self.counters_cf()?, &user_id.to_be_bytes())?;
+
+            // Quarantined entries and merge errors may have keys in either format.
+            for key_format in [KeyFormat::Legacy, KeyFormat::V1] {
+                let prefix = key_format.prefix(user_id);
+                summary.other_entries += self.purge_prefix(self.quarantine_cf()?, &prefix)?;
+                summary.other_entries += self.purge_prefix(self.merge_errors_cf()?, &prefix)?;
+            }
+        }
+
+        summary.index_entries += self.purge_index(self.domains_cf()?, user_ids, |key| {
+            domains::parse_key(key).map(|(_, user_id)| user_id)
+        })?;
+        summary.index_entries +=
+            self.purge_index(self.terms_cf()?, user_ids, search::key_user_id)?;
+
+        // Failures may have been collected while the entries were being deleted.
+        if let Some(sink) = &self.merge_failures {
+            sink.retain(|failure| {
+                failure
+                    .user_id()
+                    .filter(|user_id| user_ids.contains(user_id))
+                    .is_none()
+            });
+        }
+
+        Ok(summary)
+    }
+
+    /// Check the integrity of every profile entry, optionally repairing or quarantining the
+    /// entries with problems (see the `check` module).
+    pub fn check(&self, mode: Mode) -> Result<Report, Error> {
+        let quarantine_cf = match mode {
+            Mode::Report => None,
+            Mode::Quarantine | Mode::Repair => Some(self.quarantine_cf()?),
+        };
+        let mut report = Report::default();
+        let mut batch = WriteBatch::default();
+        let key_formats = self.reader().key_formats();
+        let mut iterator = self.db.raw_iterator_opt(total_order_read_options());
+        iterator.seek_to_first();
+
+        while let Some((key, value)) = iterator.item() {
+            report.entry_count += 1;
+
+            let checked = check::check_entry(key, value, key_formats);
+
+            if !checked.problems.is_empty() {
+                let action = match (quarantine_cf, checked.decoded) {
+                    (None, _) => Action::None,
+                    (Some(_), Some((mut header, user))) if mode == Mode::Repair => {
+                        header.first_seen = header.first_seen.min(user.snapshot);
+                        header.last_seen = header.last_seen.max(user.snapshot);
+                        let repaired = value::encode_user(&header, &user)?;
+
+                        // Entries are repaired in their own key format if it's accepted (so
+                        // that a partial key migration isn't undone). The merge operator keeps
+                        // the earlier first-seen timestamp.
+                        let key_format = key::parse(key)
+                            .map(|(format, _, _)| format)
+                            .filter(|format| key_formats.contains(format))
+                            .unwrap_or(self.key_format);
+                        let correct_key = key_format.key(user.id(), &user.screen_name);
+                        if correct_key != key {
+                            batch.delete(key);
+                        }
+                        batch.merge(correct_key, repaired);
+
+                        Action::Repaired
+                    }
+                    (Some(quarantine_cf), _) => {
+                        batch.put_cf(quarantine_cf, key, value);
+                        batch.delete(key);
+
+                        Action::Quarantined
+                    }
+                };
+
+                report.issues.push(Issue {
+                    key: key.to_vec(),
+                    problems: checked.problems,
+                    action,
+                });
+
+                if batch.len() >= MERGE_BATCH_SIZE {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+
+            iterator.next();
+        }
+
+        iterator.status()?;
+        self.db.write(batch)?;
+        self.persist_merge_failures()?;
+
+        Ok(report)
+    }
+
+    /// Rebuild the secondary indices from the stored profiles, returning the number of profiles
+    /// indexed.
+    ///
+    /// Counter samples are not cleared, since earlier samples can't be rebuilt from the stored
+    /// profiles.
+    pub fn reindex(&self) -> Result<usize, Error> {
+        self.db
+            .delete_range_cf(self.domains_cf()?, &[][..], &[u8::MAX][..])?;
+        self.db
+            .delete_range_cf(self.terms_cf()?, &[][..], &[u8::MAX][..])?;
+
+        let mut count = 0;
+
+        for result in self.raw_iter() {
+            let (_, (_, user)) = result?;
+            let mut batch = WriteBatch::default();
+            self.add_index_entries(&mut batch, &user)?;
+            self.db.write(batch)?;
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Rewrite any profile keys in the legacy format in the current format, returning the number
+    /// of entries rewritten.
+    ///
+    /// Each batch of entries is moved atomically, so an interrupted migration can be resumed by
+    /// running it again.
+    pub fn migrate_keys(&mut self) -> Result<usize, Error> {
+        let mut options = total_order_read_options();
+        options.set_iterate_upper_bound(vec![key::PROFILE_KIND]);
+        let mut iterator = self.db.raw_iterator_opt(options);
+        iterator.seek_to_first();
+
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+
+        while let Some((old_key, value)) = iterator.item() {
+            let new_key = match parse_value(value) {
+                Ok((_, user)) => KeyFormat::V1.key(user.id(), &user.screen_name),
+                // Entries that can't be decoded keep their screen name bytes (and can be
+                // quarantined later by `check`).
+                Err(_) => match key::parse(old_key) {
+                    Some((_, user_id, screen_name)) => {
+                        KeyFormat::V1.key_from_bytes(user_id, screen_name)
+                    }
+                    None => {
+                        log::warn!("Skipping invalid key: {:?}", old_key);
+                        iterator.next();
+                        continue;
+                    }
+                },
+            };
+
+            // The merge operator combines entries whose screen names only differ by case.
+            batch.merge(new_key, value);
+            batch.delete(old_key);
+            count += 1;
+
+            if batch.len() >= MERGE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+                log::info!("Migrated {} keys", count);
+            }
+
+            iterator.next();
+        }
+
+        iterator.status()?;
+        self.db.write(batch)?;
+        self.db
+            .compact_range(None::<&[u8]>, Some(&[key::PROFILE_KIND][..]));
+        self.key_format = KeyFormat::V1;
+        self.mixed_keys = false;
+
+        Ok(count)
+    }
+
+    fn add_index_entries(&self, batch: &mut WriteBatch, user: &User) -> Result<(), Error> {
+        let domains_cf = self.domains_cf()?;
+
+        for domain in domains::user_domains(user) {
+            batch.merge_cf(
+                domains_cf,
+                domains::key(&domain, user.id()),
+                domains::value(user.snapshot, user.snapshot),
+            );
+        }
+
+        let terms_cf = self.terms_cf()?;
+
+        for (key, value) in search::entries(user) {
+            batch.merge_cf(terms_cf, key, value);
+        }
+
+        batch.put_cf(
+            self.counters_cf()?,
+            counters::key(user.id(), user.snapshot),
+            counters::value(user),
+        );
+
+        Ok(())
+    }
+
+    fn reader(&self) -> Reader<'_> {
+        Reader {
+            source: ReaderSource::Db(&self.db),
+            key_format: self.key_format,
+            mixed_keys: self.mixed_keys,
+        }
+    }
+
+    /// Delete and compact the index entries for the given users, returning the number deleted.
+    fn purge_index(
+        &self,
+        cf: &ColumnFamily,
+        user_ids: &HashSet<u64>,
+        key_user_id: fn(&[u8]) -> Option<u64>,
+    ) -> Result<usize, Error> {
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+
+        for result in self.db.iterator_cf(cf, IteratorMode::Start) {
+            let (key, _) = result?;
+
+            if key_user_id(&key)
+                .filter(|user_id| user_ids.contains(user_id))
+                .is_some()
+            {
+                batch.delete_cf(cf, key);
+                deleted += 1;
+
+                if batch.len() >= MERGE_BATCH_SIZE {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        self.db.write(batch)?;
+
+        if deleted > 0 {
+            self.db.compact_range_cf::<&[u8], &[u8]>(cf, None, None);
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete and compact the entries with a prefix in a column family, returning the number
+    /// deleted.
+    fn purge_prefix(&self, cf: &ColumnFamily, prefix: &[u8]) -> Result<usize, Error> {
+        let mut batch = WriteBatch::default();
+        let mut iterator = self.db.raw_iterator_cf(cf);
+        iterator.seek(prefix);
+
+        while let Some(key) = iterator.key() {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            batch.delete_cf(cf, key);
+            iterator.next();
+        }
+
+        iterator.status()?;
+
+        let deleted = batch.len();
+
+        if deleted > 0 {
+            self.db.write(batch)?;
+
+            let end = prefix_successor(prefix);
+            self.db.compact_range_cf(cf, Some(prefix), end.as_deref());
+        }
+
+        Ok(deleted)
+    }
+
+    fn contains_user(&self, user_id: u64) -> Result<bool, Error> {
+        let mut iterator = self.db.raw_iterator();
+
+        for prefix in self.reader().prefixes(user_id) {
+            iterator.seek(&prefix);
+
+            let found = iterator
+                .key()
+                .filter(|key| key.starts_with(&prefix))
+                .is_some();
+            iterator.status()?;
+
+            if found {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn merge_errors_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(MERGE_ERRORS_CF_NAME)
+            .ok_or(Error::MissingColumnFamily(MERGE_ERRORS_CF_NAME))
+    }
+
+    fn quarantine_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(QUARANTINE_CF_NAME)
+            .ok_or(Error::MissingColumnFamily(QUARANTINE_CF_NAME))
+    }
+
+    fn counters_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(COUNTERS_CF_NAME)
+            .ok_or(Error::MissingColumnFamily(COUNTERS_CF_NAME))
+    }
+
+    fn terms_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(TERMS_CF_NAME)
+            .ok_or(Error::MissingColumnFamily(TERMS_CF_NAME))
+    }
+
+    fn domains_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(DOMAINS_CF_NAME)
+            .ok_or(Error::MissingColumnFamily(DOMAINS_CF_NAME))
+    }
+}
+
+impl Drop for ProfileDb {
+    fn drop(&mut self) {
+        // Stop the background thread first (if this is the last clone), so that the final write
+        // includes everything.
+        self.flusher.take();
+
+        if let Err(error) = self.persist_merge_failures() {
+            log::error!("Could not record merge failures: {:?}", error);
+        }
+    }
+}
+
+/// A consistent point-in-time view of a `ProfileDb` (see `ProfileDb::snapshot`).
+pub struct ProfileSnapshot<'a> {
+    snapshot: Snapshot<'a>,
+    key_format: KeyFormat,
+    mixed_keys: bool,
 }
 
-impl ProfileDb {
-    pub fn open<P: AsRef<Path>>(path: P, enable_statistics: bool) -> Result<Self, Error> {
-        let mut options = Options::default();
-        options.create_if_missing(true);
-        options.set_compression_type(DBCompressionType::Zstd);
-        options.set_merge_operator_associative("merge", merge);
+impl ProfileSnapshot<'_> {
+    pub fn lookup(&self, user_id: u64) -> Result<Vec<(DateTime<Utc>, User)>, Error> {
+        self.reader().lookup(user_id)
+    }
 
-        if enable_statistics {
-            options.enable_statistics();
+    pub fn lookup_many<I: IntoIterator<Item = u64>>(
+        &self,
+        user_ids: I,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, UserProfiles>, Error> {
+        self.reader()
+            .lookup_many(user_ids, latest_only, parse_value)
+    }
+
+    pub fn lookup_many_observed<I: IntoIterator<Item = u64>>(
+        &self,
+        user_ids: I,
+        latest_only: bool,
+    ) -> Result<HashMap<u64, ObservedUserProfiles>, Error> {
+        self.reader()
+            .lookup_many(user_ids, latest_only, parse_observed_value)
+    }
+
+    pub fn iter(&self) -> ProfileIterator<'_> {
+        self.reader().iter(parse_value)
+    }
+
+    pub fn iter_observed(&self) -> ObservedProfileIterator<'_> {
+        self.reader().iter(parse_observed_value)
+    }
+
+    pub fn iter_range(&self, start_id: u64, end_id: u64) -> ProfileIterator<'_> {
+        self.reader().iter_range(start_id, end_id)
+    }
+
+    pub fn shard_ranges(&self, shards: usize) -> Result<Vec<(u64, u64)>, Error> {
+        self.reader().shard_ranges(shards)
+    }
+
+    pub fn par_scan<T, F>(&self, shards: usize, f: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        F: Fn(ProfileIterator<'_>) -> Result<T, Error> + Sync,
+    {
+        self.reader().par_scan(shards, f)
+    }
+
+    pub fn raw_iter(&self) -> ProfileRawIterator<'_> {
+        self.reader().raw_iter()
+    }
+
+    fn reader(&self) -> Reader<'_> {
+        Reader {
+            source: ReaderSource::Snapshot(&self.snapshot),
+            key_format: self.key_format,
+            mixed_keys: self.mixed_keys,
         }
+    }
+}
 
-        let db = DB::open(&options, path)?;
+#[derive(Clone, Copy)]
+enum ReaderSource<'a> {
+    Db(&'a DB),
+    Snapshot(&'a Snapshot<'a>),
+}
 
-        Ok(Self {
-            db: Arc::new(db),
-            options,
-        })
+/// The read operations shared by the database and its snapshots.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    source: ReaderSource<'a>,
+    key_format: KeyFormat,
+    mixed_keys: bool,
+}
+
+/// Optional lower (inclusive) and upper (exclusive) bounds for profile keys.
+type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+impl<'a> Reader<'a> {
+    /// The key formats that profile entries are read with.
+    fn key_formats(self) -> &'static [KeyFormat] {
+        match (self.mixed_keys, self.key_format) {
+            (true, _) => &[KeyFormat::Legacy, KeyFormat::V1],
+            (false, KeyFormat::Legacy) => &[KeyFormat::Legacy],
+            (false, KeyFormat::V1) => &[KeyFormat::V1],
+        }
     }
 
-    pub fn estimate_key_count(&self) -> Result<usize, Error> {
-        let value = self.db.property_int_value("rocksdb.estimate-num-keys")?;
+    fn prefixes(self, user_id: u64) -> Vec<Vec<u8>> {
+        self.key_formats()
+            .iter()
+            .map(|format| format.prefix(user_id))
+            .collect()
+    }
 
-        Ok(value.map(|value| value as usize).unwrap_or_default())
+    /// The key ranges containing all profile entries (one per format if keys are mixed, since
+    /// each format's keys are ordered by user ID separately).
+    fn full_ranges(self) -> Vec<KeyRange> {
+        if self.mixed_keys {
+            vec![
+                (None, Some(vec![key::PROFILE_KIND])),
+                (Some(vec![key::PROFILE_KIND]), None),
+            ]
+        } else {
+            vec![(None, None)]
+        }
     }
 
-    pub fn statistics(&self) -> Option<String> {
-        self.options.get_statistics()
+    fn iterator(self, mode: IteratorMode, options: ReadOptions) -> DBIterator<'a> {
+        match self.source {
+            ReaderSource::Db(db) => db.iterator_opt(mode, options),
+            ReaderSource::Snapshot(snapshot) => snapshot.iterator_opt(mode, options),
+        }
     }
 
-    pub fn lookup(&self, user_id: u64) -> Result<Vec<(DateTime<Utc>, User)>, Error> {
-        let prefix = user_id.to_be_bytes();
-        let iterator = self.db.prefix_iterator(prefix);
-        let mut users: Vec<(DateTime<Utc>, User)> = vec![];
+    fn raw_iterator(self) -> DBRawIterator<'a> {
+        match self.source {
+            ReaderSource::Db(db) => db.raw_iterator(),
+            ReaderSource::Snapshot(snapshot) => snapshot.raw_iterator(),
+        }
+    }
 
-        for result in iterator {
-            let (key, value) = result?;
-            let next_user_id = u64::from_be_bytes(
-                key[0..8]
-                    .try_into()
-                    .map_err(|_| Error::InvalidKey(key.to_vec()))?,
-            );
+    fn lookup(self, user_id: u64) -> Result<Vec<(DateTime<Utc>, User)>, Error> {
+        read_user(
+            &mut self.raw_iterator(),
+            &self.prefixes(user_id),
+            parse_value,
+        )
+    }
 
-            if next_user_id == user_id {
-                users.push(parse_value(value)?);
-            } else {
-                break;
+    fn lookup_many<T, I: IntoIterator<Item = u64>>(
+        self,
+        user_ids: I,
+        latest_only: bool,
+        parse: ValueParser<T>,
+    ) -> Result<HashMap<u64, Vec<(T, User)>>, Error> {
+        let mut user_ids = user_ids.into_iter().collect::<Vec<_>>();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+
+        let mut iterator = self.raw_iterator();
+        let mut results = HashMap::with_capacity(user_ids.len());
+
+        for user_id in user_ids {
+            let mut users = read_user(&mut iterator, &self.prefixes(user_id), parse)?;
+
+            if !users.is_empty() {
+                if latest_only {
+                    users.drain(..users.len() - 1);
+                }
+
+                results.insert(user_id, users);
             }
         }
 
-        users.sort_by_key(|(_, user)| user.snapshot);
+        Ok(results)
+    }
+
+    fn iter<T>(self, parse: ValueParser<T>) -> ProfileIterator<'a, T> {
+        self.profile_iterator(self.full_ranges(), parse)
+    }
+
+    fn iter_range(self, start_id: u64, end_id: u64) -> ProfileIterator<'a> {
+        let ranges = self
+            .key_formats()
+            .iter()
+            .map(|format| (Some(format.prefix(start_id)), Some(format.prefix(end_id))))
+            .collect();
 
-        Ok(users)
+        self.profile_iterator(ranges, parse_value)
     }
 
-    pub fn iter(&self) -> ProfileIterator<'_> {
+    fn profile_iterator<T>(
+        self,
+        ranges: Vec<KeyRange>,
+        parse: ValueParser<T>,
+    ) -> ProfileIterator<'a, T> {
         ProfileIterator {
-            underlying: self
-                .db
-                .iterator(IteratorMode::From(&[], rocksdb::Direction::Forward)),
+            underlying: EntryIterator {
+                ranges: ranges
+                    .into_iter()
+                    .map(|range| {
+                        self.iterator(IteratorMode::Start, range_read_options(range))
+                            .peekable()
+                    })
+                    .collect(),
+            },
+            parse,
             current: None,
             finished: false,
         }
     }
 
-    pub fn raw_iter(&self) -> ProfileRawIterator {
-        ProfileRawIterator {
-            underlying: self.db.iterator(IteratorMode::Start),
+    fn shard_ranges(self, shards: usize) -> Result<Vec<(u64, u64)>, Error> {
+        let mut bounds: Option<(u128, u128)> = None;
+
+        for range in self.full_ranges() {
+            let first = self
+                .iterator(IteratorMode::Start, range_read_options(range.clone()))
+                .next()
+                .transpose()?;
+            let last = self
+                .iterator(IteratorMode::End, range_read_options(range))
+                .next()
+                .transpose()?;
+
+            if let (Some((first_key, _)), Some((last_key, _))) = (first, last) {
+                let first_id = parse_profile_user_id(&first_key)? as u128;
+                let last_id = parse_profile_user_id(&last_key)? as u128;
+
+                bounds = Some(match bounds {
+                    Some((min, max)) => (min.min(first_id), max.max(last_id + 1)),
+                    None => (first_id, last_id + 1),
+                });
+            }
+        }
+
+        match bounds {
+            Some((min, max)) => {
+                let shards = (shards.max(1) as u128).min(max - min);
+
+                Ok((0..shards)
+                    .map(|i| {
+                        let start = min + (max - min) * i / shards;
+                        let end = min + (max - min) * (i + 1) / shards;
+
+                        (start as u64, end.min(u64::MAX as u128) as u64)
+                    })
+                    .collect())
+            }
+            None => Ok(vec![]),
         }
     }
 
-    pub fn update(&self, user: &User) -> Result<(), Error> {
-        let key = Self::make_key(user.id, &user.screen_name);
-        let avro_value = to_value(user)?;
-        let bytes = to_avro_datum(&USER_SCHEMA, avro_value)?;
-        let mut value = Vec::with_capacity(bytes.len() + 8);
-        value.extend_from_slice(&user.snapshot.to_be_bytes());
-        value.extend_from_slice(&bytes);
-        Ok(self.db.merge(key, value)?)
+    fn par_scan<T, F>(self, shards: usize, f: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        F: Fn(ProfileIterator<'_>) -> Result<T, Error> + Sync,
+    {
+        let ranges = self.shard_ranges(shards)?;
+        let f = &f;
+
+        std::thread::scope(|scope| {
+            let handles = ranges
+                .into_iter()
+                .map(|(start_id, end_id)| scope.spawn(move || f(self.iter_range(start_id, end_id))))
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|error| std::panic::resume_unwind(error))
+                })
+                .collect()
+        })
+    }
+
+    fn raw_iter(self) -> ProfileRawIterator<'a> {
+        ProfileRawIterator {
+            underlying: self.iterator(IteratorMode::Start, total_order_read_options()),
+        }
     }
+}
+
+/// Read all profiles with any of a user's key prefixes, ordered by snapshot, leaving the iterator
+/// after the last prefix.
+fn read_user<T>(
+    iterator: &mut DBRawIterator<'_>,
+    prefixes: &[Vec<u8>],
+    parse: ValueParser<T>,
+) -> Result<Vec<(T, User)>, Error> {
+    let mut users = vec![];
+
+    for prefix in prefixes {
+        iterator.seek(prefix);
+
+        while let Some((key, value)) = iterator.item() {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            users.push(parse(value)?);
+            iterator.next();
+        }
 
-    fn make_key(user_id: i64, screen_name: &str) -> Vec<u8> {
-        let screen_name_clean = screen_name.to_lowercase();
-        let screen_name_bytes = screen_name_clean.as_bytes();
-        let mut key = Vec::with_capacity(screen_name_bytes.len() + 8);
-        key.extend_from_slice(&user_id.to_be_bytes());
-        key.extend_from_slice(screen_name_bytes);
-        key
+        iterator.status()?;
     }
+
+    users.sort_by_key(|(_, user)| user.snapshot);
+
+    Ok(users)
 }
 
 pub struct ProfileRawIterator<'a> {
@@ -134,26 +1490,240 @@ impl Iterator for ProfileRawIterator<'_> {
     }
 }
 
-fn parse_pair(key: &[u8], value: &[u8]) -> Result<(u64, (DateTime<Utc>, User)), Error> {
-    let user_id = u64::from_be_bytes(
-        key[0..8]
-            .try_into()
-            .map_err(|_| Error::InvalidKey(key.to_vec()))?,
-    );
+pub struct CounterSeriesIterator<'a> {
+    underlying: DBRawIterator<'a>,
+}
+
+impl Iterator for CounterSeriesIterator<'_> {
+    type Item = Result<(u64, Vec<CounterSample>), Error>;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut current = None;
+        let mut samples = vec![];
+
+        while let Some((key, value)) = self.underlying.item() {
+            let user_id = match parse_user_id(key) {
+                Ok(user_id) => user_id,
+                Err(_) => return Some(Err(Error::InvalidCounterEntry(key.to_vec()))),
+            };
+
+            if current.filter(|current| *current != user_id).is_some() {
+                break;
+            }
+
+            match counters::parse_entry(key, value) {
+                Some(sample) => samples.push(sample),
+                None => return Some(Err(Error::InvalidCounterEntry(key.to_vec()))),
+            }
+
+            current = Some(user_id);
+            self.underlying.next();
+        }
+
+        match self.underlying.status() {
+            Ok(()) => current.map(|user_id| Ok((user_id, samples))),
+            Err(error) => Some(Err(Error::from(error))),
+        }
+    }
+}
+
+/// Descriptors for the column families, restricted to those in `existing` if provided (since
+/// they can't be created when the database isn't opened for writing).
+fn column_family_descriptors(
+    options: &Options,
+    existing: Option<&[String]>,
+) -> Vec<ColumnFamilyDescriptor> {
+    let mut domains_options = Options::default();
+    domains_options.set_compression_type(DBCompressionType::Zstd);
+    domains_options.set_merge_operator_associative("domain_merge", domains::merge);
+
+    let mut terms_options = Options::default();
+    terms_options.set_compression_type(DBCompressionType::Zstd);
+    terms_options.set_merge_operator_associative("terms_merge", search::merge);
+
+    let mut counters_options = Options::default();
+    counters_options.set_compression_type(DBCompressionType::Zstd);
+
+    vec![
+        (DEFAULT_COLUMN_FAMILY_NAME, options.clone()),
+        (DOMAINS_CF_NAME, domains_options),
+        (TERMS_CF_NAME, terms_options),
+        (COUNTERS_CF_NAME, counters_options),
+        (QUARANTINE_CF_NAME, Options::default()),
+        (MERGE_ERRORS_CF_NAME, Options::default()),
+    ]
+    .into_iter()
+    .filter(|(name, _)| {
+        existing
+            .map(|existing| existing.iter().any(|existing_name| existing_name == name))
+            .unwrap_or(true)
+    })
+    .map(|(name, options)| ColumnFamilyDescriptor::new(name, options))
+    .collect()
+}
+
+/// Read options for iterators that cross user ID prefixes.
+fn total_order_read_options() -> ReadOptions {
+    let mut options = ReadOptions::default();
+    options.set_total_order_seek(true);
+    options
+}
+
+fn range_read_options((lower, upper): KeyRange) -> ReadOptions {
+    let mut options = total_order_read_options();
+
+    if let Some(lower) = lower {
+        options.set_iterate_lower_bound(lower);
+    }
+
+    if let Some(upper) = upper {
+        options.set_iterate_upper_bound(upper);
+    }
+
+    options
+}
+
+fn parse_pair(key: &[u8], value: &[u8]) -> Result<(u64, (DateTime<Utc>, User)), Error> {
+    let user_id = parse_profile_user_id(key)?;
     let (timestamp, user) = parse_value(value)?;
 
     Ok((user_id, (timestamp, user)))
 }
 
-pub struct ProfileIterator<'a> {
-    underlying: DBIterator<'a>,
-    current: Option<(DateTime<Utc>, User)>,
+/// Write the merge failures that have been collected, returning the number written.
+fn persist_merge_failures(db: &DB, sink: &Sink) -> Result<usize, Error> {
+    let _writing = sink.lock_writing();
+
+    write_merge_failures(db, sink.take())
+}
+
+fn write_merge_failures(db: &DB, failures: Vec<MergeFailure>) -> Result<usize, Error> {
+    if failures.is_empty() {
+        return Ok(0);
+    }
+
+    let merge_errors_cf = db
+        .cf_handle(MERGE_ERRORS_CF_NAME)
+        .ok_or(Error::MissingColumnFamily(MERGE_ERRORS_CF_NAME))?;
+    let mut batch = WriteBatch::default();
+
+    for failure in &failures {
+        batch.put_cf(
+            merge_errors_cf,
+            failure.record_key(),
+            failure.record_value(),
+        );
+    }
+
+    db.write(batch)?;
+
+    Ok(failures.len())
+}
+
+fn parse_profile_user_id(key: &[u8]) -> Result<u64, Error> {
+    key::parse(key)
+        .map(|(_, user_id, _)| user_id)
+        .ok_or_else(|| Error::InvalidKey(key.to_vec()))
+}
+
+/// The smallest key that is greater than every key with the prefix, if there is one.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// Detect the key format from the first and last profile keys (new databases use the current
+/// format), and whether keys in both formats are present.
+///
+/// Current keys always sort after legacy keys, so both formats are present exactly when the first
+/// key is a legacy key and the last is not.
+fn detect_key_format(db: &DB) -> Result<(KeyFormat, bool), Error> {
+    let first = db
+        .iterator_opt(IteratorMode::Start, total_order_read_options())
+        .next()
+        .transpose()?;
+
+    match first.and_then(|(key, _)| KeyFormat::of(&key)) {
+        Some(KeyFormat::Legacy) => {
+            let last = db
+                .iterator_opt(IteratorMode::End, total_order_read_options())
+                .next()
+                .transpose()?;
+
+            let mixed_keys = last.and_then(|(key, _)| KeyFormat::of(&key)) == Some(KeyFormat::V1);
+
+            if mixed_keys {
+                log::warn!("Key migration is incomplete; run it again to finish");
+            }
+
+            Ok((KeyFormat::Legacy, mixed_keys))
+        }
+        _ => Ok((KeyFormat::V1, false)),
+    }
+}
+
+/// Parse the user ID prefix of a key in a column family keyed by user ID.
+fn parse_user_id(key: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        key.get(0..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::InvalidKey(key.to_vec()))?,
+    ))
+}
+
+type ValueParser<T> = fn(&[u8]) -> Result<(T, User), Error>;
+
+/// Profile entries from one or more key ranges, merged in user ID order.
+///
+/// Entries with invalid keys (and errors) come first, so that they're reported immediately.
+struct EntryIterator<'a> {
+    ranges: Vec<std::iter::Peekable<DBIterator<'a>>>,
+}
+
+impl Iterator for EntryIterator<'_> {
+    type Item = Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, index) = self
+            .ranges
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, range)| {
+                range.peek().map(|result| {
+                    let user_id = result
+                        .as_ref()
+                        .ok()
+                        .and_then(|(key, _)| key::parse(key))
+                        .map(|(_, user_id, _)| user_id);
+
+                    (user_id, index)
+                })
+            })
+            .min()?;
+
+        self.ranges[index].next()
+    }
+}
+
+/// An iterator over users, yielding each user's profiles ordered by snapshot (with first-seen
+/// timestamps by default).
+pub struct ProfileIterator<'a, T = DateTime<Utc>> {
+    underlying: EntryIterator<'a>,
+    parse: ValueParser<T>,
+    current: Option<(T, User)>,
     finished: bool,
 }
 
-impl Iterator for ProfileIterator<'_> {
-    type Item = Result<Vec<(DateTime<Utc>, User)>, Error>;
+impl<T> Iterator for ProfileIterator<'_, T> {
+    type Item = Result<Vec<(T, User)>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.current.take() {
@@ -163,7 +1733,7 @@ impl Iterator for ProfileIterator<'_> {
 
                 loop {
                     match self.underlying.next() {
-                        Some(Ok((_, value))) => match parse_value(value) {
+                        Some(Ok((_, value))) => match (self.parse)(&value) {
                             Ok((next_timestamp, next_user)) => {
                                 if next_user.id == user_id {
                                     batch.push((next_timestamp, next_user));
@@ -193,7 +1763,7 @@ impl Iterator for ProfileIterator<'_> {
                     None
                 } else {
                     match self.underlying.next() {
-                        Some(Ok((_, value))) => match parse_value(value) {
+                        Some(Ok((_, value))) => match (self.parse)(&value) {
                             Ok((next_timestamp, next_user)) => {
                                 self.current = Some((next_timestamp, next_user));
                                 self.next()
@@ -209,82 +1779,403 @@ impl Iterator for ProfileIterator<'_> {
     }
 }
 
-fn parse_value<T: AsRef<[u8]>>(value: T) -> Result<(DateTime<Utc>, User), Error> {
-    let value = value.as_ref();
-    let timestamp_s = i64::from_be_bytes(
-        value[0..8]
-            .try_into()
-            .map_err(|_| Error::InvalidTimestamp(value[0..8].to_vec()))?,
-    );
+fn parse_value(value: &[u8]) -> Result<(DateTime<Utc>, User), Error> {
+    let (observations, user) = parse_observed_value(value)?;
 
-    let mut cursor = Cursor::new(&value[8..]);
-    let avro_value = from_avro_datum(&USER_SCHEMA, &mut cursor, None)?;
-    let user = from_value(&avro_value)?;
-    Ok((Utc.timestamp(timestamp_s, 0), user))
+    Ok((observations.first_seen, user))
 }
 
-fn merge(_key: &[u8], existing_val: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
-    let mut current_timestamp = None;
-    let mut current_user = None;
+fn parse_observed_value(value: &[u8]) -> Result<(Observations, User), Error> {
+    let (header, user) = value::decode(value)?;
 
-    if let Some(bytes) = existing_val {
-        match parse_value(bytes) {
-            Ok((timestamp, user)) => {
-                current_timestamp = Some(timestamp);
-                current_user = Some(user);
-            }
-            Err(error) => {
-                log::error!("Merge error: {:?}", error);
-            }
+    Ok((header.observations()?, user))
+}
+
+fn merge(key: &[u8], existing_val: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    merge_profiles(key, existing_val, operands, &mut |_| {})
+}
+
+/// Merge profile values, passing values that can't be decoded to the failure handler (after
+/// logging them).
+fn merge_profiles(
+    key: &[u8],
+    existing_val: Option<&[u8]>,
+    operands: &MergeOperands,
+    on_failure: &mut dyn FnMut(MergeFailure),
+) -> Option<Vec<u8>> {
+    let mut current: Option<(Header, User)> = None;
+
+    let mut add = |bytes: &[u8], source: Source| match value::decode(bytes) {
+        Ok((header, user)) => {
+            current = Some(match current.take() {
+                Some((mut current_header, current_user)) => {
+                    current_header.combine(header);
+
+                    if user.snapshot > current_user.snapshot {
+                        (current_header, user)
+                    } else {
+                        (current_header, current_user)
+                    }
+                }
+                None => (header, user),
+            });
+        }
+        Err(error) => {
+            log::error!("Merge error: {:?}", error);
+            on_failure(MergeFailure::new(
+                key,
+                source,
+                bytes,
+                format!("{:?}", error),
+            ));
         }
+    };
+
+    if let Some(bytes) = existing_val {
+        add(bytes, Source::ExistingValue);
     }
 
     for bytes in operands.into_iter() {
-        match parse_value(bytes) {
-            Ok((timestamp, user)) => {
-                match current_timestamp {
-                    Some(previous_timestamp) if timestamp < previous_timestamp => {
-                        current_timestamp = Some(timestamp);
-                    }
-                    None => {
-                        current_timestamp = Some(timestamp);
-                    }
-                    _ => (),
-                }
-                match current_user {
-                    Some(previous_user) if user.snapshot > previous_user.snapshot => {
-                        current_user = Some(user);
-                    }
-                    None => {
-                        current_user = Some(user);
-                    }
-                    _ => (),
-                }
-            }
+        add(bytes, Source::Operand);
+    }
+
+    match current {
+        Some((header, user)) => match value::encode_user(&header, &user) {
+            Ok(value) => Some(value),
             Err(error) => {
                 log::error!("Merge error: {:?}", error);
+                existing_val.map(|bytes| bytes.to_vec())
             }
+        },
+        None => {
+            log::error!("Unexpected merge values");
+            existing_val.map(|bytes| bytes.to_vec())
         }
     }
+}
 
-    match (current_timestamp, current_user) {
-        (Some(timestamp), Some(user)) => {
-            match to_value(user).and_then(|avro_value| to_avro_datum(&USER_SCHEMA, avro_value)) {
-                Ok(bytes) => {
-                    let mut value = Vec::with_capacity(bytes.len() + 8);
-                    value.extend_from_slice(&timestamp.timestamp().to_be_bytes());
-                    value.extend_from_slice(&bytes);
-                    Some(value)
-                }
-                Err(error) => {
-                    log::error!("Merge error: {:?}", error);
-                    existing_val.map(|bytes| bytes.to_vec())
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, user};
+
+    fn snapshots(profiles: &[(DateTime<Utc>, User)]) -> Vec<i64> {
+        profiles.iter().map(|(_, user)| user.snapshot).collect()
+    }
+
+    /// Create a database with the given entries in each key format (as if a key migration had
+    /// been interrupted), and open it.
+    fn open_with_entries(path: &Path, entries: &[(KeyFormat, User)]) -> ProfileDb {
+        let _ = std::fs::remove_dir_all(path);
+
+        {
+            let db = ProfileDb::open(path, false).unwrap();
+
+            for (format, user) in entries {
+                db.db
+                    .put(
+                        format.key(user.id(), &user.screen_name),
+                        value::encode_user(&Header::new(user.snapshot), user).unwrap(),
+                    )
+                    .unwrap();
             }
         }
-        _ => {
-            log::error!("Unexpected merge values");
-            existing_val.map(|bytes| bytes.to_vec())
+
+        ProfileDb::open(path, false).unwrap()
+    }
+
+    #[test]
+    fn check_and_repair() {
+        let path = temp_dir("check");
+
+        let open = |mode| {
+            let db = open_with_entries(&path, &[(KeyFormat::V1, user(1, "alice", 100))]);
+            let key_format = db.key_format();
+            let put = |key: Vec<u8>, first_seen: i64, user: &User| {
+                let header = Header {
+                    first_seen,
+                    ..Header::new(user.snapshot)
+                };
+                db.db
+                    .put(key, value::encode_user(&header, user).unwrap())
+                    .unwrap();
+            };
+
+            put(key_format.key(2, "bob"), 200, &user(2, "robert", 200));
+            put(key_format.key(3, "carol"), 400, &user(3, "carol", 300));
+            db.db.put(key_format.key(4, "dave"), [0xFF]).unwrap();
+
+            let report = db.check(mode).unwrap();
+            let actions = report
+                .issues
+                .iter()
+                .map(|issue| (issue.key_user_id(), issue.action))
+                .collect::<Vec<_>>();
+            assert_eq!(report.entry_count, 4);
+
+            (db, actions)
+        };
+
+        let (db, actions) = open(Mode::Report);
+        assert_eq!(
+            actions,
+            vec![
+                (Some(2), Action::None),
+                (Some(3), Action::None),
+                (Some(4), Action::None)
+            ]
+        );
+        assert_eq!(db.check(Mode::Report).unwrap().issues.len(), 3);
+        drop(db);
+
+        let (db, actions) = open(Mode::Quarantine);
+        assert_eq!(
+            actions,
+            vec![
+                (Some(2), Action::Quarantined),
+                (Some(3), Action::Quarantined),
+                (Some(4), Action::Quarantined)
+            ]
+        );
+        assert_eq!(db.iter().count(), 1);
+        drop(db);
+
+        let (db, actions) = open(Mode::Repair);
+        assert_eq!(
+            actions,
+            vec![
+                (Some(2), Action::Repaired),
+                (Some(3), Action::Repaired),
+                (Some(4), Action::Quarantined)
+            ]
+        );
+        assert_eq!(db.check(Mode::Report).unwrap().issues, vec![]);
+
+        let profiles = db.lookup(2).unwrap();
+        assert_eq!(profiles[0].1.screen_name, "robert");
+        let profiles = db.lookup(3).unwrap();
+        assert_eq!(profiles[0].0.timestamp(), 300);
+        assert_eq!(db.lookup(4).unwrap(), vec![]);
+        assert_eq!(
+            db.db
+                .iterator_cf(db.quarantine_cf().unwrap(), IteratorMode::Start)
+                .count(),
+            1
+        );
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn strict_merge() {
+        let path = temp_dir("strict-merge");
+        let settings = ProfileDbOptions {
+            strict_merge: true,
+            ..ProfileDbOptions::default()
+        };
+
+        {
+            let db = ProfileDb::open_with_options(&path, &settings).unwrap();
+            db.update(&user(1, "alice", 100)).unwrap();
+            db.db
+                .merge(db.key_format().key(1, "alice"), [0xFF, 0])
+                .unwrap();
+
+            // Reading the entry runs the merge operator, which drops the invalid operand.
+            assert_eq!(snapshots(&db.lookup(1).unwrap()), vec![100]);
+        }
+
+        // The failure was written when the database was dropped.
+        let db = ProfileDb::open_read_only(&path, &settings).unwrap();
+        let failures = db.merge_failures().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].user_id(), Some(1));
+        assert_eq!(failures[0].source, Source::Operand);
+        assert_eq!(failures[0].bytes, vec![0xFF, 0]);
+        assert_eq!(db.persist_merge_failures().unwrap(), 0);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn purge_merge_failures() {
+        let path = temp_dir("purge-merge-failures");
+        let db = ProfileDb::open_with_options(
+            &path,
+            &ProfileDbOptions {
+                strict_merge: true,
+                ..ProfileDbOptions::default()
+            },
+        )
+        .unwrap();
+        let merge_invalid = |user_id: u64, screen_name: &str, bytes: &[u8]| {
+            db.update(&user(user_id as i64, screen_name, 100)).unwrap();
+            db.db
+                .merge(db.key_format().key(user_id, screen_name), bytes)
+                .unwrap();
+            db.lookup(user_id).unwrap();
+        };
+
+        merge_invalid(1, "alice", &[0xFF, 1]);
+        assert_eq!(db.persist_merge_failures().unwrap(), 1);
+
+        // These failures are still pending when the purge starts.
+        merge_invalid(1, "alice2", &[0xFF, 2]);
+        merge_invalid(2, "bob", &[0xFF, 3]);
+
+        let summary = db.purge(1).unwrap();
+        assert_eq!(summary.profile_entries, 2);
+
+        let failures = db.merge_failures().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].user_id(), Some(2));
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn health_statistics() {
+        let path = temp_dir("health");
+
+        for enable_statistics in [false, true] {
+            let db = ProfileDb::open(path.join(enable_statistics.to_string()), enable_statistics)
+                .unwrap();
+            db.update(&user(1, "alice", 100)).unwrap();
+            db.lookup(1).unwrap();
+
+            let health = db.health().unwrap();
+            assert_eq!(health.block_cache_hits.is_some(), enable_statistics);
+            assert_eq!(health.block_cache_misses.is_some(), enable_statistics);
+            assert_eq!(health.warnings(), vec![]);
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn merge_skips_invalid_entries() {
+        let path = temp_dir("merge-invalid");
+        let other = ProfileDb::open(path.join("other"), false).unwrap();
+        other.update(&user(1, "alice", 100)).unwrap();
+        other.update(&user(3, "carol", 300)).unwrap();
+        other
+            .db
+            .put(other.key_format().key(2, "bob"), b"invalid")
+            .unwrap();
+        other.db.put([0, 0, 0], b"").unwrap();
+
+        let db = ProfileDb::open(path.join("db"), false).unwrap();
+        db.update(&user(3, "carol", 200)).unwrap();
+
+        assert_eq!(
+            db.merge_from(&other).unwrap(),
+            MergeSummary {
+                entry_count: 2,
+                new_users: 1,
+                new_screen_names: 1,
+                updated_profiles: 1,
+                skipped_entries: 2,
+            }
+        );
+        assert_eq!(snapshots(&db.lookup(1).unwrap()), vec![100]);
+        assert_eq!(db.lookup(2).unwrap(), vec![]);
+        assert_eq!(snapshots(&db.lookup(3).unwrap()), vec![300]);
+
+        drop((db, other));
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn mixed_keys() {
+        let path = temp_dir("mixed");
+        let mut db = open_with_entries(
+            &path,
+            &[
+                (KeyFormat::Legacy, user(1, "alice", 100)),
+                (KeyFormat::Legacy, user(2, "bob", 200)),
+                (KeyFormat::V1, user(2, "Bob", 300)),
+                (KeyFormat::V1, user(3, "carol", 400)),
+                (KeyFormat::Legacy, user(4, "ÀB", 500)),
+                (KeyFormat::Legacy, user(5, "eve", 600)),
+                (KeyFormat::V1, user(5, "eve2", 700)),
+            ],
+        );
+
+        assert_eq!(db.key_format(), KeyFormat::Legacy);
+        assert!(db.has_mixed_keys());
+
+        // Profiles in both formats are read, in user ID order.
+        assert_eq!(snapshots(&db.lookup(2).unwrap()), vec![200, 300]);
+        assert_eq!(
+            db.lookup_many([5, 3, 2], false)
+                .unwrap()
+                .iter()
+                .map(|(user_id, profiles)| (*user_id, snapshots(profiles)))
+                .collect::<BTreeMap<_, _>>(),
+            BTreeMap::from([(2, vec![200, 300]), (3, vec![400]), (5, vec![600, 700])])
+        );
+        assert_eq!(
+            db.iter()
+                .map(|profiles| snapshots(&profiles.unwrap()))
+                .collect::<Vec<_>>(),
+            vec![
+                vec![100],
+                vec![200, 300],
+                vec![400],
+                vec![500],
+                vec![600, 700]
+            ]
+        );
+        assert_eq!(
+            db.snapshot()
+                .iter_range(2, 5)
+                .map(|profiles| snapshots(&profiles.unwrap()))
+                .collect::<Vec<_>>(),
+            vec![vec![200, 300], vec![400], vec![500]]
+        );
+        assert_eq!(db.shard_ranges(2).unwrap(), vec![(1, 3), (3, 6)]);
+        assert_eq!(
+            db.par_scan(2, |profiles| Ok(profiles.count())).unwrap(),
+            vec![2, 3]
+        );
+
+        // Neither format is a problem, and repairing doesn't move any entries.
+        let report = db.check(Mode::Repair).unwrap();
+        assert_eq!(report.entry_count, 7);
+        assert_eq!(report.issues, vec![]);
+
+        // Both formats are purged.
+        let summary = db.purge(5).unwrap();
+        assert_eq!(summary.profile_entries, 2);
+        assert_eq!(db.lookup(5).unwrap(), vec![]);
+
+        // Entries whose screen names only differ by case are combined.
+        assert_eq!(db.migrate_keys().unwrap(), 3);
+        assert_eq!(db.key_format(), KeyFormat::V1);
+        assert!(!db.has_mixed_keys());
+
+        let profiles = db.lookup(2).unwrap();
+        assert_eq!(snapshots(&profiles), vec![300]);
+        assert_eq!(profiles[0].0.timestamp(), 200);
+        assert_eq!(snapshots(&db.lookup(4).unwrap()), vec![500]);
+
+        for result in db.db.iterator(IteratorMode::Start) {
+            let (key, _) = result.unwrap();
+            assert_eq!(KeyFormat::of(&key), Some(KeyFormat::V1));
         }
+
+        drop(db);
+
+        let db = ProfileDb::open(&path, false).unwrap();
+        assert_eq!(db.key_format(), KeyFormat::V1);
+        assert!(!db.has_mixed_keys());
+        assert_eq!(db.iter().count(), 4);
+        assert_eq!(db.check(Mode::Report).unwrap().issues, vec![]);
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
     }
 }
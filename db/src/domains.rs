@@ -0,0 +1,111 @@
+//! Encoding for the index from linked domains to users.
+//!
+//! Keys are the registrable domain, a zero byte, and the user ID (big-endian), so all users for
+//! a domain are contiguous and ordered by ID. Values are the first and last snapshot timestamps
+//! at which the user's profile linked to the domain.
+
+use chrono::{DateTime, TimeZone, Utc};
+use rocksdb::MergeOperands;
+use std::collections::BTreeSet;
+use twprs::model::User;
+
+pub(crate) const DOMAINS_CF_NAME: &str = "domains";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DomainEntry {
+    pub user_id: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The distinct registrable domains linked in the profile's `url` and `description` entities.
+pub fn user_domains(user: &User) -> BTreeSet<String> {
+    user.entities
+        .iter()
+        .flat_map(|entities| entities.url.iter().chain(entities.description.iter()))
+        .flat_map(|entity| &entity.urls)
+        .filter_map(|url| url.expanded_url.as_deref())
+        .filter_map(twprs::url::normalize)
+        .map(|url| url.domain)
+        .collect()
+}
+
+pub(crate) fn prefix(domain: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(domain.len() + 1);
+    prefix.extend_from_slice(domain.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+pub(crate) fn key(domain: &str, user_id: u64) -> Vec<u8> {
+    let mut key = prefix(domain);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key
+}
+
+pub(crate) fn parse_key(key: &[u8]) -> Option<(&str, u64)> {
+    let separator = key.len().checked_sub(9)?;
+
+    if key[separator] == 0 {
+        let domain = std::str::from_utf8(&key[0..separator]).ok()?;
+        let user_id = u64::from_be_bytes(key[separator + 1..].try_into().ok()?);
+
+        Some((domain, user_id))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn value(first_seen: i64, last_seen: i64) -> [u8; 16] {
+    let mut value = [0; 16];
+    value[0..8].copy_from_slice(&first_seen.to_be_bytes());
+    value[8..16].copy_from_slice(&last_seen.to_be_bytes());
+    value
+}
+
+fn parse_raw_value(value: &[u8]) -> Option<(i64, i64)> {
+    let first_seen = i64::from_be_bytes(value.get(0..8)?.try_into().ok()?);
+    let last_seen = i64::from_be_bytes(value.get(8..16)?.try_into().ok()?);
+
+    Some((first_seen, last_seen))
+}
+
+pub(crate) fn parse_entry(key: &[u8], value: &[u8]) -> Option<DomainEntry> {
+    let (_, user_id) = parse_key(key)?;
+    let (first_seen, last_seen) = parse_raw_value(value)?;
+
+    Some(DomainEntry {
+        user_id,
+        first_seen: Utc.timestamp_opt(first_seen, 0).single()?,
+        last_seen: Utc.timestamp_opt(last_seen, 0).single()?,
+    })
+}
+
+pub(crate) fn merge(
+    _key: &[u8],
+    existing_val: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut current = existing_val.and_then(parse_raw_value);
+
+    for bytes in operands.into_iter() {
+        match parse_raw_value(bytes) {
+            Some((first_seen, last_seen)) => {
+                current = Some(match current {
+                    Some((current_first, current_last)) => {
+                        (current_first.min(first_seen), current_last.max(last_seen))
+                    }
+                    None => (first_seen, last_seen),
+                });
+            }
+            None => {
+                log::error!("Domain index merge error: invalid value {:?}", bytes);
+            }
+        }
+    }
+
+    match current {
+        Some((first_seen, last_seen)) => Some(value(first_seen, last_seen).to_vec()),
+        None => existing_val.map(|bytes| bytes.to_vec()),
+    }
+}
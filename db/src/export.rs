@@ -0,0 +1,503 @@
+//! Export of a profile database to Avro files with the `user.avsc` schema.
+//!
+//! Profiles can be written in snapshot order (by snapshot and then user ID, the order checked by
+//! `twprs::avro::validate`) or in user ID order (with each user's profiles ordered by snapshot).
+//!
+//! Partitioned exports write one `YYYY-MM-DD.avro` file per snapshot date to the output
+//! directory, with each file in the requested order. Snapshot-ordered and partitioned exports
+//! use an external sort (see `twprs::sort`), which spills sorted runs of profiles to a temporary
+//! directory next to the output, so memory use and the number of open files are bounded however
+//! many profiles and dates there are.
+
+use super::db::ProfileDb;
+use chrono::{NaiveDate, TimeZone, Utc};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use twprs::{model::User, sort::Sorter};
+
+type Writer = apache_avro::Writer<'static, BufWriter<File>>;
+
+const TEMPORARY_DIRECTORY_SUFFIX: &str = ".tmp";
+/// The number of profiles that are sorted in memory before being written to a temporary file.
+const SORT_RUN_SIZE: usize = 100_000;
+const SECONDS_PER_DAY: i64 = 86_400;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Avro error")]
+    Avro(#[from] apache_avro::Error),
+    #[error("Profile Avro error")]
+    ProfileAvro(#[from] twprs::avro::Error),
+    #[error("ProfileDb error")]
+    ProfileDb(#[from] super::db::Error),
+    #[error("Sort error")]
+    Sort(#[from] twprs::sort::Error),
+    #[error("Output path already exists")]
+    AlreadyExists(PathBuf),
+    #[error("Invalid snapshot timestamp")]
+    InvalidSnapshot(i64),
+    #[error("Unknown order: {0}")]
+    UnknownOrder(String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    #[default]
+    Snapshot,
+    UserId,
+}
+
+impl FromStr for Order {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(Order::Snapshot),
+            "id" => Ok(Order::UserId),
+            other => Err(Error::UnknownOrder(other.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub order: Order,
+    pub partition_by_date: bool,
+    /// Only export profiles with snapshots at or after this timestamp (epoch seconds).
+    pub first_snapshot: Option<i64>,
+    /// Only export profiles with snapshots at or before this timestamp (epoch seconds).
+    pub last_snapshot: Option<i64>,
+    /// Only export profiles for these users.
+    pub user_ids: Option<Vec<u64>>,
+}
+
+impl ExportOptions {
+    fn includes(&self, user: &User) -> bool {
+        self.first_snapshot
+            .filter(|first| user.snapshot < *first)
+            .is_none()
+            && self
+                .last_snapshot
+                .filter(|last| user.snapshot > *last)
+                .is_none()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub user_count: usize,
+    pub profile_count: usize,
+    pub file_count: usize,
+}
+
+/// Export the database to a file (or a directory if partitioning by date).
+///
+/// The output path must not already exist. All profiles are read from a single database
+/// snapshot. No file is written if no profiles are selected.
+pub fn export<P: AsRef<Path>>(
+    db: &ProfileDb,
+    output: P,
+    options: &ExportOptions,
+) -> Result<Summary, Error> {
+    let output = output.as_ref();
+
+    if output.exists() {
+        return Err(Error::AlreadyExists(output.to_path_buf()));
+    }
+
+    let mut summary = Summary::default();
+
+    let file_count = match (options.order, options.partition_by_date) {
+        (Order::UserId, false) => {
+            let mut writer = twprs::avro::writer(BufWriter::new(File::create(output)?));
+            scan(db, options, &mut summary, |user| {
+                writer.append_ser(user)?;
+                Ok(())
+            })?;
+            writer.into_inner()?.flush()?;
+
+            1
+        }
+        (order, partition_by_date) => {
+            let mut temporary = output.as_os_str().to_os_string();
+            temporary.push(TEMPORARY_DIRECTORY_SUFFIX);
+            let temporary = PathBuf::from(temporary);
+
+            if temporary.exists() {
+                return Err(Error::AlreadyExists(temporary));
+            }
+
+            let key: fn(&User) -> (i64, i64) = match order {
+                Order::Snapshot => |user| (user.snapshot, user.id),
+                // The sort is stable, so sorting by day keeps the user ID order within each day.
+                Order::UserId => |user| (user.snapshot.div_euclid(SECONDS_PER_DAY), 0),
+            };
+            let mut sorter = Sorter::new(&temporary, SORT_RUN_SIZE, key)?;
+            scan(db, options, &mut summary, |user| {
+                snapshot_date(&user)?;
+                Ok(sorter.push(user)?)
+            })?;
+            let users = sorter.finish()?;
+
+            let file_count = if partition_by_date {
+                std::fs::create_dir_all(output)?;
+
+                let mut current: Option<(NaiveDate, Writer)> = None;
+                let mut file_count = 0;
+
+                for user in users {
+                    let user = user?;
+                    let date = snapshot_date(&user)?;
+
+                    if current
+                        .as_ref()
+                        .filter(|(current_date, _)| *current_date == date)
+                        .is_none()
+                    {
+                        if let Some((_, writer)) = current.take() {
+                            writer.into_inner()?.flush()?;
+                        }
+
+                        let path = output.join(format!("{}.avro", date.format("%Y-%m-%d")));
+                        current = Some((
+                            date,
+                            twprs::avro::writer(BufWriter::new(File::create(path)?)),
+                        ));
+                        file_count += 1;
+                    }
+
+                    if let Some((_, writer)) = current.as_mut() {
+                        writer.append_ser(user)?;
+                    }
+                }
+
+                if let Some((_, writer)) = current {
+                    writer.into_inner()?.flush()?;
+                }
+
+                file_count
+            } else {
+                let mut writer = twprs::avro::writer(BufWriter::new(File::create(output)?));
+
+                for user in users {
+                    writer.append_ser(user?)?;
+                }
+                writer.into_inner()?.flush()?;
+
+                1
+            };
+
+            std::fs::remove_dir_all(temporary)?;
+
+            file_count
+        }
+    };
+
+    // Avro files with no records would have no header, so we don't leave them behind.
+    if summary.profile_count == 0 && !options.partition_by_date {
+        std::fs::remove_file(output)?;
+        summary.file_count = 0;
+    } else {
+        summary.file_count = file_count;
+    }
+
+    Ok(summary)
+}
+
+/// Scan the selected profiles in user ID order, with each user's profiles ordered by snapshot.
+fn scan<F: FnMut(User) -> Result<(), Error>>(
+    db: &ProfileDb,
+    options: &ExportOptions,
+    summary: &mut Summary,
+    mut f: F,
+) -> Result<(), Error> {
+    let snapshot = db.snapshot();
+    let mut add_user = |profiles: Vec<User>| -> Result<(), Error> {
+        let mut included = false;
+
+        for user in profiles {
+            if options.includes(&user) {
+                f(user)?;
+                summary.profile_count += 1;
+                included = true;
+            }
+        }
+
+        if included {
+            summary.user_count += 1;
+        }
+
+        Ok(())
+    };
+
+    match &options.user_ids {
+        Some(user_ids) => {
+            let mut results = snapshot
+                .lookup_many(user_ids.iter().copied(), false)?
+                .into_iter()
+                .collect::<Vec<_>>();
+            results.sort_unstable_by_key(|(user_id, _)| *user_id);
+
+            for (_, profiles) in results {
+                add_user(profiles.into_iter().map(|(_, user)| user).collect())?;
+            }
+        }
+        None => {
+            for result in snapshot.iter() {
+                add_user(result?.into_iter().map(|(_, user)| user).collect())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn snapshot_date(user: &User) -> Result<NaiveDate, Error> {
+    Ok(Utc
+        .timestamp_opt(user.snapshot, 0)
+        .single()
+        .ok_or(Error::InvalidSnapshot(user.snapshot))?
+        .date_naive())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, user};
+
+    const DAY: i64 = SECONDS_PER_DAY;
+
+    /// A database with profiles on three days, with the later users having earlier snapshots (each
+    /// profile has its own screen name, since the database stores one profile per screen name).
+    fn open_db(path: &Path) -> ProfileDb {
+        let db = ProfileDb::open(path.join("db"), false).unwrap();
+
+        for (id, snapshot) in [
+            (1, 2 * DAY + 10),
+            (1, 2 * DAY + 20),
+            (2, DAY + 30),
+            (2, 2 * DAY + 5),
+            (3, 10),
+            (3, DAY + 20),
+            (4, 5),
+        ] {
+            db.update(&user(id, &format!("user{}_{}", id, snapshot), snapshot))
+                .unwrap();
+        }
+
+        db
+    }
+
+    fn read(path: &Path) -> Vec<(i64, i64)> {
+        twprs::avro::reader(File::open(path).unwrap())
+            .unwrap()
+            .map(|value| {
+                let user = apache_avro::from_value::<User>(&value.unwrap()).unwrap();
+                (user.id, user.snapshot)
+            })
+            .collect()
+    }
+
+    fn read_partitioned(path: &Path) -> Vec<(String, Vec<(i64, i64)>)> {
+        let mut paths = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        paths
+            .iter()
+            .map(|path| {
+                (
+                    path.file_name().unwrap().to_string_lossy().to_string(),
+                    read(path),
+                )
+            })
+            .collect()
+    }
+
+    fn export_with(path: &Path, options: ExportOptions) -> Summary {
+        let db = open_db(path);
+        let summary = export(&db, path.join("output"), &options).unwrap();
+
+        // The temporary directory is always removed.
+        assert!(!path.join("output.tmp").exists());
+
+        summary
+    }
+
+    #[test]
+    fn export_snapshot_order() {
+        let path = temp_dir("export-snapshot");
+        let summary = export_with(&path, ExportOptions::default());
+
+        assert_eq!(
+            summary,
+            Summary {
+                user_count: 4,
+                profile_count: 7,
+                file_count: 1
+            }
+        );
+        assert_eq!(
+            read(&path.join("output")),
+            vec![
+                (4, 5),
+                (3, 10),
+                (3, DAY + 20),
+                (2, DAY + 30),
+                (2, 2 * DAY + 5),
+                (1, 2 * DAY + 10),
+                (1, 2 * DAY + 20)
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn export_user_id_order() {
+        let path = temp_dir("export-id");
+        let summary = export_with(
+            &path,
+            ExportOptions {
+                order: Order::UserId,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(summary.file_count, 1);
+        assert_eq!(
+            read(&path.join("output")),
+            vec![
+                (1, 2 * DAY + 10),
+                (1, 2 * DAY + 20),
+                (2, DAY + 30),
+                (2, 2 * DAY + 5),
+                (3, 10),
+                (3, DAY + 20),
+                (4, 5)
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn export_partitioned() {
+        for (order, expected) in [
+            (
+                Order::Snapshot,
+                vec![
+                    vec![(4, 5), (3, 10)],
+                    vec![(3, DAY + 20), (2, DAY + 30)],
+                    vec![(2, 2 * DAY + 5), (1, 2 * DAY + 10), (1, 2 * DAY + 20)],
+                ],
+            ),
+            (
+                Order::UserId,
+                vec![
+                    vec![(3, 10), (4, 5)],
+                    vec![(2, DAY + 30), (3, DAY + 20)],
+                    vec![(1, 2 * DAY + 10), (1, 2 * DAY + 20), (2, 2 * DAY + 5)],
+                ],
+            ),
+        ] {
+            let path = temp_dir("export-partitioned");
+            let summary = export_with(
+                &path,
+                ExportOptions {
+                    order,
+                    partition_by_date: true,
+                    ..Default::default()
+                },
+            );
+
+            assert_eq!(summary.file_count, 3);
+            assert_eq!(
+                read_partitioned(&path.join("output")),
+                vec![
+                    ("1970-01-01.avro".to_string(), expected[0].clone()),
+                    ("1970-01-02.avro".to_string(), expected[1].clone()),
+                    ("1970-01-03.avro".to_string(), expected[2].clone()),
+                ]
+            );
+
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn export_selection() {
+        let path = temp_dir("export-selection");
+        let summary = export_with(
+            &path,
+            ExportOptions {
+                first_snapshot: Some(10),
+                last_snapshot: Some(2 * DAY + 10),
+                user_ids: Some(vec![4, 3, 1, 5]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            summary,
+            Summary {
+                user_count: 2,
+                profile_count: 3,
+                file_count: 1
+            }
+        );
+        assert_eq!(
+            read(&path.join("output")),
+            vec![(3, 10), (3, DAY + 20), (1, 2 * DAY + 10)]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn export_nothing() {
+        let path = temp_dir("export-nothing");
+        let db = open_db(&path);
+        let options = ExportOptions {
+            first_snapshot: Some(3 * DAY),
+            ..Default::default()
+        };
+
+        for order in [Order::Snapshot, Order::UserId] {
+            let options = ExportOptions {
+                order,
+                ..options.clone()
+            };
+
+            assert_eq!(
+                export(&db, path.join("output"), &options).unwrap(),
+                Summary::default()
+            );
+            assert!(!path.join("output").exists());
+        }
+
+        let options = ExportOptions {
+            partition_by_date: true,
+            ..options
+        };
+        assert_eq!(
+            export(&db, path.join("output"), &options).unwrap(),
+            Summary::default()
+        );
+        assert_eq!(read_partitioned(&path.join("output")), vec![]);
+
+        assert!(matches!(
+            export(&db, path.join("output"), &options),
+            Err(Error::AlreadyExists(_))
+        ));
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Detection of sudden changes in follower counts.
+//!
+//! Each interval between consecutive counter samples for a user is compared to the user's own
+//! baseline: the mean and standard deviation of the daily follower change rate over all of the
+//! user's other intervals. An interval is flagged if the absolute change is at least
+//! `min_change` and either its rate has a z-score of at least `z_threshold` against the baseline,
+//! or (for users with fewer than `min_baseline` other intervals) the change is at least
+//! `min_relative_change` times the follower count at the start of the interval.
+
+use super::counters::CounterSample;
+use chrono::{DateTime, Utc};
+
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrowthOptions {
+    pub z_threshold: f64,
+    pub min_change: i64,
+    /// The number of other intervals required to use the z-score.
+    pub min_baseline: usize,
+    pub min_relative_change: f64,
+}
+
+impl Default for GrowthOptions {
+    fn default() -> Self {
+        Self {
+            z_threshold: 4.0,
+            min_change: 1000,
+            min_baseline: 5,
+            min_relative_change: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrowthAnomaly {
+    pub user_id: u64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub before: i64,
+    pub after: i64,
+    /// The z-score of the daily change rate, if the user has enough intervals for a baseline.
+    pub z_score: Option<f64>,
+}
+
+impl GrowthAnomaly {
+    pub fn change(&self) -> i64 {
+        self.after - self.before
+    }
+
+    /// The change relative to the follower count at the start of the window.
+    pub fn relative_change(&self) -> f64 {
+        self.change() as f64 / self.before.max(1) as f64
+    }
+}
+
+/// Find the anomalous intervals in a user's counter samples (which must be ordered by snapshot).
+pub fn anomalies(
+    user_id: u64,
+    samples: &[CounterSample],
+    options: &GrowthOptions,
+) -> Vec<GrowthAnomaly> {
+    let rates = samples
+        .windows(2)
+        .map(|window| {
+            let days =
+                (window[1].snapshot - window[0].snapshot).num_seconds() as f64 / SECONDS_PER_DAY;
+            let change = (window[1].followers_count - window[0].followers_count) as f64;
+
+            // Treat intervals shorter than a day as a day so that close snapshots don't dominate.
+            change / days.max(1.0)
+        })
+        .collect::<Vec<_>>();
+
+    let sum = rates.iter().sum::<f64>();
+    let sum_squares = rates.iter().map(|rate| rate * rate).sum::<f64>();
+
+    samples
+        .windows(2)
+        .zip(&rates)
+        .filter_map(|(window, rate)| {
+            let anomaly = GrowthAnomaly {
+                user_id,
+                start: window[0].snapshot,
+                end: window[1].snapshot,
+                before: window[0].followers_count,
+                after: window[1].followers_count,
+                z_score: None,
+            };
+
+            if anomaly.change().abs() < options.min_change {
+                return None;
+            }
+
+            let baseline_count = rates.len() - 1;
+
+            if baseline_count >= options.min_baseline.max(1) {
+                // The baseline excludes the interval being tested.
+                let count = baseline_count as f64;
+                let mean = (sum - rate) / count;
+                let variance = ((sum_squares - rate * rate) / count - mean * mean).max(0.0);
+                // A floor of one follower per day avoids flagging tiny deviations from a flat
+                // baseline.
+                let z_score = (rate - mean) / variance.sqrt().max(1.0);
+
+                (z_score.abs() >= options.z_threshold).then_some(GrowthAnomaly {
+                    z_score: Some(z_score),
+                    ..anomaly
+                })
+            } else {
+                (anomaly.relative_change().abs() >= options.min_relative_change).then_some(anomaly)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// Samples with the given snapshot offsets (in seconds) and follower counts.
+    fn samples(counts: &[(i64, i64)]) -> Vec<CounterSample> {
+        counts
+            .iter()
+            .map(|(offset, followers_count)| CounterSample {
+                snapshot: Utc.timestamp_opt(1_600_000_000 + offset, 0).unwrap(),
+                followers_count: *followers_count,
+                friends_count: 0,
+                statuses_count: 0,
+                listed_count: 0,
+            })
+            .collect()
+    }
+
+    /// Daily samples starting from zero followers with the given changes.
+    fn daily(changes: &[i64]) -> Vec<CounterSample> {
+        let mut counts = vec![(0, 0)];
+
+        for (i, change) in changes.iter().enumerate() {
+            counts.push(((i as i64 + 1) * DAY, counts[i].1 + change));
+        }
+
+        samples(&counts)
+    }
+
+    fn z_scores(anomalies: &[GrowthAnomaly]) -> Vec<Option<f64>> {
+        anomalies.iter().map(|anomaly| anomaly.z_score).collect()
+    }
+
+    #[test]
+    fn spike_from_flat_baseline() {
+        let flagged = anomalies(
+            1,
+            &daily(&[10, 10, 10, 10, 10, 10, 10, 5000]),
+            &GrowthOptions::default(),
+        );
+
+        // The baseline has no variance, so the standard deviation is floored at one.
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].user_id, 1);
+        assert_eq!((flagged[0].before, flagged[0].after), (70, 5070));
+        assert_eq!(flagged[0].change(), 5000);
+        assert_eq!(flagged[0].z_score, Some(4990.0));
+    }
+
+    #[test]
+    fn z_score_against_other_intervals() {
+        let flagged = anomalies(
+            1,
+            &daily(&[1000, 3000, 1000, 3000, 1000, 3000, 20000]),
+            &GrowthOptions::default(),
+        );
+
+        // The other intervals have a mean of 2000 and a (population) standard deviation of 1000,
+        // and the spike itself isn't part of its baseline.
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].change(), 20000);
+        assert!((flagged[0].z_score.unwrap() - 18.0).abs() < 1e-9);
+
+        // The earlier intervals are compared to baselines that include the spike, so only the
+        // smaller increases are flagged with a lower threshold.
+        let options = GrowthOptions {
+            z_threshold: 0.5,
+            ..GrowthOptions::default()
+        };
+        let flagged = anomalies(
+            1,
+            &daily(&[1000, 3000, 1000, 3000, 1000, 3000, 20000]),
+            &options,
+        );
+        assert_eq!(flagged.len(), 4);
+        assert!(z_scores(&flagged[0..3])
+            .iter()
+            .all(|z_score| (-1.0..-0.5).contains(&z_score.unwrap())));
+    }
+
+    #[test]
+    fn rates_and_drops() {
+        // The drop is over two days, so its rate is -4000 per day, and the short interval is
+        // treated as a full day.
+        let samples = samples(&[
+            (0, 10000),
+            (DAY, 10100),
+            (2 * DAY, 10200),
+            (3 * DAY, 10300),
+            (4 * DAY, 10400),
+            (5 * DAY, 10500),
+            (7 * DAY, 2500),
+            (7 * DAY + 3600, 2600),
+        ]);
+        let options = GrowthOptions {
+            min_change: 100,
+            z_threshold: 2.0,
+            ..GrowthOptions::default()
+        };
+        let flagged = anomalies(1, &samples, &options);
+
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].change(), -8000);
+        assert_eq!(flagged[0].end - flagged[0].start, chrono::Duration::days(2));
+
+        // All of the other rates are 100 per day (including the one-hour interval).
+        assert_eq!(flagged[0].z_score, Some(-4100.0));
+    }
+
+    #[test]
+    fn relative_change_without_baseline() {
+        let options = GrowthOptions::default();
+
+        // Starting from zero followers counts as starting from one.
+        let flagged = anomalies(1, &daily(&[1000, 1000]), &options);
+        assert_eq!(z_scores(&flagged), vec![None, None]);
+        assert_eq!(flagged[0].relative_change(), 1000.0);
+        assert_eq!(flagged[1].relative_change(), 1.0);
+
+        assert_eq!(
+            anomalies(1, &samples(&[(0, 10000), (DAY, 11000)]), &options),
+            vec![]
+        );
+        assert_eq!(anomalies(1, &samples(&[(0, 0)]), &options), vec![]);
+        assert_eq!(anomalies(1, &[], &options), vec![]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Typed storage metrics for the profile column family.
+//!
+//! Most values come from RocksDB properties, table file sizes come from the list of live files,
+//! and the block cache hit rate comes from the statistics dump (so it's only available when the
+//! database was opened with statistics enabled).
+
+/// RocksDB's default `level0_slowdown_writes_trigger`.
+const L0_FILE_WARNING_COUNT: usize = 20;
+/// RocksDB's default `soft_pending_compaction_bytes_limit`.
+const PENDING_COMPACTION_WARNING_BYTES: u64 = 64 << 30;
+const BLOCK_CACHE_HIT_RATE_WARNING: f64 = 0.5;
+/// The number of block cache lookups needed before the hit rate is considered meaningful.
+const BLOCK_CACHE_MIN_LOOKUPS: u64 = 10_000;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Health {
+    /// Total table file size in bytes for each level (starting with level 0).
+    pub level_sizes: Vec<u64>,
+    /// Number of table files for each level (starting with level 0).
+    pub level_file_counts: Vec<usize>,
+    pub pending_compaction_bytes: u64,
+    pub running_compactions: u64,
+    pub memtable_bytes: u64,
+    pub estimated_key_count: u64,
+    pub block_cache_hits: Option<u64>,
+    pub block_cache_misses: Option<u64>,
+    /// The current rate in bytes per second if writes are being delayed.
+    pub delayed_write_rate: Option<u64>,
+    pub write_stopped: bool,
+}
+
+impl Health {
+    pub fn total_sst_bytes(&self) -> u64 {
+        self.level_sizes.iter().sum()
+    }
+
+    pub fn block_cache_hit_rate(&self) -> Option<f64> {
+        let hits = self.block_cache_hits?;
+        let lookups = hits + self.block_cache_misses?;
+
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = vec![];
+
+        if self.write_stopped {
+            warnings.push(Warning::WriteStopped);
+        }
+
+        if let Some(rate) = self.delayed_write_rate {
+            warnings.push(Warning::WriteDelayed(rate));
+        }
+
+        let l0_file_count = self.level_file_counts.first().copied().unwrap_or_default();
+
+        if l0_file_count >= L0_FILE_WARNING_COUNT {
+            warnings.push(Warning::TooManyL0Files(l0_file_count));
+        }
+
+        if self.pending_compaction_bytes >= PENDING_COMPACTION_WARNING_BYTES {
+            warnings.push(Warning::CompactionBacklog(self.pending_compaction_bytes));
+        }
+
+        if let (Some(hits), Some(misses)) = (self.block_cache_hits, self.block_cache_misses) {
+            let rate = self.block_cache_hit_rate().unwrap_or_default();
+
+            if hits + misses >= BLOCK_CACHE_MIN_LOOKUPS && rate < BLOCK_CACHE_HIT_RATE_WARNING {
+                warnings.push(Warning::LowBlockCacheHitRate(rate));
+            }
+        }
+
+        warnings
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warning {
+    WriteStopped,
+    WriteDelayed(u64),
+    TooManyL0Files(usize),
+    CompactionBacklog(u64),
+    LowBlockCacheHitRate(f64),
+}
+
+impl Warning {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::WriteStopped => "write_stopped",
+            Self::WriteDelayed(_) => "write_delayed",
+            Self::TooManyL0Files(_) => "too_many_l0_files",
+            Self::CompactionBacklog(_) => "compaction_backlog",
+            Self::LowBlockCacheHitRate(_) => "low_block_cache_hit_rate",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::WriteStopped => "Writes are stopped".to_string(),
+            Self::WriteDelayed(rate) => format!("Writes are delayed to {} bytes per second", rate),
+            Self::TooManyL0Files(count) => {
+                format!(
+                    "{} level 0 files (writes slow down at {})",
+                    count, L0_FILE_WARNING_COUNT
+                )
+            }
+            Self::CompactionBacklog(bytes) => {
+                format!("{} bytes pending compaction", bytes)
+            }
+            Self::LowBlockCacheHitRate(rate) => {
+                format!("Block cache hit rate is {:.3}", rate)
+            }
+        }
+    }
+}
+
+/// Read a ticker count (e.g. `rocksdb.block.cache.hit`) from a statistics dump.
+pub(crate) fn parse_ticker(statistics: &str, name: &str) -> Option<u64> {
+    statistics.lines().find_map(|line| {
+        let rest = line.strip_prefix(name)?.strip_prefix(" COUNT : ")?;
+
+        rest.trim().parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATISTICS: &str = "rocksdb.block.cache.miss COUNT : 1234
+rocksdb.block.cache.hit COUNT : 98765
+rocksdb.block.cache.add COUNT : 12
+rocksdb.block.cache.data.hit COUNT : 5
+rocksdb.db.get.micros P50 : 1.500000 P95 : 3.000000 P99 : 7.000000 COUNT : 42 SUM : 100
+rocksdb.invalid COUNT : many
+";
+
+    #[test]
+    fn parse_tickers() {
+        assert_eq!(
+            parse_ticker(STATISTICS, "rocksdb.block.cache.hit"),
+            Some(98765)
+        );
+        assert_eq!(
+            parse_ticker(STATISTICS, "rocksdb.block.cache.miss"),
+            Some(1234)
+        );
+        // Names must match exactly, and histograms aren't tickers.
+        assert_eq!(parse_ticker(STATISTICS, "rocksdb.block.cache"), None);
+        assert_eq!(parse_ticker(STATISTICS, "rocksdb.db.get.micros"), None);
+        assert_eq!(parse_ticker(STATISTICS, "rocksdb.invalid"), None);
+        assert_eq!(parse_ticker(STATISTICS, "rocksdb.missing"), None);
+        assert_eq!(parse_ticker("", "rocksdb.block.cache.hit"), None);
+    }
+
+    #[test]
+    fn healthy() {
+        let health = Health {
+            level_sizes: vec![100, 0, 2000],
+            level_file_counts: vec![19, 0, 3],
+            pending_compaction_bytes: PENDING_COMPACTION_WARNING_BYTES - 1,
+            block_cache_hits: Some(1),
+            block_cache_misses: Some(BLOCK_CACHE_MIN_LOOKUPS - 2),
+            ..Health::default()
+        };
+
+        assert_eq!(health.total_sst_bytes(), 2100);
+        assert_eq!(health.warnings(), vec![]);
+        assert_eq!(Health::default().warnings(), vec![]);
+        assert_eq!(Health::default().block_cache_hit_rate(), None);
+    }
+
+    #[test]
+    fn warnings() {
+        let health = Health {
+            level_file_counts: vec![L0_FILE_WARNING_COUNT, 1],
+            pending_compaction_bytes: PENDING_COMPACTION_WARNING_BYTES,
+            block_cache_hits: Some(4_000),
+            block_cache_misses: Some(6_000),
+            delayed_write_rate: Some(1024),
+            write_stopped: true,
+            ..Health::default()
+        };
+
+        assert_eq!(health.block_cache_hit_rate(), Some(0.4));
+        assert_eq!(
+            health.warnings(),
+            vec![
+                Warning::WriteStopped,
+                Warning::WriteDelayed(1024),
+                Warning::TooManyL0Files(L0_FILE_WARNING_COUNT),
+                Warning::CompactionBacklog(PENDING_COMPACTION_WARNING_BYTES),
+                Warning::LowBlockCacheHitRate(0.4),
+            ]
+        );
+        assert_eq!(
+            health
+                .warnings()
+                .iter()
+                .map(Warning::kind)
+                .collect::<Vec<_>>(),
+            vec![
+                "write_stopped",
+                "write_delayed",
+                "too_many_l0_files",
+                "compaction_backlog",
+                "low_block_cache_hit_rate"
+            ]
+        );
+        assert_eq!(
+            Warning::LowBlockCacheHitRate(0.4).message(),
+            "Block cache hit rate is 0.400"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Keys for the profile entries in the default column family.
+//!
+//! Current keys start with a key kind byte, followed by the user ID (big-endian), a zero byte,
+//! and the screen name with ASCII letters lowercased (Twitter screen names are case-insensitive
+//! and can only contain ASCII letters, digits, and underscores). Kind bytes are at least `0x80`,
+//! which means they can't be confused with the first byte of a legacy key (since user IDs are
+//! below 2^63), and kinds after `PROFILE_KIND` are reserved for other kinds of entries (e.g.
+//! secondary indices) that share the column family.
+//!
+//! Legacy keys are the user ID followed by the screen name lowercased with Rust's Unicode rules.
+//! Databases with legacy keys are still read and written with them until they are migrated with
+//! `ProfileDb::migrate_keys`.
+
+/// The kind byte for profile entry keys.
+pub(crate) const PROFILE_KIND: u8 = 0x80;
+const SEPARATOR: u8 = 0;
+
+const LEGACY_PREFIX_LEN: usize = 8;
+const PREFIX_LEN: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFormat {
+    Legacy,
+    V1,
+}
+
+impl KeyFormat {
+    /// The format of a key (based only on its first byte).
+    pub(crate) fn of(key: &[u8]) -> Option<Self> {
+        match key.first() {
+            Some(&PROFILE_KIND) => Some(Self::V1),
+            Some(byte) if *byte < PROFILE_KIND => Some(Self::Legacy),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::V1 => "v1",
+        }
+    }
+
+    /// The prefix of all keys for a user.
+    pub(crate) fn prefix(self, user_id: u64) -> Vec<u8> {
+        match self {
+            Self::Legacy => user_id.to_be_bytes().to_vec(),
+            Self::V1 => {
+                let mut prefix = Vec::with_capacity(PREFIX_LEN);
+                prefix.push(PROFILE_KIND);
+                prefix.extend_from_slice(&user_id.to_be_bytes());
+                prefix
+            }
+        }
+    }
+
+    pub(crate) fn key(self, user_id: u64, screen_name: &str) -> Vec<u8> {
+        self.key_from_bytes(user_id, self.normalize(screen_name).as_bytes())
+    }
+
+    /// The key for an already normalized screen name.
+    pub(crate) fn key_from_bytes(self, user_id: u64, screen_name: &[u8]) -> Vec<u8> {
+        let mut key = self.prefix(user_id);
+
+        if self == Self::V1 {
+            key.push(SEPARATOR);
+        }
+
+        key.extend_from_slice(screen_name);
+        key
+    }
+
+    pub(crate) fn normalize(self, screen_name: &str) -> String {
+        match self {
+            Self::Legacy => screen_name.to_lowercase(),
+            Self::V1 => screen_name.to_ascii_lowercase(),
+        }
+    }
+}
+
+/// Split a key in either format into its user ID and screen name bytes.
+pub(crate) fn parse(key: &[u8]) -> Option<(KeyFormat, u64, &[u8])> {
+    let format = KeyFormat::of(key)?;
+
+    let (user_id_bytes, screen_name) = match format {
+        KeyFormat::Legacy => (key.get(0..8)?, &key[8..]),
+        KeyFormat::V1 => {
+            if *key.get(PREFIX_LEN)? != SEPARATOR {
+                return None;
+            }
+
+            (&key[1..PREFIX_LEN], &key[PREFIX_LEN + 1..])
+        }
+    };
+
+    Some((
+        format,
+        u64::from_be_bytes(user_id_bytes.try_into().ok()?),
+        screen_name,
+    ))
+}
+
+/// Prefix extractor transform that returns the user prefix for keys in either format.
+pub(crate) fn prefix_transform(key: &[u8]) -> &[u8] {
+    match KeyFormat::of(key) {
+        Some(KeyFormat::V1) => &key[..PREFIX_LEN],
+        _ => &key[..LEGACY_PREFIX_LEN],
+    }
+}
+
+pub(crate) fn prefix_in_domain(key: &[u8]) -> bool {
+    match KeyFormat::of(key) {
+        Some(KeyFormat::V1) => key.len() >= PREFIX_LEN,
+        Some(KeyFormat::Legacy) => key.len() >= LEGACY_PREFIX_LEN,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_key_layout() {
+        let key = KeyFormat::V1.key(0x0102, "Jack_1");
+
+        assert_eq!(key[0], 0x80);
+        assert_eq!(&key[1..9], &0x0102u64.to_be_bytes());
+        assert_eq!(key[9], 0);
+        assert_eq!(&key[10..], b"jack_1");
+        assert_eq!(KeyFormat::V1.prefix(0x0102), key[..9].to_vec());
+    }
+
+    #[test]
+    fn legacy_key_layout() {
+        let key = KeyFormat::Legacy.key(0x0102, "Jack_1");
+
+        assert_eq!(&key[..8], &0x0102u64.to_be_bytes());
+        assert_eq!(&key[8..], b"jack_1");
+        assert_eq!(KeyFormat::Legacy.prefix(0x0102), key[..8].to_vec());
+    }
+
+    #[test]
+    fn normalize() {
+        // Unicode lowercasing changes non-ASCII letters (and their lengths), while the current
+        // format only lowercases ASCII letters.
+        assert_eq!(KeyFormat::Legacy.normalize("ÀBÇ_İ"), "àbç_i̇");
+        assert_eq!(KeyFormat::V1.normalize("ÀBÇ_İ"), "ÀbÇ_İ");
+        assert_eq!(KeyFormat::Legacy.normalize("Jack_1"), "jack_1");
+        assert_eq!(KeyFormat::V1.normalize("Jack_1"), "jack_1");
+    }
+
+    #[test]
+    fn key_formats() {
+        assert_eq!(
+            KeyFormat::of(&KeyFormat::V1.key(12, "jack")),
+            Some(KeyFormat::V1)
+        );
+        assert_eq!(
+            KeyFormat::of(&KeyFormat::Legacy.key(12, "jack")),
+            Some(KeyFormat::Legacy)
+        );
+        // The largest user ID with a legacy key can't be confused with a current key.
+        assert_eq!(
+            KeyFormat::of(&KeyFormat::Legacy.key(i64::MAX as u64, "jack")),
+            Some(KeyFormat::Legacy)
+        );
+        assert_eq!(KeyFormat::of(&[0x81, 0]), None);
+        assert_eq!(KeyFormat::of(&[]), None);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        for format in [KeyFormat::Legacy, KeyFormat::V1] {
+            for screen_name in ["jack", "Jack_1", "ÀBÇ_İ", ""] {
+                let key = format.key(1234567890123, screen_name);
+                let normalized = format.normalize(screen_name);
+
+                assert_eq!(
+                    parse(&key),
+                    Some((format, 1234567890123, normalized.as_bytes()))
+                );
+                assert_eq!(
+                    format.key_from_bytes(1234567890123, normalized.as_bytes()),
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse(&[0, 0, 0]), None);
+        assert_eq!(parse(&[0x80, 0, 0, 0, 0, 0, 0, 0, 12]), None);
+        assert_eq!(parse(&[0x80, 0, 0, 0, 0, 0, 0, 0, 12, 1]), None);
+        assert_eq!(parse(&[0x81, 0, 0, 0, 0, 0, 0, 0, 12, 0]), None);
+    }
+
+    #[test]
+    fn prefixes() {
+        let legacy = KeyFormat::Legacy.key(12, "jack");
+        let v1 = KeyFormat::V1.key(12, "jack");
+
+        assert_eq!(prefix_transform(&legacy), &legacy[..8]);
+        assert_eq!(prefix_transform(&v1), &v1[..9]);
+        assert!(prefix_in_domain(&legacy));
+        assert!(prefix_in_domain(&v1));
+        assert!(!prefix_in_domain(&v1[..8]));
+        assert!(!prefix_in_domain(&legacy[..7]));
+        assert!(!prefix_in_domain(&[0x81; 16]));
+    }
+}
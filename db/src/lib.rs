@@ -1,2 +1,17 @@
+pub mod async_db;
+pub mod backup;
+pub mod check;
+pub mod counters;
 pub mod db;
 pub mod deactivation;
+pub mod domains;
+pub mod export;
+pub mod growth;
+pub mod health;
+pub mod key;
+pub mod merge_errors;
+pub mod search;
+pub mod sqlite;
+#[cfg(test)]
+mod test_util;
+pub mod value;
@@ -0,0 +1,324 @@
+//! Persistent records of profile values that couldn't be decoded during a merge.
+//!
+//! By default the merge operator logs these failures and drops the value, which means that a
+//! corrupted operand can disappear silently during a compaction. In strict mode (see
+//! `ProfileDbOptions::strict_merge`) failures are also collected and written to a dedicated column
+//! family, with the entry's key and the bytes of the value that was dropped.
+//!
+//! Failures are collected in memory by the merge operator (which can't write to the database). A
+//! `ProfileDb` opened for writing persists them from a background thread every
+//! `PERSIST_INTERVAL`, which covers failures from background compactions, as well as after
+//! updates, merges, checks, and when it is dropped. A failure can therefore be lost only if the
+//! process exits abnormally within `PERSIST_INTERVAL` of it being collected. Read-only and
+//! secondary instances can't write, so their failures are only logged.
+//!
+//! The same value may fail to decode every time its entry is read until it is compacted away, so
+//! records are keyed by the entry key, the source, and a hash of the bytes.
+
+use chrono::{DateTime, TimeZone, Utc};
+use integer_encoding::VarInt;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub(crate) const MERGE_ERRORS_CF_NAME: &str = "merge_errors";
+/// How often collected failures are persisted in the background.
+pub(crate) const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(crate) type Sink = Arc<Pending>;
+
+/// Failures that have been collected but not yet persisted.
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    failures: Mutex<Vec<MergeFailure>>,
+    /// Held while failures are taken and written, so that writes don't interleave with purges.
+    writing: Mutex<()>,
+}
+
+impl Pending {
+    pub(crate) fn push(&self, failure: MergeFailure) {
+        lock(&self.failures).push(failure);
+    }
+
+    pub(crate) fn take(&self) -> Vec<MergeFailure> {
+        std::mem::take(&mut *lock(&self.failures))
+    }
+
+    /// Drop the pending failures that don't satisfy the predicate.
+    pub(crate) fn retain<F: FnMut(&MergeFailure) -> bool>(&self, f: F) {
+        lock(&self.failures).retain(f);
+    }
+
+    pub(crate) fn lock_writing(&self) -> MutexGuard<'_, ()> {
+        lock(&self.writing)
+    }
+}
+
+/// Lock a mutex, ignoring poisoning (the protected values are always left in a valid state).
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// A background thread that calls a function at a fixed interval until it is dropped.
+pub(crate) struct Flusher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(crate) fn spawn<F: FnMut() + Send + 'static>(
+        interval: Duration,
+        mut flush: F,
+    ) -> Result<Self, std::io::Error> {
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("merge-errors".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    flush();
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread.
+        self.stop.take();
+
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("Merge failure flushing thread panicked");
+            }
+        }
+    }
+}
+
+/// Whether the value that failed to decode was the stored value or a new operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    ExistingValue,
+    Operand,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ExistingValue => "existing",
+            Self::Operand => "operand",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::ExistingValue => 0,
+            Self::Operand => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::ExistingValue),
+            1 => Some(Self::Operand),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeFailure {
+    pub key: Vec<u8>,
+    pub source: Source,
+    pub bytes: Vec<u8>,
+    pub message: String,
+    /// The last time the failure was recorded.
+    pub recorded_at: DateTime<Utc>,
+}
+
+impl MergeFailure {
+    pub(crate) fn new(key: &[u8], source: Source, bytes: &[u8], message: String) -> Self {
+        Self {
+            key: key.to_vec(),
+            source,
+            bytes: bytes.to_vec(),
+            message,
+            recorded_at: Utc::now(),
+        }
+    }
+
+    /// The user ID prefix of the entry key, if it has one.
+    pub fn user_id(&self) -> Option<u64> {
+        super::key::parse(&self.key).map(|(_, user_id, _)| user_id)
+    }
+
+    /// The screen name suffix of the entry key (with invalid UTF-8 replaced).
+    pub fn screen_name(&self) -> String {
+        super::key::parse(&self.key)
+            .map(|(_, _, screen_name)| String::from_utf8_lossy(screen_name).into_owned())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn record_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.key.len() + 9);
+        key.extend_from_slice(&self.key);
+        key.push(self.source.to_byte());
+        key.extend_from_slice(&super::search::fnv1a(&self.bytes).to_be_bytes());
+        key
+    }
+
+    pub(crate) fn record_value(&self) -> Vec<u8> {
+        let message = self.message.as_bytes();
+        let mut value = Vec::with_capacity(8 + message.len() + self.bytes.len() + 4);
+        value.extend_from_slice(&self.recorded_at.timestamp().to_be_bytes());
+        value.extend_from_slice(&message.len().encode_var_vec());
+        value.extend_from_slice(message);
+        value.extend_from_slice(&self.bytes);
+        value
+    }
+
+    pub(crate) fn parse_record(key: &[u8], value: &[u8]) -> Option<Self> {
+        let source_index = key.len().checked_sub(9)?;
+        let source = Source::from_byte(key[source_index])?;
+
+        let recorded_at = i64::from_be_bytes(value.get(0..8)?.try_into().ok()?);
+        let (message_len, varint_len) = usize::decode_var(value.get(8..)?)?;
+        let message_start = 8 + varint_len;
+        let message = value.get(message_start..message_start + message_len)?;
+
+        Some(Self {
+            key: key[..source_index].to_vec(),
+            source,
+            bytes: value[message_start + message_len..].to_vec(),
+            message: String::from_utf8_lossy(message).into_owned(),
+            recorded_at: Utc.timestamp_opt(recorded_at, 0).single()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::KeyFormat;
+
+    fn failure(user_id: u64, source: Source, bytes: &[u8]) -> MergeFailure {
+        MergeFailure {
+            recorded_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            ..MergeFailure::new(
+                &KeyFormat::V1.key(user_id, "Foo"),
+                source,
+                bytes,
+                "bad value".to_string(),
+            )
+        }
+    }
+
+    #[test]
+    fn record_round_trip() {
+        for failure in [
+            failure(1, Source::ExistingValue, &[1, 2, 3]),
+            failure(u64::MAX >> 1, Source::Operand, &[]),
+        ] {
+            let key = failure.record_key();
+            let value = failure.record_value();
+
+            assert_eq!(&key[..key.len() - 9], &failure.key[..]);
+            assert_eq!(&value[..8], &1_600_000_000i64.to_be_bytes());
+            assert_eq!(MergeFailure::parse_record(&key, &value), Some(failure));
+        }
+
+        // Records for the same entry and source are only distinguished by the bytes.
+        assert_ne!(
+            failure(1, Source::Operand, &[1]).record_key(),
+            failure(1, Source::Operand, &[2]).record_key()
+        );
+        assert_ne!(
+            failure(1, Source::Operand, &[1]).record_key(),
+            failure(1, Source::ExistingValue, &[1]).record_key()
+        );
+    }
+
+    #[test]
+    fn invalid_records() {
+        let failure = failure(1, Source::Operand, &[1, 2, 3]);
+        let key = failure.record_key();
+        let value = failure.record_value();
+
+        let mut invalid_source = key.clone();
+        let source_index = key.len() - 9;
+        invalid_source[source_index] = 2;
+
+        assert_eq!(MergeFailure::parse_record(&key[..8], &value), None);
+        assert_eq!(MergeFailure::parse_record(&invalid_source, &value), None);
+        assert_eq!(MergeFailure::parse_record(&key, &value[..7]), None);
+        // The message is longer than the rest of the value.
+        assert_eq!(MergeFailure::parse_record(&key, &value[..12]), None);
+    }
+
+    #[test]
+    fn entry_key_parts() {
+        let failure = failure(12, Source::Operand, &[]);
+
+        assert_eq!(failure.user_id(), Some(12));
+        assert_eq!(failure.screen_name(), "foo");
+        assert_eq!(failure.source.name(), "operand");
+
+        let invalid = MergeFailure {
+            key: vec![0xFF],
+            ..failure
+        };
+
+        assert_eq!(invalid.user_id(), None);
+        assert_eq!(invalid.screen_name(), "");
+    }
+
+    #[test]
+    fn pending_failures() {
+        let pending = Pending::default();
+        pending.push(failure(1, Source::Operand, &[1]));
+        pending.push(failure(2, Source::Operand, &[2]));
+        pending.push(failure(1, Source::ExistingValue, &[3]));
+
+        pending.retain(|failure| failure.user_id() != Some(1));
+
+        assert_eq!(pending.take(), vec![failure(2, Source::Operand, &[2])]);
+        assert_eq!(pending.take(), vec![]);
+    }
+
+    #[test]
+    fn flusher() {
+        let sink = Sink::default();
+        let flushed = Arc::new(Mutex::new(vec![]));
+
+        let flusher = {
+            let (sink, flushed) = (sink.clone(), flushed.clone());
+
+            Flusher::spawn(Duration::from_millis(10), move || {
+                lock(&flushed).extend(sink.take());
+            })
+            .unwrap()
+        };
+
+        sink.push(failure(1, Source::Operand, &[1]));
+
+        // The failure is flushed without any other calls.
+        let started = std::time::Instant::now();
+        while lock(&flushed).is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Dropping the flusher stops the thread (so nothing else is flushed).
+        drop(flusher);
+        sink.push(failure(2, Source::Operand, &[2]));
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(*lock(&flushed), vec![failure(1, Source::Operand, &[1])]);
+        assert_eq!(sink.take().len(), 1);
+    }
+}
@@ -0,0 +1,827 @@
+//! Full-text search over profile descriptions, names, and locations.
+//!
+//! Text is normalized (NFKC and lowercased) and split into tokens on any character that isn't
+//! alphanumeric or an underscore. The index has one entry per field, token, user, and distinct
+//! version of the field's text:
+//!
+//! * Key: a field byte, the token, a zero byte, the user ID, and a hash of the normalized text
+//!   (both big-endian).
+//! * Value: the first and last snapshot timestamps at which the text was seen, followed by the
+//!   token's positions in the text as delta-encoded varints.
+//!
+//! Queries are sequences of terms that must all match (in any of the indexed fields, or in a
+//! specific field with a prefix like `description:`). Terms can be words or quoted phrases, can
+//! be negated with `-`, and can be combined with `OR` and parentheses:
+//!
+//! ```text
+//! description:"independent journalist" (kremlin OR moscow) -parody
+//! ```
+
+use integer_encoding::VarInt;
+use rocksdb::MergeOperands;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use twprs::model::User;
+use unicode_normalization::UnicodeNormalization;
+
+pub(crate) const TERMS_CF_NAME: &str = "terms";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unexpected end of query")]
+    UnexpectedEnd,
+    #[error("Unexpected token in query: {0}")]
+    UnexpectedToken(String),
+    #[error("Unterminated phrase")]
+    UnterminatedPhrase,
+    #[error("Unknown field: {0}")]
+    UnknownField(String),
+    #[error("Term contains no searchable text: {0}")]
+    EmptyTerm(String),
+    #[error("A group of terms cannot consist only of negated terms")]
+    OnlyNegated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Field {
+    Description,
+    Name,
+    Location,
+}
+
+impl Field {
+    pub const ALL: [Field; 3] = [Field::Description, Field::Name, Field::Location];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Description => "description",
+            Field::Name => "name",
+            Field::Location => "location",
+        }
+    }
+
+    fn byte(&self) -> u8 {
+        match self {
+            Field::Description => 0,
+            Field::Name => 1,
+            Field::Location => 2,
+        }
+    }
+
+    fn text<'a>(&self, user: &'a User) -> Option<&'a str> {
+        match self {
+            Field::Description => user.description.as_deref(),
+            Field::Name => Some(&user.name),
+            Field::Location => user.location.as_deref(),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "description" | "bio" => Ok(Field::Description),
+            "name" => Ok(Field::Name),
+            "location" => Ok(Field::Location),
+            _ => Err(Error::UnknownField(s.to_string())),
+        }
+    }
+}
+
+/// Split text into normalized tokens.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let normalized = text.nfkc().collect::<String>().to_lowercase();
+
+    normalized
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_string())
+        .collect()
+}
+
+/// A field of a user's profile that matched a query, with the range of snapshots in which the
+/// matching text was seen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hit {
+    pub field: Field,
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Posting {
+    pub user_id: u64,
+    pub text_hash: u64,
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+    pub positions: Vec<u32>,
+}
+
+/// Index entries (key and value pairs) for all indexed fields of the user object.
+pub(crate) fn entries(user: &User) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = vec![];
+
+    for field in Field::ALL {
+        if let Some(text) = field.text(user) {
+            let tokens = tokenize(text);
+            let text_hash = fnv1a(tokens.join(" ").as_bytes());
+            let mut positions: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+
+            for (position, token) in tokens.iter().enumerate() {
+                positions.entry(token).or_default().push(position as u32);
+            }
+
+            for (token, positions) in positions {
+                entries.push((
+                    key(field, token, user.id(), text_hash),
+                    value(user.snapshot, user.snapshot, &positions),
+                ));
+            }
+        }
+    }
+
+    entries
+}
+
+pub(crate) fn prefix(field: Field, token: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(token.len() + 2);
+    prefix.push(field.byte());
+    prefix.extend_from_slice(token.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+fn key(field: Field, token: &str, user_id: u64, text_hash: u64) -> Vec<u8> {
+    let mut key = prefix(field, token);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&text_hash.to_be_bytes());
+    key
+}
+
+fn value(first_snapshot: i64, last_snapshot: i64, positions: &[u32]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(16 + positions.len() * 2);
+    value.extend_from_slice(&first_snapshot.to_be_bytes());
+    value.extend_from_slice(&last_snapshot.to_be_bytes());
+
+    let mut previous = 0;
+    for position in positions {
+        value.extend_from_slice(&(position - previous).encode_var_vec());
+        previous = *position;
+    }
+
+    value
+}
+
+fn parse_snapshots(value: &[u8]) -> Option<(i64, i64)> {
+    let first_snapshot = i64::from_be_bytes(value.get(0..8)?.try_into().ok()?);
+    let last_snapshot = i64::from_be_bytes(value.get(8..16)?.try_into().ok()?);
+
+    Some((first_snapshot, last_snapshot))
+}
+
+pub(crate) fn key_user_id(key: &[u8]) -> Option<u64> {
+    let suffix = key.len().checked_sub(16)?;
+
+    Some(u64::from_be_bytes(key[suffix..suffix + 8].try_into().ok()?))
+}
+
+pub(crate) fn parse_posting(key: &[u8], value: &[u8]) -> Option<Posting> {
+    let suffix = key.len().checked_sub(16)?;
+    let user_id = u64::from_be_bytes(key[suffix..suffix + 8].try_into().ok()?);
+    let text_hash = u64::from_be_bytes(key[suffix + 8..].try_into().ok()?);
+    let (first_snapshot, last_snapshot) = parse_snapshots(value)?;
+
+    let mut positions = vec![];
+    let mut remaining = &value[16..];
+    let mut previous = 0;
+
+    while !remaining.is_empty() {
+        let (delta, length) = u32::decode_var(remaining)?;
+        previous += delta;
+        positions.push(previous);
+        remaining = &remaining[length..];
+    }
+
+    Some(Posting {
+        user_id,
+        text_hash,
+        first_snapshot,
+        last_snapshot,
+        positions,
+    })
+}
+
+/// Merge operator that widens the snapshot range (the positions are the same for every value
+/// with a given key, since the key includes a hash of the text).
+pub(crate) fn merge(
+    _key: &[u8],
+    existing_val: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut current: Option<(i64, i64, &[u8])> = existing_val
+        .and_then(|bytes| parse_snapshots(bytes).map(|(first, last)| (first, last, &bytes[16..])));
+
+    for bytes in operands.into_iter() {
+        match parse_snapshots(bytes) {
+            Some((first, last)) => {
+                current = Some(match current {
+                    Some((current_first, current_last, positions)) => {
+                        (current_first.min(first), current_last.max(last), positions)
+                    }
+                    None => (first, last, &bytes[16..]),
+                });
+            }
+            None => {
+                log::error!("Search index merge error: invalid value {:?}", bytes);
+            }
+        }
+    }
+
+    match current {
+        Some((first, last, positions)) => {
+            let mut value = Vec::with_capacity(16 + positions.len());
+            value.extend_from_slice(&first.to_be_bytes());
+            value.extend_from_slice(&last.to_be_bytes());
+            value.extend_from_slice(positions);
+            Some(value)
+        }
+        None => existing_val.map(|bytes| bytes.to_vec()),
+    }
+}
+
+// 64-bit FNV-1a, used because the hash has to be stable across builds.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// A single token or a phrase, in one field or in any indexed field.
+    Terms {
+        field: Option<Field>,
+        tokens: Vec<String>,
+    },
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Evaluate the query, given a function that returns all postings for a field and token.
+    pub(crate) fn evaluate<E, F: FnMut(Field, &str) -> Result<Vec<Posting>, E>>(
+        &self,
+        lookup: &mut F,
+    ) -> Result<BTreeMap<u64, Vec<Hit>>, E> {
+        match self {
+            Query::Terms { field, tokens } => {
+                let fields = field
+                    .map(|field| vec![field])
+                    .unwrap_or(Field::ALL.to_vec());
+                let mut results = BTreeMap::new();
+
+                for field in fields {
+                    for (user_id, hit) in phrase_hits(field, tokens, lookup)? {
+                        add_hit(&mut results, user_id, hit);
+                    }
+                }
+
+                Ok(results)
+            }
+            Query::And(queries) => {
+                let mut results: Option<BTreeMap<u64, Vec<Hit>>> = None;
+                let mut excluded = vec![];
+
+                for query in queries {
+                    if let Query::Not(query) = query {
+                        excluded.push(query.evaluate(lookup)?);
+                    } else {
+                        let next = query.evaluate(lookup)?;
+
+                        results = Some(match results {
+                            Some(current) => current
+                                .into_iter()
+                                .filter_map(|(user_id, mut hits)| {
+                                    next.get(&user_id).map(|next_hits| {
+                                        for hit in next_hits {
+                                            merge_hit(&mut hits, *hit);
+                                        }
+                                        (user_id, hits)
+                                    })
+                                })
+                                .collect(),
+                            None => next,
+                        });
+                    }
+                }
+
+                let mut results = results.unwrap_or_default();
+
+                for excluded in excluded {
+                    results.retain(|user_id, _| !excluded.contains_key(user_id));
+                }
+
+                Ok(results)
+            }
+            Query::Or(queries) => {
+                let mut results = BTreeMap::new();
+
+                for query in queries {
+                    for (user_id, hits) in query.evaluate(lookup)? {
+                        for hit in hits {
+                            add_hit(&mut results, user_id, hit);
+                        }
+                    }
+                }
+
+                Ok(results)
+            }
+            // Negated terms are only meaningful inside a conjunction (which the parser
+            // guarantees), so a bare negation matches nothing.
+            Query::Not(_) => Ok(BTreeMap::new()),
+        }
+    }
+}
+
+fn add_hit(results: &mut BTreeMap<u64, Vec<Hit>>, user_id: u64, hit: Hit) {
+    merge_hit(results.entry(user_id).or_default(), hit);
+}
+
+fn merge_hit(hits: &mut Vec<Hit>, hit: Hit) {
+    match hits.iter_mut().find(|current| current.field == hit.field) {
+        Some(current) => {
+            current.first_snapshot = current.first_snapshot.min(hit.first_snapshot);
+            current.last_snapshot = current.last_snapshot.max(hit.last_snapshot);
+        }
+        None => {
+            hits.push(hit);
+            hits.sort_by_key(|hit| hit.field);
+        }
+    }
+}
+
+fn phrase_hits<E, F: FnMut(Field, &str) -> Result<Vec<Posting>, E>>(
+    field: Field,
+    tokens: &[String],
+    lookup: &mut F,
+) -> Result<Vec<(u64, Hit)>, E> {
+    let mut candidates: HashMap<(u64, u64), (i64, i64, Vec<u32>)> = HashMap::new();
+
+    for (offset, token) in tokens.iter().enumerate() {
+        let postings = lookup(field, token)?;
+
+        if offset == 0 {
+            for posting in postings {
+                candidates.insert(
+                    (posting.user_id, posting.text_hash),
+                    (
+                        posting.first_snapshot,
+                        posting.last_snapshot,
+                        posting.positions,
+                    ),
+                );
+            }
+        } else {
+            let postings = postings
+                .into_iter()
+                .map(|posting| ((posting.user_id, posting.text_hash), posting.positions))
+                .collect::<HashMap<_, _>>();
+
+            candidates.retain(|document, (_, _, starts)| match postings.get(document) {
+                Some(positions) => {
+                    starts.retain(|start| positions.contains(&(start + offset as u32)));
+                    !starts.is_empty()
+                }
+                None => false,
+            });
+        }
+
+        if candidates.is_empty() {
+            break;
+        }
+    }
+
+    Ok(candidates
+        .into_iter()
+        .map(|((user_id, _), (first_snapshot, last_snapshot, _))| {
+            (
+                user_id,
+                Hit {
+                    field,
+                    first_snapshot,
+                    last_snapshot,
+                },
+            )
+        })
+        .collect())
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: lex(s)?,
+            position: 0,
+        };
+        let query = parser.parse_or()?;
+
+        match parser.tokens.get(parser.position) {
+            Some(token) => Err(Error::UnexpectedToken(token.to_string())),
+            None => Ok(query),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Field(String),
+    Minus,
+    Or,
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(value) => write!(f, "{}", value),
+            Token::Phrase(value) => write!(f, "{:?}", value),
+            Token::Field(value) => write!(f, "{}:", value),
+            Token::Minus => write!(f, "-"),
+            Token::Or => write!(f, "OR"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn lex(input: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    let mut term_start = true;
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            term_start = true;
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::Open);
+            term_start = true;
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::Close);
+            term_start = true;
+        } else if c == '-' && term_start {
+            chars.next();
+            tokens.push(Token::Minus);
+        } else if c == '"' {
+            chars.next();
+            let mut phrase = String::new();
+            let mut terminated = false;
+
+            for next in chars.by_ref() {
+                if next == '"' {
+                    terminated = true;
+                    break;
+                }
+                phrase.push(next);
+            }
+
+            if !terminated {
+                return Err(Error::UnterminatedPhrase);
+            }
+
+            tokens.push(Token::Phrase(phrase));
+            term_start = true;
+        } else {
+            let mut word = String::new();
+
+            while let Some(&next) = chars.peek() {
+                if next.is_whitespace() || next == '(' || next == ')' || next == '"' || next == ':'
+                {
+                    break;
+                }
+                word.push(next);
+                chars.next();
+            }
+
+            if chars.peek() == Some(&':') {
+                chars.next();
+                tokens.push(Token::Field(word));
+                term_start = false;
+            } else if word == "OR" {
+                tokens.push(Token::Or);
+                term_start = true;
+            } else if word != "AND" {
+                tokens.push(Token::Word(word));
+                term_start = true;
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self.peek().cloned().ok_or(Error::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn parse_or(&mut self) -> Result<Query, Error> {
+        let mut queries = vec![self.parse_and()?];
+
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            queries.push(self.parse_and()?);
+        }
+
+        Ok(if queries.len() == 1 {
+            queries.remove(0)
+        } else {
+            Query::Or(queries)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, Error> {
+        let mut queries = vec![];
+
+        while let Some(token) = self.peek() {
+            if *token == Token::Or || *token == Token::Close {
+                break;
+            }
+
+            queries.push(self.parse_unary()?);
+        }
+
+        if queries.is_empty() {
+            Err(self
+                .peek()
+                .map(|token| Error::UnexpectedToken(token.to_string()))
+                .unwrap_or(Error::UnexpectedEnd))
+        } else if queries.iter().all(|query| matches!(query, Query::Not(_))) {
+            Err(Error::OnlyNegated)
+        } else if queries.len() == 1 {
+            Ok(queries.remove(0))
+        } else {
+            Ok(Query::And(queries))
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, Error> {
+        if self.peek() == Some(&Token::Minus) {
+            self.position += 1;
+            Ok(Query::Not(Box::new(self.parse_primary(None)?)))
+        } else {
+            self.parse_primary(None)
+        }
+    }
+
+    fn parse_primary(&mut self, field: Option<Field>) -> Result<Query, Error> {
+        match self.next()? {
+            Token::Open if field.is_none() => {
+                let query = self.parse_or()?;
+
+                match self.next()? {
+                    Token::Close => Ok(query),
+                    other => Err(Error::UnexpectedToken(other.to_string())),
+                }
+            }
+            Token::Field(name) if field.is_none() => {
+                let field = name.parse()?;
+                self.parse_primary(Some(field))
+            }
+            Token::Word(text) | Token::Phrase(text) => {
+                let tokens = tokenize(&text);
+
+                if tokens.is_empty() {
+                    Err(Error::EmptyTerm(text))
+                } else {
+                    Ok(Query::Terms { field, tokens })
+                }
+            }
+            other => Err(Error::UnexpectedToken(other.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: i64, snapshot: i64, description: &str) -> User {
+        User {
+            id,
+            id_str: id.to_string(),
+            name: format!("User {}", id),
+            screen_name: format!("user{}", id),
+            description: Some(description.to_string()),
+            snapshot,
+            ..Default::default()
+        }
+    }
+
+    fn terms(field: Option<Field>, tokens: &[&str]) -> Query {
+        Query::Terms {
+            field,
+            tokens: tokens.iter().map(|token| token.to_string()).collect(),
+        }
+    }
+
+    /// Evaluate a query against the index entries for the given users.
+    fn search(users: &[User], query: &str) -> BTreeMap<u64, Vec<Hit>> {
+        let entries = users.iter().flat_map(entries).collect::<Vec<_>>();
+        let query = query.parse::<Query>().unwrap();
+
+        query
+            .evaluate(&mut |field, token| {
+                let prefix = prefix(field, token);
+
+                Ok::<_, ()>(
+                    entries
+                        .iter()
+                        .filter(|(key, _)| key.starts_with(&prefix))
+                        .map(|(key, value)| parse_posting(key, value).unwrap())
+                        .collect(),
+                )
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn tokenize_text() {
+        assert_eq!(
+            tokenize("Independent  JOURNALIST, @rt_com #news!"),
+            vec!["independent", "journalist", "rt_com", "news"]
+        );
+        assert_eq!(tokenize("ｆｕｌｌ－ｗｉｄｔｈ"), vec!["full", "width"]);
+        assert_eq!(tokenize("Москва 2024"), vec!["москва", "2024"]);
+        assert!(tokenize(" -- !! ").is_empty());
+    }
+
+    #[test]
+    fn parse_words_and_phrases() {
+        assert_eq!(
+            "Kremlin".parse::<Query>().unwrap(),
+            terms(None, &["kremlin"])
+        );
+        assert_eq!(
+            "\"Independent journalist\" moscow"
+                .parse::<Query>()
+                .unwrap(),
+            Query::And(vec![
+                terms(None, &["independent", "journalist"]),
+                terms(None, &["moscow"]),
+            ])
+        );
+        assert!(matches!(
+            "\"unterminated".parse::<Query>(),
+            Err(Error::UnterminatedPhrase)
+        ));
+        assert!(matches!(
+            "\"!!\"".parse::<Query>(),
+            Err(Error::EmptyTerm(_))
+        ));
+    }
+
+    #[test]
+    fn parse_field_prefixes() {
+        assert_eq!(
+            "bio:\"independent journalist\" location:moscow"
+                .parse::<Query>()
+                .unwrap(),
+            Query::And(vec![
+                terms(Some(Field::Description), &["independent", "journalist"]),
+                terms(Some(Field::Location), &["moscow"]),
+            ])
+        );
+        assert!(matches!(
+            "handle:jack".parse::<Query>(),
+            Err(Error::UnknownField(_))
+        ));
+        assert!(matches!(
+            "name:(a OR b)".parse::<Query>(),
+            Err(Error::UnexpectedToken(_))
+        ));
+    }
+
+    #[test]
+    fn parse_operators() {
+        assert_eq!(
+            "a AND (b OR c) -d".parse::<Query>().unwrap(),
+            Query::And(vec![
+                terms(None, &["a"]),
+                Query::Or(vec![terms(None, &["b"]), terms(None, &["c"])]),
+                Query::Not(Box::new(terms(None, &["d"]))),
+            ])
+        );
+        // A hyphen inside a word isn't negation.
+        assert_eq!(
+            "pro-russia".parse::<Query>().unwrap(),
+            terms(None, &["pro", "russia"])
+        );
+        assert!(matches!("-a -b".parse::<Query>(), Err(Error::OnlyNegated)));
+        assert!(matches!("(a".parse::<Query>(), Err(Error::UnexpectedEnd)));
+        assert!(matches!(
+            "a)".parse::<Query>(),
+            Err(Error::UnexpectedToken(_))
+        ));
+    }
+
+    #[test]
+    fn posting_round_trip() {
+        let user = user(12, 1600000000, "one two one three one");
+        let entries = entries(&user);
+        let key = key(
+            Field::Description,
+            "one",
+            12,
+            fnv1a(b"one two one three one"),
+        );
+        let (_, value) = entries
+            .iter()
+            .find(|(entry_key, _)| *entry_key == key)
+            .unwrap();
+
+        assert_eq!(key_user_id(&key), Some(12));
+        assert_eq!(
+            parse_posting(&key, value),
+            Some(Posting {
+                user_id: 12,
+                text_hash: fnv1a(b"one two one three one"),
+                first_snapshot: 1600000000,
+                last_snapshot: 1600000000,
+                positions: vec![0, 2, 4],
+            })
+        );
+        assert!(key.starts_with(&prefix(Field::Description, "one")));
+        assert!(!key.starts_with(&prefix(Field::Description, "on")));
+        assert_eq!(parse_posting(&key, &value[..10]), None);
+    }
+
+    #[test]
+    fn match_phrases() {
+        let users = [
+            user(1, 10, "An independent journalist"),
+            user(2, 20, "journalist, independent"),
+            user(3, 30, "Independent. Journalist!"),
+        ];
+
+        assert_eq!(
+            search(&users, "\"independent journalist\"")
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            search(&users, "independent journalist")
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            search(&users, "journalist -\"an independent\"")
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn match_fields() {
+        let mut located = user(1, 10, "from moscow");
+        located.location = Some("Moscow".to_string());
+        let users = [located, user(2, 20, "moscow")];
+
+        let results = search(&users, "location:moscow");
+
+        assert_eq!(results.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(
+            results[&1],
+            vec![Hit {
+                field: Field::Location,
+                first_snapshot: 10,
+                last_snapshot: 10,
+            }]
+        );
+        assert_eq!(search(&users, "moscow")[&1].len(), 2);
+        assert_eq!(search(&users, "name:\"user 2\"").len(), 1);
+    }
+}
@@ -0,0 +1,277 @@
+//! Export of a profile database (and optionally a deactivation log) to SQLite.
+//!
+//! The schema is normalized for ad hoc querying:
+//!
+//! * `users`: one row per user ID with the most recent profile values.
+//! * `snapshots`: one row per stored profile (the most recent snapshot for each screen name).
+//! * `screen_names`: the screen names used by each user, with first and last seen timestamps.
+//! * `urls`: the URLs from the `url` and `description` entities of each stored profile.
+//! * `deactivations`: the entries of the deactivation log, if one is provided.
+//!
+//! All timestamps are epoch seconds, and booleans are stored as `0` or `1`.
+
+use super::{db::ProfileDb, deactivation::Log};
+use rusqlite::{params, Connection, Transaction};
+use std::path::Path;
+use twprs::model::User;
+
+const SCHEMA: &str = "
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    screen_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    protected INTEGER NOT NULL,
+    verified INTEGER NOT NULL,
+    followers_count INTEGER NOT NULL,
+    friends_count INTEGER NOT NULL,
+    statuses_count INTEGER NOT NULL
+);
+CREATE TABLE snapshots (
+    user_id INTEGER NOT NULL,
+    snapshot INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    screen_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    location TEXT,
+    description TEXT,
+    url TEXT,
+    expanded_url TEXT,
+    protected INTEGER NOT NULL,
+    verified INTEGER NOT NULL,
+    followers_count INTEGER NOT NULL,
+    friends_count INTEGER NOT NULL,
+    listed_count INTEGER NOT NULL,
+    favourites_count INTEGER NOT NULL,
+    statuses_count INTEGER NOT NULL,
+    created_at INTEGER,
+    lang TEXT,
+    profile_image_url_https TEXT NOT NULL,
+    profile_banner_url TEXT,
+    default_profile INTEGER NOT NULL,
+    default_profile_image INTEGER NOT NULL,
+    withheld_scope TEXT,
+    withheld_in_countries TEXT
+);
+CREATE TABLE screen_names (
+    user_id INTEGER NOT NULL,
+    screen_name TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    PRIMARY KEY (user_id, screen_name)
+);
+CREATE TABLE urls (
+    user_id INTEGER NOT NULL,
+    snapshot INTEGER NOT NULL,
+    source TEXT NOT NULL,
+    url TEXT NOT NULL,
+    expanded_url TEXT
+);
+CREATE TABLE deactivations (
+    user_id INTEGER NOT NULL,
+    status INTEGER NOT NULL,
+    observed INTEGER NOT NULL,
+    reversal INTEGER
+);
+";
+
+// Indexes are created after the data is inserted, which is much faster for large exports.
+const INDEXES: &str = "
+CREATE INDEX users_screen_name ON users (screen_name COLLATE NOCASE);
+CREATE INDEX snapshots_user_id ON snapshots (user_id, snapshot);
+CREATE INDEX snapshots_snapshot ON snapshots (snapshot);
+CREATE INDEX screen_names_screen_name ON screen_names (screen_name COLLATE NOCASE);
+CREATE INDEX urls_user_id ON urls (user_id);
+CREATE INDEX urls_expanded_url ON urls (expanded_url);
+CREATE INDEX deactivations_user_id ON deactivations (user_id);
+CREATE INDEX deactivations_observed ON deactivations (observed);
+";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Profile database error")]
+    ProfileDb(#[from] super::db::Error),
+    #[error("SQLite error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Output file already exists")]
+    AlreadyExists(std::path::PathBuf),
+}
+
+/// Row counts for a completed export.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub user_count: usize,
+    pub snapshot_count: usize,
+    pub url_count: usize,
+    pub deactivation_count: usize,
+}
+
+/// Write the contents of the profile database to a new SQLite file at the given path.
+pub fn export<P: AsRef<Path>>(
+    db: &ProfileDb,
+    deactivations: Option<&Log>,
+    path: P,
+) -> Result<Summary, Error> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        return Err(Error::AlreadyExists(path.to_path_buf()));
+    }
+
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(SCHEMA)?;
+
+    let mut summary = write_profiles(db, &transaction)?;
+
+    if let Some(log) = deactivations {
+        summary.deactivation_count = write_deactivations(log, &transaction)?;
+    }
+
+    transaction.execute_batch(INDEXES)?;
+    transaction.commit()?;
+
+    Ok(summary)
+}
+
+fn write_profiles(db: &ProfileDb, transaction: &Transaction) -> Result<Summary, Error> {
+    let mut summary = Summary::default();
+
+    let mut insert_user = transaction
+        .prepare("INSERT INTO users VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)")?;
+    let mut insert_snapshot = transaction.prepare(
+        "INSERT INTO snapshots VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12,
+            ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24
+        )",
+    )?;
+    let mut insert_screen_name = transaction.prepare(
+        "INSERT INTO screen_names VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (user_id, screen_name) DO UPDATE SET
+                first_seen = MIN(first_seen, excluded.first_seen),
+                last_seen = MAX(last_seen, excluded.last_seen)",
+    )?;
+    let mut insert_url = transaction.prepare("INSERT INTO urls VALUES (?1, ?2, ?3, ?4, ?5)")?;
+
+    for result in db.iter() {
+        let batch = result?;
+
+        for (first_seen, user) in &batch {
+            let first_seen = first_seen.timestamp();
+
+            insert_snapshot.execute(params![
+                user.id,
+                user.snapshot,
+                first_seen,
+                user.screen_name,
+                user.name,
+                user.location,
+                user.description,
+                user.url,
+                user.expanded_url(),
+                user.protected,
+                user.verified,
+                user.followers_count,
+                user.friends_count,
+                user.listed_count,
+                user.favourites_count,
+                user.statuses_count,
+                created_at(user),
+                user.lang,
+                user.profile_image_url_https,
+                user.profile_banner_url,
+                user.default_profile,
+                user.default_profile_image,
+                user.withheld_scope,
+                withheld_in_countries(user),
+            ])?;
+            summary.snapshot_count += 1;
+
+            insert_screen_name.execute(params![
+                user.id,
+                user.screen_name,
+                first_seen,
+                user.snapshot
+            ])?;
+
+            if let Some(entities) = &user.entities {
+                let sources = [
+                    ("url", &entities.url),
+                    ("description", &entities.description),
+                ];
+
+                for (source, entity) in sources {
+                    for url in entity.iter().flat_map(|entity| &entity.urls) {
+                        insert_url.execute(params![
+                            user.id,
+                            user.snapshot,
+                            source,
+                            url.url,
+                            url.expanded_url
+                        ])?;
+                        summary.url_count += 1;
+                    }
+                }
+            }
+        }
+
+        let first_seen = batch.iter().map(|(first_seen, _)| first_seen).min();
+
+        if let (Some(first_seen), Some((_, most_recent))) = (first_seen, batch.last()) {
+            insert_user.execute(params![
+                most_recent.id,
+                most_recent.screen_name,
+                most_recent.name,
+                created_at(most_recent),
+                first_seen.timestamp(),
+                most_recent.snapshot,
+                most_recent.protected,
+                most_recent.verified,
+                most_recent.followers_count,
+                most_recent.friends_count,
+                most_recent.statuses_count,
+            ])?;
+            summary.user_count += 1;
+        } else {
+            log::error!("Empty user result when reading database");
+        }
+    }
+
+    Ok(summary)
+}
+
+fn write_deactivations(log: &Log, transaction: &Transaction) -> Result<usize, Error> {
+    let mut insert_deactivation =
+        transaction.prepare("INSERT INTO deactivations VALUES (?1, ?2, ?3, ?4)")?;
+    let mut count = 0;
+
+    for (user_id, status, observed, reversal) in log.deactivations() {
+        insert_deactivation.execute(params![
+            user_id as i64,
+            status.code(),
+            observed.timestamp(),
+            reversal.map(|reversal| reversal.timestamp())
+        ])?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn created_at(user: &User) -> Option<i64> {
+    user.created_at()
+        .ok()
+        .map(|created_at| created_at.timestamp())
+}
+
+fn withheld_in_countries(user: &User) -> Option<String> {
+    if user.withheld_in_countries.is_empty() {
+        None
+    } else {
+        Some(user.withheld_in_countries.join(","))
+    }
+}
@@ -0,0 +1,23 @@
+//! Fixtures shared by the unit tests.
+
+use std::path::PathBuf;
+use twprs::model::User;
+
+/// A user with only the ID, screen name, and snapshot set.
+pub(crate) fn user(id: i64, screen_name: &str, snapshot: i64) -> User {
+    User {
+        id,
+        id_str: id.to_string(),
+        screen_name: screen_name.to_string(),
+        snapshot,
+        ..Default::default()
+    }
+}
+
+/// An empty temporary directory that is unique to the test (and process).
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("twprs-db-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
@@ -0,0 +1,292 @@
+//! Encoding for the values of profile entries.
+//!
+//! The original encoding is the first-seen timestamp (a big-endian `i64`) followed by the
+//! Avro-encoded user object. The current encoding starts with a marker byte (`0xFF`, which can't
+//! begin an original value with a non-negative timestamp) and a version byte, followed by:
+//!
+//! * the first-seen and last-seen timestamps (big-endian `i64`s),
+//! * the number of times the profile was observed (a varint),
+//! * the distinct days (since the epoch) on which it was observed, as a varint count followed by
+//!   the first day and the varint deltas between days,
+//! * the Avro-encoded user object.
+//!
+//! Values in the original encoding are read as a single observation at the snapshot time, and
+//! are rewritten in the current encoding when they are merged.
+
+use super::db::Error;
+use apache_avro::{from_avro_datum, from_value, to_avro_datum, to_value};
+use chrono::{DateTime, TimeZone, Utc};
+use integer_encoding::VarInt;
+use std::io::Cursor;
+use twprs::{avro::USER_SCHEMA, model::User};
+
+const MARKER: u8 = 0xFF;
+const VERSION: u8 = 1;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Observation statistics for a user ID and screen name pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Observations {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// The number of times the profile was added (which includes duplicate imports).
+    pub count: u64,
+    pub distinct_days: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) first_seen: i64,
+    pub(crate) last_seen: i64,
+    pub(crate) count: u64,
+    /// Sorted days since the epoch.
+    pub(crate) days: Vec<i64>,
+}
+
+impl Header {
+    /// The header for a single observation.
+    pub(crate) fn new(snapshot: i64) -> Self {
+        Self {
+            first_seen: snapshot,
+            last_seen: snapshot,
+            count: 1,
+            days: vec![snapshot.div_euclid(SECONDS_PER_DAY)],
+        }
+    }
+
+    pub(crate) fn combine(&mut self, other: Header) {
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.count = self.count.saturating_add(other.count);
+        self.days.extend(other.days);
+        self.days.sort_unstable();
+        self.days.dedup();
+    }
+
+    pub(crate) fn observations(&self) -> Result<Observations, Error> {
+        Ok(Observations {
+            first_seen: timestamp(self.first_seen)?,
+            last_seen: timestamp(self.last_seen)?,
+            count: self.count,
+            distinct_days: self.days.len(),
+        })
+    }
+
+    fn write(&self, output: &mut Vec<u8>) {
+        output.push(MARKER);
+        output.push(VERSION);
+        output.extend_from_slice(&self.first_seen.to_be_bytes());
+        output.extend_from_slice(&self.last_seen.to_be_bytes());
+        output.extend_from_slice(&self.count.encode_var_vec());
+        output.extend_from_slice(&self.days.len().encode_var_vec());
+
+        let mut previous = None;
+
+        for day in &self.days {
+            match previous {
+                Some(previous) => {
+                    output.extend_from_slice(&((day - previous) as u64).encode_var_vec())
+                }
+                None => output.extend_from_slice(&day.encode_var_vec()),
+            }
+            previous = Some(*day);
+        }
+    }
+}
+
+/// Encode a value with the given header and Avro bytes.
+pub(crate) fn encode(header: &Header, avro_bytes: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(avro_bytes.len() + 32 + header.days.len());
+    header.write(&mut value);
+    value.extend_from_slice(avro_bytes);
+    value
+}
+
+pub(crate) fn encode_user(header: &Header, user: &User) -> Result<Vec<u8>, Error> {
+    let avro_bytes = to_avro_datum(&USER_SCHEMA, to_value(user)?)?;
+
+    Ok(encode(header, &avro_bytes))
+}
+
+/// Decode a value in either encoding.
+pub(crate) fn decode(value: &[u8]) -> Result<(Header, User), Error> {
+    let (header, avro_bytes) = split(value)?;
+    let mut cursor = Cursor::new(avro_bytes);
+    let avro_value = from_avro_datum(&USER_SCHEMA, &mut cursor, None)?;
+    let user: User = from_value(&avro_value)?;
+
+    let header = match header {
+        Ok(header) => header,
+        Err(first_seen) => Header {
+            first_seen,
+            ..Header::new(user.snapshot)
+        },
+    };
+
+    Ok((header, user))
+}
+
+/// Split a value into its header (or just the first-seen timestamp for the original encoding)
+/// and the Avro bytes.
+fn split(value: &[u8]) -> Result<(Result<Header, i64>, &[u8]), Error> {
+    if value.first() == Some(&MARKER) {
+        parse_header(value).ok_or_else(|| Error::InvalidValueHeader(value.to_vec()))
+    } else {
+        let first_seen = value
+            .get(0..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(i64::from_be_bytes)
+            .ok_or_else(|| Error::InvalidTimestamp(value.to_vec()))?;
+
+        Ok((Err(first_seen), &value[8..]))
+    }
+}
+
+fn parse_header(value: &[u8]) -> Option<(Result<Header, i64>, &[u8])> {
+    if *value.get(1)? != VERSION {
+        return None;
+    }
+
+    let first_seen = i64::from_be_bytes(value.get(2..10)?.try_into().ok()?);
+    let last_seen = i64::from_be_bytes(value.get(10..18)?.try_into().ok()?);
+    let mut offset = 18;
+
+    let (count, length) = u64::decode_var(value.get(offset..)?)?;
+    offset += length;
+    let (day_count, length) = usize::decode_var(value.get(offset..)?)?;
+    offset += length;
+
+    let mut days = Vec::with_capacity(day_count.min(value.len()));
+
+    for i in 0..day_count {
+        if i == 0 {
+            let (day, length) = i64::decode_var(value.get(offset..)?)?;
+            offset += length;
+            days.push(day);
+        } else {
+            let (delta, length) = u64::decode_var(value.get(offset..)?)?;
+            offset += length;
+            days.push(days[i - 1].checked_add(delta as i64)?);
+        }
+    }
+
+    Some((
+        Ok(Header {
+            first_seen,
+            last_seen,
+            count,
+            days,
+        }),
+        &value[offset..],
+    ))
+}
+
+fn timestamp(value: i64) -> Result<DateTime<Utc>, Error> {
+    Utc.timestamp_opt(value, 0)
+        .single()
+        .ok_or_else(|| Error::InvalidTimestamp(value.to_be_bytes().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(snapshot: i64, screen_name: &str) -> User {
+        User {
+            id: 12,
+            id_str: "12".to_string(),
+            name: "Jack".to_string(),
+            screen_name: screen_name.to_string(),
+            snapshot,
+            ..Default::default()
+        }
+    }
+
+    /// A value in the original encoding.
+    fn legacy_value(first_seen: i64, user: &User) -> Vec<u8> {
+        let mut value = first_seen.to_be_bytes().to_vec();
+        value.extend(to_avro_datum(&USER_SCHEMA, to_value(user).unwrap()).unwrap());
+        value
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = Header {
+            first_seen: 1,
+            last_seen: 2,
+            count: 300,
+            days: vec![200, 201, 400],
+        };
+        let value = encode(&header, &[]);
+
+        assert_eq!(value[0], 0xFF);
+        assert_eq!(value[1], VERSION);
+        assert_eq!(&value[2..10], &1i64.to_be_bytes());
+        assert_eq!(&value[10..18], &2i64.to_be_bytes());
+        // Varint count, day count, first day (zigzag-encoded), and deltas.
+        assert_eq!(&value[18..], &[0xAC, 0x02, 3, 0x90, 0x03, 1, 0xC7, 0x01]);
+        assert_eq!(split(&value).unwrap(), (Ok(header), &[][..]));
+    }
+
+    #[test]
+    fn round_trip() {
+        for screen_name in ["jack", "ÀBÇ_İ"] {
+            let user = user(1_600_000_000, screen_name);
+            let mut header = Header::new(1_500_000_000);
+            header.combine(Header::new(user.snapshot));
+
+            let (decoded_header, decoded_user) =
+                decode(&encode_user(&header, &user).unwrap()).unwrap();
+
+            assert_eq!(decoded_header, header);
+            assert_eq!(decoded_header.days, vec![17361, 18518]);
+            assert_eq!(decoded_user, user);
+        }
+    }
+
+    #[test]
+    fn negative_days() {
+        let header = Header {
+            first_seen: -SECONDS_PER_DAY * 3,
+            last_seen: 0,
+            count: 2,
+            days: vec![-3, 0],
+        };
+
+        assert_eq!(split(&encode(&header, &[])).unwrap().0, Ok(header));
+        assert_eq!(Header::new(-1).days, vec![-1]);
+    }
+
+    #[test]
+    fn legacy_value_fallback() {
+        let user = user(1_600_000_000, "ÀBÇ_İ");
+        let (header, decoded_user) = decode(&legacy_value(1_500_000_000, &user)).unwrap();
+
+        assert_eq!(
+            header,
+            Header {
+                first_seen: 1_500_000_000,
+                last_seen: user.snapshot,
+                count: 1,
+                days: vec![18518],
+            }
+        );
+        assert_eq!(decoded_user, user);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(matches!(decode(&[0, 1]), Err(Error::InvalidTimestamp(_))));
+        assert!(matches!(
+            decode(&[0xFF, VERSION + 1]),
+            Err(Error::InvalidValueHeader(_))
+        ));
+
+        let mut truncated = encode(&Header::new(1_600_000_000), &[]);
+        truncated.truncate(20);
+        assert!(matches!(
+            decode(&truncated),
+            Err(Error::InvalidValueHeader(_))
+        ));
+    }
+}